@@ -0,0 +1,217 @@
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Output unit periods in CPU cycles, indexed by the low 4 bits of $4010
+const NTSC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+/// The delta modulation channel, $4010-$4013. It plays 1 bit delta encoded samples straight out of
+/// cartridge space, each bit moving the 7 bit output level up or down by 2.
+///
+/// The memory reader can't fetch sample bytes itself: it asks the memory bus for them, which halts the
+/// CPU while the fetch happens
+pub(crate) struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    /// The timer's reload value, in CPU cycles
+    period: u16,
+    timer: u16,
+
+    /// Where samples start, and how long they are, as set by $4012 and $4013
+    sample_address: u16,
+    sample_length: u16,
+    /// The memory reader's progress through the current sample
+    current_address: u16,
+    bytes_remaining: u16,
+    /// The byte waiting to be played, once the output unit is done with the one in the shift register
+    sample_buffer: Option<u8>,
+    /// Set while the memory bus is busy fetching the next byte, so it's only asked once
+    fetching: bool,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    /// Set when the output unit found the sample buffer empty. The level holds while silent
+    silence: bool,
+    level: u8,
+}
+
+impl Dmc {
+    pub fn init() -> Self {
+        Self {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            period: NTSC_RATES[0] - 1,
+            timer: 0,
+
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            fetching: false,
+
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            level: 0,
+        }
+    }
+
+    /// Writes to one of the channel's 4 registers, numbered from 0
+    pub fn write_register(&mut self, register: u16, value: u8, region: Region) {
+        match register {
+            // IL-- RRRR: IRQ enabled, loop, rate. Disabling the IRQ also acknowledges it
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = value & 0x40 != 0;
+                let rates = match region {
+                    Region::Pal => &PAL_RATES,
+                    Region::Ntsc | Region::Dendy => &NTSC_RATES,
+                };
+                self.period = rates[(value & 0x0f) as usize] - 1;
+            },
+            // -DDD DDDD: loads the output level directly
+            1 => self.level = value & 0x7f,
+            // AAAA AAAA: sample address, %11AAAAAA.AA000000
+            2 => self.sample_address = 0xc000 | ((value as u16) << 6),
+            // LLLL LLLL: sample length, %LLLL.LLLL0001
+            _ => self.sample_length = ((value as u16) << 4) | 1,
+        }
+    }
+
+    /// Enabling or disabling the channel through $4015. Enabling it only starts the sample again
+    /// if it had finished; disabling it lets the byte already in the buffer play out
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        match enabled {
+            true if self.bytes_remaining == 0 => self.restart(),
+            true => (),
+            false => self.bytes_remaining = 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Whether there's still some of the sample left to fetch, which is what $4015 reports
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    /// The address the memory reader needs fetched, if the sample buffer is empty and the sample hasn't
+    /// finished. Only returns each address once: the bus is expected to come back with `fill`
+    pub fn fetch_request(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_some() || self.bytes_remaining == 0 || self.fetching {
+            return None;
+        }
+        self.fetching = true;
+        Some(self.current_address)
+    }
+
+    /// Hands the memory reader the byte it asked for
+    pub fn fill(&mut self, value: u8) {
+        self.fetching = false;
+        self.sample_buffer = Some(value);
+        // The address wraps around to $8000, not $0000
+        self.current_address = match self.current_address {
+            0xffff => 0x8000,
+            address => address + 1,
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            }
+            else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period;
+
+        if !self.silence {
+            match self.shift_register & 1 {
+                1 if self.level <= 125 => self.level += 2,
+                0 if self.level >= 2 => self.level -= 2,
+                _ => (),
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        // Starts the next output cycle with whatever's in the buffer, which empties it for the next fetch
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift_register = value;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The channel's current output level, 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.irq);
+        state.bool(self.looping);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u16(self.sample_address);
+        state.u16(self.sample_length);
+        state.u16(self.current_address);
+        state.u16(self.bytes_remaining);
+        state.bool(self.sample_buffer.is_some());
+        state.u8(self.sample_buffer.unwrap_or(0));
+        state.bool(self.fetching);
+        state.u8(self.shift_register);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+        state.u8(self.level);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.bool()?;
+        self.irq = state.bool()?;
+        self.looping = state.bool()?;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        let buffer = state.u8()?;
+        self.sample_buffer = if buffered { Some(buffer) } else { None };
+        self.fetching = state.bool()?;
+        self.shift_register = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        self.silence = state.bool()?;
+        self.level = state.u8()? & 0x7f;
+        Ok(())
+    }
+}
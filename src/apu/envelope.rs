@@ -0,0 +1,85 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The volume envelope shared by the pulse and noise channels. It either gives a constant volume,
+/// or a sawtooth which decays from 15 to 0, optionally looping, at a rate set by the same 4 bits
+pub(crate) struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    /// The constant volume, or the period of the divider
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn init() -> Self {
+        Self {
+            start: false,
+            looping: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    /// A write to the channel's first register: --LC VVVV
+    pub fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant_volume = value & 0x10 != 0;
+        self.volume = value & 0x0f;
+    }
+
+    /// Restarts the envelope on the next quarter frame, as writing the channel's last register does
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked every quarter frame by the frame counter
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        }
+        else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            }
+            else if self.looping {
+                self.decay = 15;
+            }
+        }
+        else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        match self.constant_volume {
+            true => self.volume,
+            false => self.decay,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant_volume);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant_volume = state.bool()?;
+        self.volume = state.u8()? & 0x0f;
+        self.divider = state.u8()?;
+        self.decay = state.u8()? & 0x0f;
+        Ok(())
+    }
+}
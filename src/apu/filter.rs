@@ -0,0 +1,83 @@
+use std::f32::consts::PI;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// A first order filter, running at the output sample rate
+pub(crate) enum Filter {
+    HighPass {
+        alpha: f32,
+        previous_input: f32,
+        previous_output: f32,
+    },
+    LowPass {
+        alpha: f32,
+        previous_output: f32,
+    },
+}
+
+impl Filter {
+    pub fn high_pass(sample_rate: f32, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Filter::HighPass {
+            alpha: rc / (rc + dt),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn low_pass(sample_rate: f32, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Filter::LowPass {
+            alpha: dt / (rc + dt),
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        match self {
+            Filter::HighPass { alpha, previous_input, previous_output } => {
+                *previous_output = *alpha * (*previous_output + input - *previous_input);
+                *previous_input = input;
+                *previous_output
+            },
+            Filter::LowPass { alpha, previous_output } => {
+                *previous_output += *alpha * (input - *previous_output);
+                *previous_output
+            },
+        }
+    }
+
+    /// Only what the filter remembers is saved: the coefficients come from the sample rate
+    pub fn save_state(&self, state: &mut StateWriter) {
+        match self {
+            Filter::HighPass { previous_input, previous_output, .. } => {
+                state.f32(*previous_input);
+                state.f32(*previous_output);
+            },
+            Filter::LowPass { previous_output, .. } => state.f32(*previous_output),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        match self {
+            Filter::HighPass { previous_input, previous_output, .. } => {
+                *previous_input = state.f32()?;
+                *previous_output = state.f32()?;
+            },
+            Filter::LowPass { previous_output, .. } => *previous_output = state.f32()?,
+        }
+        Ok(())
+    }
+}
+
+/// The filters between the APU and the console's audio output: two high passes, at 90Hz and 440Hz,
+/// which take out the DC offset, and a low pass at 14kHz
+pub(crate) fn nes_filters(sample_rate: f32) -> Vec<Filter> {
+    vec![
+        Filter::high_pass(sample_rate, 90.0),
+        Filter::high_pass(sample_rate, 440.0),
+        Filter::low_pass(sample_rate, 14_000.0),
+    ]
+}
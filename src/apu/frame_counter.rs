@@ -0,0 +1,128 @@
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// What the frame counter clocks on a given cycle. Half frames also clock everything a quarter frame does
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum FrameStep {
+    /// Envelopes and the triangle's linear counter
+    Quarter,
+    /// As well as the above, length counters and sweep units
+    Half,
+}
+
+/// The frame counter ($4017), which divides the CPU clock down to the roughly 240Hz quarter frame clock
+/// and 120Hz half frame clock which drive the channels' envelopes, sweeps and length counters.
+///
+/// The 4 step sequence also raises an IRQ at the end of each sequence, unless inhibited. The 5 step
+/// sequence never raises an IRQ
+pub(crate) struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    irq: bool,
+    /// CPU cycles since the start of the sequence
+    cycle: u32,
+    /// Writes to $4017 restart the sequence 3 or 4 cycles later, depending on where in an APU cycle they land
+    reset_delay: Option<u8>,
+}
+
+impl FrameCounter {
+    pub fn init() -> Self {
+        Self {
+            five_step: false,
+            irq_inhibit: false,
+            irq: false,
+            cycle: 0,
+            reset_delay: None,
+        }
+    }
+
+    /// MI-- ----: mode (5 step if set), IRQ inhibit. Setting the inhibit flag also acknowledges the IRQ.
+    /// `apu_cycle` is whether the write happened on the second half of an APU cycle
+    pub fn write(&mut self, value: u8, apu_cycle: bool) {
+        self.five_step = value & 0x80 != 0;
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        self.reset_delay = Some(if apu_cycle { 3 } else { 4 });
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    /// Reading $4015 acknowledges the IRQ
+    pub fn acknowledge(&mut self) {
+        self.irq = false;
+    }
+
+    /// Clocked every CPU cycle, returning anything which needs clocking in the channels
+    pub fn clock(&mut self, region: Region) -> Option<FrameStep> {
+        match self.reset_delay {
+            Some(0) => {
+                self.reset_delay = None;
+                self.cycle = 0;
+                // Switching to the 5 step sequence clocks everything straight away
+                return match self.five_step {
+                    true => Some(FrameStep::Half),
+                    false => None,
+                };
+            },
+            Some(delay) => self.reset_delay = Some(delay - 1),
+            None => (),
+        }
+
+        self.cycle += 1;
+        match self.five_step {
+            false => {
+                let steps = region.frame_counter_four_step();
+                // The IRQ flag is set on the three cycles around the last step,
+                // the last of which is also the first cycle of the next sequence
+                if !self.irq_inhibit && (steps[3] - 1..=steps[3] + 1).contains(&self.cycle) {
+                    self.irq = true;
+                }
+                match self.cycle {
+                    c if c == steps[0] || c == steps[2] => Some(FrameStep::Quarter),
+                    c if c == steps[1] || c == steps[3] => Some(FrameStep::Half),
+                    c if c == steps[3] + 1 => {
+                        self.cycle = 0;
+                        None
+                    },
+                    _ => None,
+                }
+            },
+            true => {
+                let steps = region.frame_counter_five_step();
+                match self.cycle {
+                    c if c == steps[0] || c == steps[2] => Some(FrameStep::Quarter),
+                    c if c == steps[1] || c == steps[4] => Some(FrameStep::Half),
+                    c if c == steps[4] + 1 => {
+                        self.cycle = 0;
+                        None
+                    },
+                    _ => None,
+                }
+            },
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.five_step);
+        state.bool(self.irq_inhibit);
+        state.bool(self.irq);
+        state.u32(self.cycle);
+        state.bool(self.reset_delay.is_some());
+        state.u8(self.reset_delay.unwrap_or(0));
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.five_step = state.bool()?;
+        self.irq_inhibit = state.bool()?;
+        self.irq = state.bool()?;
+        self.cycle = state.u32()?;
+        let resetting = state.bool()?;
+        let delay = state.u8()?;
+        self.reset_delay = if resetting { Some(delay) } else { None };
+        Ok(())
+    }
+}
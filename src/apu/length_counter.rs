@@ -0,0 +1,69 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Lengths in half frames, indexed by the 5 bit value written to a channel's last register
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences a channel once a set number of half frames have passed, unless halted
+pub(crate) struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn init() -> Self {
+        Self {
+            enabled: false,
+            halted: false,
+            counter: 0,
+        }
+    }
+
+    /// Enabling or disabling the channel through $4015. Disabling it silences it straight away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    /// Loads the counter from the top 5 bits of a write to the channel's last register.
+    /// This is ignored while the channel is disabled
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTHS[(value >> 3) as usize];
+        }
+    }
+
+    /// Clocked every half frame by the frame counter
+    pub fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    /// Whether the channel is still playing, which is what $4015 reports
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.halted);
+        state.u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.halted = state.bool()?;
+        self.counter = state.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use super::ChannelLevels;
+
+/// Combines the channels the way the NES's output resistors do. The pulses share one DAC and the
+/// triangle, noise and DMC share another, and neither is linear: louder channels quieten the others
+/// on the same DAC a little. Both DACs only see a few hundred distinct inputs, so they're looked up
+pub(crate) struct Mixer {
+    /// Indexed by pulse 1 + pulse 2
+    pulse_table: [f32; 31],
+    /// Indexed by 3 * triangle + 2 * noise + DMC
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn init() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, value) in pulse_table.iter_mut().enumerate().skip(1) {
+            *value = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, value) in tnd_table.iter_mut().enumerate().skip(1) {
+            *value = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self {
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    /// The output level, between 0 and 1
+    pub fn mix(&self, levels: &ChannelLevels) -> f32 {
+        let pulse = self.pulse_table[(levels.pulse_1 + levels.pulse_2) as usize];
+        let tnd = self.tnd_table[3 * levels.triangle as usize + 2 * levels.noise as usize + levels.dmc as usize];
+        pulse + tnd
+    }
+}
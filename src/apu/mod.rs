@@ -0,0 +1,303 @@
+mod dmc;
+mod envelope;
+mod filter;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod resampler;
+mod triangle;
+
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use dmc::Dmc;
+use filter::Filter;
+use frame_counter::{FrameCounter, FrameStep};
+use mixer::Mixer;
+use noise::Noise;
+use pulse::{Channel, Pulse};
+use resampler::Resampler;
+use triangle::Triangle;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+/// Samples kept waiting to be read before the oldest start being dropped: a second's worth at the default rate
+const MAX_BUFFERED_SAMPLES: usize = DEFAULT_SAMPLE_RATE as usize;
+
+/// The audio processing unit, which lives on the same chip as the CPU and is mapped at $4000-$4017.
+///
+/// It has 5 channels: two pulse waves, a triangle, noise and the DMC, which plays samples. Each one
+/// produces a level every CPU cycle, which are mixed, resampled to the output rate and filtered
+/// like the console's own output. The samples pile up until the frontend reads them with `read_samples`
+pub struct APU {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+
+    region: Region,
+    /// The pulse and noise timers are clocked every other CPU cycle, on the second half of each APU cycle
+    apu_cycle: bool,
+
+    mixer: Mixer,
+    /// The mixer's output on the previous cycle. Only changes are passed to the resampler
+    mixed: f32,
+    resampler: Resampler,
+    filters: Vec<Filter>,
+    sample_rate: u32,
+    /// Scales the sample rate slightly, so the frontend can keep its buffer from running dry or filling up
+    rate_adjustment: f64,
+    samples: Vec<f32>,
+}
+
+/// The level of each channel at one moment
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChannelLevels {
+    /// 0-15
+    pub pulse_1: u8,
+    /// 0-15
+    pub pulse_2: u8,
+    /// 0-15
+    pub triangle: u8,
+    /// 0-15
+    pub noise: u8,
+    /// 0-127
+    pub dmc: u8,
+}
+
+impl APU {
+    pub fn init() -> Self {
+        Self {
+            pulse_1: Pulse::init(Channel::One),
+            pulse_2: Pulse::init(Channel::Two),
+            triangle: Triangle::init(),
+            noise: Noise::init(),
+            dmc: Dmc::init(),
+            frame_counter: FrameCounter::init(),
+
+            region: Region::Ntsc,
+            apu_cycle: false,
+
+            mixer: Mixer::init(),
+            mixed: 0.0,
+            resampler: Resampler::init(Region::Ntsc.cpu_clock_rate(), DEFAULT_SAMPLE_RATE as f64),
+            filters: filter::nes_filters(DEFAULT_SAMPLE_RATE as f32),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            rate_adjustment: 1.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Changes the frame counter's timing, and the noise and DMC rate tables
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.update_rates();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets the rate samples are produced at, in Hz
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.filters = filter::nes_filters(sample_rate as f32);
+        self.update_rates();
+    }
+
+    /// Produces `adjustment` times as many samples as the sample rate says. Keeping this within half
+    /// a percent or so of 1 lets the frontend stay in sync with the audio device without the pitch
+    /// changing noticeably
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.rate_adjustment = adjustment;
+        self.update_rates();
+    }
+
+    fn update_rates(&mut self) {
+        let sample_rate = self.sample_rate as f64 * self.rate_adjustment;
+        self.resampler.set_rates(self.region.cpu_clock_rate(), sample_rate);
+    }
+
+    /// Moves every sample produced since the last call onto the end of `output`, between -1 and 1
+    pub fn read_samples(&mut self, output: &mut Vec<f32>) {
+        self.flush_samples();
+        output.append(&mut self.samples);
+    }
+
+    fn flush_samples(&mut self) {
+        let filters = &mut self.filters;
+        let samples = &mut self.samples;
+        self.resampler.flush(|sample| {
+            let filtered = filters.iter_mut().fold(sample, |sample, filter| filter.process(sample));
+            samples.push(filtered);
+        });
+        // Nobody's listening, so don't let them build up forever
+        if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            let excess = self.samples.len() - MAX_BUFFERED_SAMPLES;
+            self.samples.drain(..excess);
+        }
+    }
+
+    /// Writes to $4000-$4013, $4015 or $4017
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write_register(address & 0b11, value),
+            0x4004..=0x4007 => self.pulse_2.write_register(address & 0b11, value),
+            0x4008..=0x400b => self.triangle.write_register(address & 0b11, value),
+            0x400c..=0x400f => self.noise.write_register(address & 0b11, value, self.region),
+            0x4010..=0x4013 => self.dmc.write_register(address & 0b11, value, self.region),
+            // ---D NT21: enables each channel
+            0x4015 => {
+                self.pulse_1.length.set_enabled(value & 0x01 != 0);
+                self.pulse_2.length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            },
+            0x4017 => self.frame_counter.write(value, self.apu_cycle),
+            _ => (),
+        }
+    }
+
+    /// Reads $4015: IF-D NT21, the DMC and frame IRQ flags, then whether each channel is still playing.
+    /// Bit 5 isn't driven, so `open_bus` fills it in. Reading acknowledges the frame IRQ, but not the DMC's
+    pub fn read_status(&mut self, open_bus: u8) -> u8 {
+        let status = (self.dmc.irq() as u8) << 7
+            | (self.frame_counter.irq() as u8) << 6
+            | (open_bus & 0x20)
+            | (self.dmc.active() as u8) << 4
+            | (self.noise.length.active() as u8) << 3
+            | (self.triangle.length.active() as u8) << 2
+            | (self.pulse_2.length.active() as u8) << 1
+            | self.pulse_1.length.active() as u8;
+        self.frame_counter.acknowledge();
+        status
+    }
+
+    /// Advances the APU by one CPU cycle
+    pub fn step(&mut self) {
+        match self.frame_counter.clock(self.region) {
+            Some(FrameStep::Quarter) => self.clock_quarter_frame(),
+            Some(FrameStep::Half) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+            None => (),
+        }
+
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.apu_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.apu_cycle = !self.apu_cycle;
+
+        let mixed = self.mixer.mix(&self.levels());
+        if mixed != self.mixed {
+            self.resampler.add_delta(mixed - self.mixed);
+            self.mixed = mixed;
+        }
+        self.resampler.clock();
+        // Every so often, so the impulses waiting to be integrated don't pile up
+        if self.resampler.pending() >= 1024 {
+            self.flush_samples();
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.length.clock();
+        self.pulse_2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    /// Whether the frame counter is asserting IRQ
+    pub fn frame_irq(&self) -> bool {
+        self.frame_counter.irq()
+    }
+
+    /// Whether the DMC is asserting IRQ, having reached the end of a sample
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq()
+    }
+
+    /// The address of the next sample byte, if the DMC needs one fetched. The memory bus should halt
+    /// the CPU, read it and pass it back through `dmc_fill`
+    pub(crate) fn dmc_fetch_request(&mut self) -> Option<u16> {
+        self.dmc.fetch_request()
+    }
+
+    pub(crate) fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    pub fn levels(&self) -> ChannelLevels {
+        ChannelLevels {
+            pulse_1: self.pulse_1.output(),
+            pulse_2: self.pulse_2.output(),
+            triangle: self.triangle.output(),
+            noise: self.noise.output(),
+            dmc: self.dmc.output(),
+        }
+    }
+
+    /// Everything emulated, and the resampler and filters' history so the sound carries on smoothly.
+    /// The sample rate is the frontend's business, so it isn't saved
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.region(self.region);
+        state.bool(self.apu_cycle);
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        self.frame_counter.save_state(state);
+        state.f32(self.mixed);
+        self.resampler.save_state(state);
+        state.u8(self.filters.len() as u8);
+        for filter in &self.filters {
+            filter.save_state(state);
+        }
+    }
+
+    /// Loading drops any samples which haven't been read yet, since they came from before the state
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.region = state.region()?;
+        self.apu_cycle = state.bool()?;
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_counter.load_state(state)?;
+        self.mixed = state.f32()?;
+        self.resampler.load_state(state)?;
+        if state.u8()? as usize != self.filters.len() {
+            return Err(state.invalid());
+        }
+        for filter in self.filters.iter_mut() {
+            filter.load_state(state)?;
+        }
+        self.update_rates();
+        self.samples.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Timer periods in APU cycles, indexed by the low 4 bits of $400E
+const NTSC_PERIODS: [u16; 16] = [2, 4, 8, 16, 32, 48, 64, 80, 101, 127, 190, 254, 381, 508, 1017, 2034];
+const PAL_PERIODS: [u16; 16] = [2, 4, 7, 15, 30, 44, 59, 74, 94, 118, 177, 236, 354, 472, 945, 1889];
+
+/// The noise channel, $400C-$400F. A 15 bit linear feedback shift register gives pseudo-random bits,
+/// either from a sequence 32767 steps long, or from a short one of 93 (or 31) steps which sounds metallic
+pub(crate) struct Noise {
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+
+    /// Short mode, which takes the feedback from bit 6 instead of bit 1
+    short_mode: bool,
+    shift_register: u16,
+    /// The timer's reload value, in APU cycles
+    period: u16,
+    timer: u16,
+}
+
+impl Noise {
+    pub fn init() -> Self {
+        Self {
+            envelope: Envelope::init(),
+            length: LengthCounter::init(),
+
+            short_mode: false,
+            // Loaded with 1 at power on. It can never become 0
+            shift_register: 1,
+            period: NTSC_PERIODS[0],
+            timer: 0,
+        }
+    }
+
+    /// Writes to one of the channel's 4 registers, numbered from 0
+    pub fn write_register(&mut self, register: u16, value: u8, region: Region) {
+        match register {
+            // --LC VVVV: length counter halt / envelope loop, constant volume, volume / envelope period
+            0 => {
+                self.length.set_halted(value & 0x20 != 0);
+                self.envelope.write(value);
+            },
+            // Unused
+            1 => (),
+            // M--- PPPP: mode, period
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                let periods = match region {
+                    Region::Pal => &PAL_PERIODS,
+                    Region::Ntsc | Region::Dendy => &NTSC_PERIODS,
+                };
+                self.period = periods[(value & 0x0f) as usize] - 1;
+            },
+            // LLLL L---: length counter load, which also restarts the envelope
+            _ => {
+                self.length.load(value);
+                self.envelope.restart();
+            },
+        }
+    }
+
+    /// Clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The channel's current output level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.short_mode);
+        state.u16(self.shift_register);
+        state.u16(self.period);
+        state.u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.short_mode = state.bool()?;
+        self.shift_register = state.u16()?;
+        if self.shift_register == 0 {
+            return Err(state.invalid());
+        }
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The 4 duty cycles, as the 8 steps of the sequencer
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Which of the two pulse channels this is. They only differ in how the sweep unit negates
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum Channel {
+    /// Negates with ones' complement, so sweeping down subtracts one more than pulse 2 does
+    One,
+    Two,
+}
+
+/// One of the two square wave channels, $4000-$4003 and $4004-$4007
+pub(crate) struct Pulse {
+    channel: Channel,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    sweep: Sweep,
+
+    duty: usize,
+    step: usize,
+    /// The timer's reload value, in APU cycles
+    period: u16,
+    timer: u16,
+}
+
+/// Bends the pitch by periodically adding or subtracting a shifted copy of the period
+struct Sweep {
+    enabled: bool,
+    divider_period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Pulse {
+    pub fn init(channel: Channel) -> Self {
+        Self {
+            channel,
+            envelope: Envelope::init(),
+            length: LengthCounter::init(),
+            sweep: Sweep {
+                enabled: false,
+                divider_period: 0,
+                negate: false,
+                shift: 0,
+                divider: 0,
+                reload: false,
+            },
+
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    /// Writes to one of the channel's 4 registers, numbered from 0
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // DDLC VVVV: duty, length counter halt / envelope loop, constant volume, volume / envelope period
+            0 => {
+                self.duty = (value >> 6) as usize;
+                self.length.set_halted(value & 0x20 != 0);
+                self.envelope.write(value);
+            },
+            // EPPP NSSS: enabled, divider period, negate, shift
+            1 => {
+                self.sweep.enabled = value & 0x80 != 0;
+                self.sweep.divider_period = (value >> 4) & 0b111;
+                self.sweep.negate = value & 0x08 != 0;
+                self.sweep.shift = value & 0b111;
+                self.sweep.reload = true;
+            },
+            2 => self.period = (self.period & 0x700) | value as u16,
+            // LLLL LTTT: length counter load, high 3 bits of the period. This also restarts the note
+            _ => {
+                self.period = (self.period & 0xff) | (((value & 0b111) as u16) << 8);
+                self.length.load(value);
+                self.step = 0;
+                self.envelope.restart();
+            },
+        }
+    }
+
+    /// Clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked every half frame, along with the length counter
+    pub fn clock_sweep(&mut self) {
+        let target = self.target_period();
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.muted(target) {
+            self.period = target;
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.divider_period;
+            self.sweep.reload = false;
+        }
+        else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    /// The period the sweep unit is heading for. This is worked out all the time, whether or not
+    /// the sweep is enabled, and can mute the channel even when it isn't
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+        match (self.sweep.negate, self.channel) {
+            (false, _) => self.period + change,
+            (true, Channel::One) => self.period.saturating_sub(change + 1),
+            (true, Channel::Two) => self.period.saturating_sub(change),
+        }
+    }
+
+    /// Very high notes, or a sweep which would go past the top of the period's range, silence the channel
+    fn muted(&self, target: u16) -> bool {
+        self.period < 8 || target > 0x7ff
+    }
+
+    /// The channel's current output level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.muted(self.target_period()) || DUTY_CYCLES[self.duty][self.step] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.sweep.enabled);
+        state.u8(self.sweep.divider_period);
+        state.bool(self.sweep.negate);
+        state.u8(self.sweep.shift);
+        state.u8(self.sweep.divider);
+        state.bool(self.sweep.reload);
+        state.u8(self.duty as u8);
+        state.u8(self.step as u8);
+        state.u16(self.period);
+        state.u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.sweep.enabled = state.bool()?;
+        self.sweep.divider_period = state.u8()?;
+        self.sweep.negate = state.bool()?;
+        self.sweep.shift = state.u8()?;
+        self.sweep.divider = state.u8()?;
+        self.sweep.reload = state.bool()?;
+        // Both index tables, so are masked to stay in range
+        self.duty = state.u8()? as usize & 3;
+        self.step = state.u8()? as usize & 7;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        Ok(())
+    }
+}
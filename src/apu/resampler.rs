@@ -0,0 +1,131 @@
+use std::f64::consts::PI;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// How many output samples each step is spread over
+const KERNEL_WIDTH: usize = 16;
+/// How finely the position of a step between two output samples is resolved
+const PHASES: usize = 32;
+/// Cutoff as a fraction of the output sample rate, a little under Nyquist to leave room for the window
+const CUTOFF: f64 = 0.45;
+
+/// Turns the APU's output, which changes at arbitrary CPU cycles, into samples at the output rate
+/// without aliasing, by band-limited step synthesis.
+///
+/// The mixed output is a series of steps. Rather than sampling it, which would alias every sharp edge,
+/// each change in level is added to the output as a band-limited impulse: a windowed sinc, shifted to
+/// where the change happened between two samples. Integrating the impulses gives back band-limited steps.
+/// This is only any work when the level changes, which is much less often than once a cycle
+pub(crate) struct Resampler {
+    /// One windowed sinc for each phase, from 0 to 1 inclusive
+    kernels: Vec<[f32; KERNEL_WIDTH]>,
+    /// Output samples per CPU cycle
+    step: f64,
+    /// The current time, in output samples since the first entry of `impulses`
+    position: f64,
+    /// Changes in level waiting to be integrated, one entry per output sample
+    impulses: Vec<f32>,
+    /// The running total of the impulses integrated so far
+    level: f32,
+}
+
+impl Resampler {
+    pub fn init(clock_rate: f64, sample_rate: f64) -> Self {
+        let kernels = (0..=PHASES).map(|phase| kernel(phase as f64 / PHASES as f64)).collect();
+        Self {
+            kernels,
+            step: sample_rate / clock_rate,
+            position: 0.0,
+            impulses: vec![0.0; KERNEL_WIDTH * 2],
+            level: 0.0,
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.step = sample_rate / clock_rate;
+    }
+
+    /// Adds a change in level at the current time
+    pub fn add_delta(&mut self, delta: f32) {
+        let index = self.position as usize;
+        let phase = ((self.position - index as f64) * PHASES as f64).round() as usize;
+        if self.impulses.len() < index + KERNEL_WIDTH {
+            self.impulses.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (impulse, weight) in self.impulses[index..index + KERNEL_WIDTH].iter_mut().zip(&self.kernels[phase]) {
+            *impulse += delta * weight;
+        }
+    }
+
+    /// Moves on by one CPU cycle
+    pub fn clock(&mut self) {
+        self.position += self.step;
+    }
+
+    /// Number of samples which are finished, but haven't been flushed
+    pub fn pending(&self) -> usize {
+        self.position as usize
+    }
+
+    /// Integrates every sample which can't be changed any more, passing each one to `output`.
+    /// Later steps only ever add to samples at or after the current position
+    pub fn flush<F: FnMut(f32)>(&mut self, mut output: F) {
+        let finished = self.position as usize;
+        if self.impulses.len() < finished {
+            self.impulses.resize(finished, 0.0);
+        }
+        for impulse in self.impulses.drain(..finished) {
+            self.level += impulse;
+            output(self.level);
+        }
+        self.position -= finished as f64;
+        if self.impulses.len() < KERNEL_WIDTH * 2 {
+            self.impulses.resize(KERNEL_WIDTH * 2, 0.0);
+        }
+    }
+
+    /// The step isn't saved, as it depends on the output rate rather than anything emulated
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.f64(self.position);
+        state.f32s(&self.impulses);
+        state.f32(self.level);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let position = state.f64()?;
+        let impulses = state.f32s()?;
+        if !position.is_finite() || position < 0.0 {
+            return Err(state.invalid());
+        }
+        self.position = position;
+        self.impulses = impulses;
+        self.level = state.f32()?;
+        Ok(())
+    }
+}
+
+/// A Blackman windowed sinc, normalised so each step adds up to exactly its delta, centred on
+/// `offset` past the middle of the kernel
+fn kernel(offset: f64) -> [f32; KERNEL_WIDTH] {
+    let mut kernel = [0.0; KERNEL_WIDTH];
+    let mut total = 0.0;
+    for (i, value) in kernel.iter_mut().enumerate() {
+        let x = i as f64 - (KERNEL_WIDTH / 2) as f64 - offset;
+        let sinc = if x == 0.0 {
+            1.0
+        }
+        else {
+            (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x)
+        };
+        // The window spans the kernel, from -width/2 to width/2
+        let t = (x / KERNEL_WIDTH as f64 + 0.5).clamp(0.0, 1.0);
+        let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+        let weight = sinc * window;
+        *value = weight as f32;
+        total += weight;
+    }
+    for value in kernel.iter_mut() {
+        *value /= total as f32;
+    }
+    kernel
+}
@@ -0,0 +1,118 @@
+use super::length_counter::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The 32 step sequence the triangle plays: down from 15 to 0, then back up
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The triangle wave channel, $4008-$400B. It has no volume control, but does have a second,
+/// finer grained, counter to stop it: the linear counter, clocked every quarter frame
+pub(crate) struct Triangle {
+    pub length: LengthCounter,
+
+    /// Also the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+
+    step: usize,
+    /// The timer's reload value, in CPU cycles
+    period: u16,
+    timer: u16,
+}
+
+impl Triangle {
+    pub fn init() -> Self {
+        Self {
+            length: LengthCounter::init(),
+
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+
+            step: 0,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    /// Writes to one of the channel's 4 registers, numbered from 0
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // CRRR RRRR: control / length counter halt, linear counter reload value
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.set_halted(self.control);
+                self.linear_reload_value = value & 0x7f;
+            },
+            // Unused
+            1 => (),
+            2 => self.period = (self.period & 0x700) | value as u16,
+            // LLLL LTTT: length counter load, high 3 bits of the period
+            _ => {
+                self.period = (self.period & 0xff) | (((value & 0b111) as u16) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            },
+        }
+    }
+
+    /// Clocked every CPU cycle, unlike the other channels. The sequencer only moves while both counters
+    /// are non-zero, so silencing the triangle leaves it holding its last level rather than popping to 0
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear_counter > 0 && self.length.active() {
+                self.step = (self.step + 1) % 32;
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked every quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        }
+        else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    /// The channel's current output level, 0-15
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step]
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.u8(self.linear_counter);
+        state.bool(self.linear_reload);
+        state.u8(self.step as u8);
+        state.u16(self.period);
+        state.u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.length.load_state(state)?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_counter = state.u8()?;
+        self.linear_reload = state.bool()?;
+        self.step = state.u8()? as usize & 0x1f;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        Ok(())
+    }
+}
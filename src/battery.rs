@@ -0,0 +1,84 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+
+#[derive(Debug)]
+pub enum BatteryError {
+    Io(io::Error),
+    /// The save isn't the size of the cartridge's battery backed RAM
+    WrongSize {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for BatteryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatteryError::Io(e) => write!(f, "Couldn't read or write save file: {}", e),
+            BatteryError::WrongSize { expected, found } => {
+                write!(f, "Save file is {} bytes, but the cartridge has {} bytes of battery backed RAM", found, expected)
+            },
+        }
+    }
+}
+
+impl std::error::Error for BatteryError {}
+
+/// Where a ROM's battery backed RAM is kept: next to it, as game.sav
+pub fn sav_path<P: AsRef<Path>>(rom: P) -> PathBuf {
+    rom.as_ref().with_extension("sav")
+}
+
+/// A .sav file holding a cartridge's battery backed RAM, the way the battery would between power cycles.
+///
+/// The RAM is only written out when it has changed since the last time, so `flush` can be called
+/// as often as is convenient
+pub struct SaveFile {
+    path: PathBuf,
+    /// What the file holds, as far as we know
+    saved: Vec<u8>,
+}
+
+impl SaveFile {
+    /// Loads the save into the cartridge, if there is one yet. Returns None if the cartridge has no battery
+    pub fn open<P: AsRef<Path>>(cpu: &mut CPU, path: P) -> Result<Option<SaveFile>, BatteryError> {
+        if cpu.battery_ram_size() == 0 {
+            return Ok(None);
+        }
+        let path = path.as_ref().to_path_buf();
+        let saved = match fs::read(&path) {
+            Ok(data) => {
+                cpu.load_battery_ram(&data)?;
+                data
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => cpu.battery_ram(),
+            Err(e) => return Err(BatteryError::Io(e)),
+        };
+        Ok(Some(SaveFile {
+            path,
+            saved,
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the RAM out if it's changed, returning whether it had. The new contents go to a temporary
+    /// file which then replaces the old one, so being killed part way through can't lose the save
+    pub fn flush(&mut self, cpu: &CPU) -> Result<bool, BatteryError> {
+        let ram = cpu.battery_ram();
+        if ram == self.saved {
+            return Ok(false);
+        }
+        let temporary = self.path.with_extension("sav.tmp");
+        fs::write(&temporary, &ram).map_err(BatteryError::Io)?;
+        fs::rename(&temporary, &self.path).map_err(BatteryError::Io)?;
+        self.saved = ram;
+        Ok(true)
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller::Buttons;
+
+/// The frontend's settings, read from a TOML file.
+///
+/// Keys are named the way SDL names scancodes ("X", "Right Shift", "Keypad 8"), and game controller buttons
+/// the way SDL's game controller database does ("a", "back", "dpup", "leftshoulder"). Anything missing from
+/// the file gets its default, so a file only needs to mention what it changes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub player_1: PlayerBindings,
+    pub player_2: PlayerBindings,
+    pub turbo: Turbo,
+    pub hotkeys: Hotkeys,
+    pub video: Video,
+    pub rewind: Rewind,
+}
+
+/// What controls one player's joypad. Player 1 uses the first game controller connected, and player 2 the second.
+/// The defaults differ between the players, so anything missing is filled in by `Config::parse`, not by serde
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerBindings {
+    pub keyboard: Bindings,
+    pub controller: Bindings,
+}
+
+/// The keys or buttons bound to each button on the joypad. Each can have any number of them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bindings {
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub select: Vec<String>,
+    pub start: Vec<String>,
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+    /// Presses A repeatedly while held
+    pub turbo_a: Vec<String>,
+    /// Presses B repeatedly while held
+    pub turbo_b: Vec<String>,
+}
+
+/// What a key or button does to the joypad
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Binding {
+    /// Holds the buttons down for as long as it's held
+    Held(Buttons),
+    /// Presses the buttons repeatedly for as long as it's held
+    Turbo(Buttons),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Turbo {
+    /// Presses per second. Turbo buttons alternate between pressed and released every frame at most,
+    /// so anything over half the frame rate is the same as half the frame rate
+    pub rate: f64,
+}
+
+/// How the picture is drawn in the window
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Video {
+    /// The window starts at this many times the size of the picture
+    pub scale: u32,
+    pub scaling: Scaling,
+    pub pixel_aspect: PixelAspect,
+    /// Lines cut off the top of the picture. TVs hid around 8 lines at the top and bottom,
+    /// and games often leave garbage there
+    pub overscan_top: u32,
+    /// Lines cut off the bottom of the picture
+    pub overscan_bottom: u32,
+    pub fullscreen: bool,
+    pub pacing: Pacing,
+    /// A .pal file to use instead of the built in palette
+    pub palette: Option<PathBuf>,
+}
+
+/// How the picture is fitted into a window which isn't an exact multiple of its size
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scaling {
+    /// The largest whole multiple which fits, so every pixel is the same size
+    Integer,
+    /// As large as fits, keeping the aspect ratio
+    Fit,
+    /// Fills the window
+    Stretch,
+}
+
+/// The shape of each pixel
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelAspect {
+    Square,
+    /// 8:7, as an NTSC TV shows them
+    Ntsc,
+}
+
+/// What keeps the emulator running at the right speed
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pacing {
+    /// Waits for the display's vertical blank after each frame. Only right when the display runs at
+    /// the console's frame rate
+    Vsync,
+    /// Waits for the sound card to play through the queued audio
+    Audio,
+    /// Sleeps until the next frame is due
+    Timer,
+}
+
+impl PixelAspect {
+    /// Width of a pixel relative to its height
+    pub fn ratio(&self) -> f64 {
+        match self {
+            PixelAspect::Square => 1.0,
+            PixelAspect::Ntsc => 8.0 / 7.0,
+        }
+    }
+}
+
+/// Holding the rewind key runs the game backwards, as far back as the history goes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rewind {
+    pub enabled: bool,
+    /// How far back it can go
+    pub seconds: f64,
+    /// The most memory the history can take up, in megabytes. Whichever of this and `seconds` runs
+    /// out first decides how far back it can go
+    pub memory: usize,
+    /// Frames between snapshots. Fewer uses more memory, while more makes each step back slower
+    pub interval: usize,
+}
+
+/// Keys for controlling the emulator itself
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hotkeys {
+    pub pause: String,
+    pub reset: String,
+    /// Saves to the current slot
+    pub save_state: String,
+    /// Loads from the current slot
+    pub load_state: String,
+    /// Keys which pick the current save state slot, in order from slot 0. Up to ten are used
+    pub select_slot: Vec<String>,
+    /// Saves to a new file next to the ROM, rather than a slot, for loading with --state
+    pub export_state: String,
+    /// Runs as fast as possible while held
+    pub fast_forward: String,
+    /// Runs backwards while held
+    pub rewind: String,
+    pub screenshot: String,
+    pub fullscreen: String,
+    pub quit: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Couldn't read or write config: {}", e),
+            ConfigError::Parse(e) => write!(f, "Couldn't parse config: {}", e),
+            ConfigError::Serialize(e) => write!(f, "Couldn't write config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Puts everything in `overrides` over `defaults`. Tables are merged key by key, so a table in the file only
+/// changes what it mentions, while anything else, lists included, replaces the default outright
+fn merge(defaults: &mut toml::Value, overrides: toml::Value) {
+    match (defaults, overrides) {
+        (toml::Value::Table(defaults), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match defaults.get_mut(&key) {
+                    Some(default) => merge(default, value),
+                    None => {
+                        defaults.insert(key, value);
+                    },
+                }
+            }
+        },
+        (default, value) => *default = value,
+    }
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            player_1: PlayerBindings {
+                keyboard: Bindings {
+                    a: names(&["X"]),
+                    b: names(&["Z"]),
+                    select: names(&["Right Shift"]),
+                    start: names(&["Return"]),
+                    up: names(&["Up"]),
+                    down: names(&["Down"]),
+                    left: names(&["Left"]),
+                    right: names(&["Right"]),
+                    turbo_a: names(&["S"]),
+                    turbo_b: names(&["A"]),
+                },
+                controller: Bindings::default_controller(),
+            },
+            player_2: PlayerBindings {
+                keyboard: Bindings {
+                    a: names(&["M"]),
+                    b: names(&["N"]),
+                    select: names(&["U"]),
+                    start: names(&["O"]),
+                    up: names(&["I"]),
+                    down: names(&["K"]),
+                    left: names(&["J"]),
+                    right: names(&["L"]),
+                    turbo_a: Vec::new(),
+                    turbo_b: Vec::new(),
+                },
+                controller: Bindings::default_controller(),
+            },
+            turbo: Turbo::default(),
+            hotkeys: Hotkeys::default(),
+            video: Video::default(),
+            rewind: Rewind::default(),
+        }
+    }
+}
+
+impl Default for Turbo {
+    fn default() -> Self {
+        Self {
+            rate: 10.0,
+        }
+    }
+}
+
+impl Default for Video {
+    fn default() -> Self {
+        Self {
+            scale: 3,
+            scaling: Scaling::Integer,
+            pixel_aspect: PixelAspect::Ntsc,
+            overscan_top: 8,
+            overscan_bottom: 8,
+            fullscreen: false,
+            pacing: Pacing::Audio,
+            palette: None,
+        }
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seconds: 30.0,
+            memory: 256,
+            interval: 10,
+        }
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            pause: "P".to_string(),
+            reset: "R".to_string(),
+            save_state: "F5".to_string(),
+            load_state: "F7".to_string(),
+            select_slot: names(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]),
+            export_state: "F8".to_string(),
+            fast_forward: "Tab".to_string(),
+            rewind: "Backspace".to_string(),
+            screenshot: "F12".to_string(),
+            fullscreen: "F11".to_string(),
+            quit: "Escape".to_string(),
+        }
+    }
+}
+
+impl Bindings {
+    /// Buttons going by position: the NES's B and A are on the left and right, like X and A on most pads
+    fn default_controller() -> Self {
+        Self {
+            a: names(&["a", "b"]),
+            b: names(&["x", "y"]),
+            select: names(&["back"]),
+            start: names(&["start"]),
+            up: names(&["dpup"]),
+            down: names(&["dpdown"]),
+            left: names(&["dpleft"]),
+            right: names(&["dpright"]),
+            turbo_a: names(&["rightshoulder"]),
+            turbo_b: names(&["leftshoulder"]),
+        }
+    }
+
+    /// Every key or button name, with what it's bound to
+    pub fn entries(&self) -> Vec<(&str, Binding)> {
+        let bindings = [
+            (&self.a, Binding::Held(Buttons::A)),
+            (&self.b, Binding::Held(Buttons::B)),
+            (&self.select, Binding::Held(Buttons::SELECT)),
+            (&self.start, Binding::Held(Buttons::START)),
+            (&self.up, Binding::Held(Buttons::UP)),
+            (&self.down, Binding::Held(Buttons::DOWN)),
+            (&self.left, Binding::Held(Buttons::LEFT)),
+            (&self.right, Binding::Held(Buttons::RIGHT)),
+            (&self.turbo_a, Binding::Turbo(Buttons::A)),
+            (&self.turbo_b, Binding::Turbo(Buttons::B)),
+        ];
+        bindings.iter()
+            .flat_map(|(names, binding)| names.iter().map(move |name| (name.as_str(), *binding)))
+            .collect()
+    }
+}
+
+impl Turbo {
+    /// Whether turbo buttons are pressed on the given frame
+    pub fn pressed(&self, frame: u64, frame_rate: f64) -> bool {
+        let half_presses = (frame as f64 * self.rate * 2.0 / frame_rate) as u64;
+        half_presses & 1 == 0
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = toml::Value::try_from(Config::default()).map_err(ConfigError::Serialize)?;
+        merge(&mut config, toml::from_str(text).map_err(ConfigError::Parse)?);
+        config.try_into().map_err(ConfigError::Parse)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let text = toml::to_string_pretty(self).map_err(ConfigError::Serialize)?;
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent).map_err(ConfigError::Io)?;
+        }
+        fs::write(path, text).map_err(ConfigError::Io)
+    }
+
+    /// Loads the config at `path`, or writes out the defaults there if there isn't one yet
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        if path.as_ref().exists() {
+            return Config::load(path);
+        }
+        let config = Config::default();
+        config.save(path)?;
+        Ok(config)
+    }
+
+    /// Where the config lives if `--config` isn't given: neks/config.toml in the user's config directory,
+    /// or the current directory if there doesn't seem to be one
+    pub fn default_path() -> PathBuf {
+        let directory = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        match directory {
+            Some(directory) => directory.join("neks").join("config.toml"),
+            None => PathBuf::from("neks.toml"),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::{self, Write};
+
+use crate::cpu::CPU;
+use crate::ines::Cartridge;
+use crate::palette::Palette;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::savestate::StateError;
+
+/// A whole console, for running games without a frontend, e.g. in tests.
+///
+/// It runs a frame at a time, collecting the audio as it goes if asked to, and can write out what the
+/// screen showed, what was heard, and the state of RAM and the CPU's registers
+pub struct Console {
+    cpu: CPU,
+    record_audio: bool,
+    audio: Vec<f32>,
+}
+
+impl Console {
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self {
+            cpu: CPU::init(cartridge),
+            record_audio: false,
+            audio: Vec::new(),
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame(&self) -> u64 {
+        self.cpu.ppu().frame()
+    }
+
+    /// Starts or stops keeping the audio produced by each frame
+    pub fn set_record_audio(&mut self, record: bool) {
+        self.record_audio = record;
+    }
+
+    /// The audio recorded so far, at the APU's sample rate
+    pub fn audio(&self) -> &[f32] {
+        &self.audio
+    }
+
+    /// Runs until the PPU finishes the current frame
+    pub fn run_frame(&mut self) {
+        let frame = self.frame();
+        while self.frame() == frame {
+            self.cpu.step();
+        }
+        self.collect_audio();
+    }
+
+    pub fn run_frames(&mut self, frames: u64) {
+        for _ in 0..frames {
+            self.run_frame();
+        }
+    }
+
+    /// Runs until `condition` holds, checking it before each instruction, or until `max_frames` frames have
+    /// gone by. Returns whether the condition was met
+    pub fn run_until<F: FnMut(&CPU) -> bool>(&mut self, max_frames: u64, mut condition: F) -> bool {
+        let last_frame = self.frame() + max_frames;
+        let mut frame = self.frame();
+        while frame < last_frame {
+            if condition(&self.cpu) {
+                self.collect_audio();
+                return true;
+            }
+            self.cpu.step();
+            if self.frame() != frame {
+                frame = self.frame();
+                self.collect_audio();
+            }
+        }
+        condition(&self.cpu)
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    /// Restores a state from `save_state`. Audio recorded so far is kept, and recording carries on from the state
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.cpu.load_state(state)
+    }
+
+    fn collect_audio(&mut self) {
+        let start = self.audio.len();
+        self.cpu.apu_mut().read_samples(&mut self.audio);
+        if !self.record_audio {
+            self.audio.truncate(start);
+        }
+    }
+
+    /// Writes the last frame as a 256x240 PNG, using `palette` for the colours
+    pub fn write_png<W: Write>(&self, palette: &Palette, writer: W) -> io::Result<()> {
+        write_png(self.cpu.ppu().framebuffer(), palette, writer)
+    }
+
+    /// Writes the recorded audio as a mono 16 bit WAV file
+    pub fn write_wav<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let sample_rate = self.cpu.apu().sample_rate();
+        let data_length = self.audio.len() as u32 * 2;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(36 + data_length).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16_u32.to_le_bytes())?;
+        // PCM, 1 channel
+        writer.write_all(&1_u16.to_le_bytes())?;
+        writer.write_all(&1_u16.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        // Bytes per second, then bytes per sample, then bits per sample
+        writer.write_all(&(sample_rate * 2).to_le_bytes())?;
+        writer.write_all(&2_u16.to_le_bytes())?;
+        writer.write_all(&16_u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&data_length.to_le_bytes())?;
+        for sample in &self.audio {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the CPU's registers and the 2KB of RAM as text, for comparing between runs
+    pub fn write_snapshot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let registers = self.cpu.registers();
+        writeln!(writer, "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{} FRAME:{}",
+            registers.pc, registers.a, registers.x, registers.y, registers.p, registers.s,
+            self.cpu.cycles(), self.frame())?;
+        for row in (0..0x800_u16).step_by(16) {
+            let bytes: Vec<String> = (row..row + 16).map(|address| format!("{:02X}", self.cpu.peek(address))).collect();
+            writeln!(writer, "{:04X}: {}", row, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a frame from the PPU as a 256x240 PNG, using `palette` for the colours
+pub fn write_png<W: Write>(framebuffer: &[u16], palette: &Palette, writer: W) -> io::Result<()> {
+    let mut rgba = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+    palette.convert(framebuffer, &mut rgba);
+
+    let mut encoder = png::Encoder::new(writer, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&rgba).map_err(io::Error::other)
+}
@@ -0,0 +1,119 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use bitflags::*;
+
+bitflags! {
+    /// The buttons on a standard controller, in the order it reports them
+    pub struct Buttons: u8 {
+        const A = 0b00000001;
+        const B = 0b00000010;
+        const SELECT = 0b00000100;
+        const START = 0b00001000;
+        const UP = 0b00010000;
+        const DOWN = 0b00100000;
+        const LEFT = 0b01000000;
+        const RIGHT = 0b10000000;
+    }
+}
+
+/// An input device is shared between the memory bus, which reads it, and the frontend, which sets its state
+pub type SharedInputDevice = Rc<RefCell<dyn InputDevice>>;
+
+/// One of the two controller ports on the front of the console
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Port {
+    /// Read through $4016
+    One,
+    /// Read through $4017
+    Two,
+}
+
+/// Something plugged into one of the two controller ports.
+///
+/// Writes to $4016 set the OUT lines, which go to both ports at once. Reads from $4016 and $4017
+/// clock ports 1 and 2 respectively, and return whatever the device puts on D0-D4
+pub trait InputDevice {
+    /// A write to $4016. Only bit 0, OUT0, is used by standard controllers
+    fn write(&mut self, value: u8);
+    /// A read from the port's register. Only the low 5 bits are driven by the port; the rest are open bus
+    fn read(&mut self) -> u8;
+}
+
+/// The standard controller: a shift register, which is loaded with the buttons' state while OUT0 (the strobe) is
+/// high, and shifted out one button per read once it goes low
+pub struct Joypad {
+    buttons: Buttons,
+    strobe: bool,
+    shift_register: u8,
+    /// Reads past the 8th return 1, as the shift register is filled with 1s behind the buttons
+    reads: u8,
+}
+
+impl Joypad {
+    pub fn init() -> Self {
+        Self {
+            buttons: Buttons::empty(),
+            strobe: false,
+            shift_register: 0,
+            reads: 0,
+        }
+    }
+
+    pub fn shared() -> Rc<RefCell<Joypad>> {
+        Rc::new(RefCell::new(Joypad::init()))
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    /// Sets which buttons are held. Up and down, or left and right, can't both be pressed on a real controller,
+    /// and some games crash if they are, so the second of each pair is dropped
+    pub fn set_buttons(&mut self, mut buttons: Buttons) {
+        if buttons.contains(Buttons::UP | Buttons::DOWN) {
+            buttons.remove(Buttons::DOWN);
+        }
+        if buttons.contains(Buttons::LEFT | Buttons::RIGHT) {
+            buttons.remove(Buttons::RIGHT);
+        }
+        self.force_buttons(buttons);
+    }
+
+    /// Sets which buttons are held, even the impossible pairs `set_buttons` drops. Movies recorded
+    /// with them allowed need them to play back the same
+    pub fn force_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        self.shift_register = self.buttons.bits();
+        self.reads = 0;
+    }
+}
+
+impl InputDevice for Joypad {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.reload();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        // While the strobe is high the register keeps being reloaded, so it only ever returns A
+        if self.strobe {
+            return self.buttons.contains(Buttons::A) as u8;
+        }
+        if self.reads >= 8 {
+            return 1;
+        }
+        let bit = self.shift_register & 1;
+        self.shift_register >>= 1;
+        self.reads += 1;
+        bit
+    }
+}
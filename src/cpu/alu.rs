@@ -15,7 +15,8 @@ pub fn adc(a: u8, b: u8, carry_in: bool) -> (u8, bool, bool) {
         },
         false => a.overflowing_add(b)
     };
-    let overflow = (a ^ value) & (b ^ value) & 0x80 == 0;
+    // Overflow happens when both inputs have the same sign, and the result's sign differs from them
+    let overflow = (a ^ value) & (b ^ value) & 0x80 != 0;
     (value, carry_out, overflow)
 }
 
@@ -28,9 +29,9 @@ pub fn asl(a: u8) -> (u8, bool) {
     (value, carry)
 }
 
-// This is basically sbc without overflow or carry in
+// This is basically sbc without overflow, and with the carry in always set
 pub fn cmp(a: u8, b: u8) -> (u8, bool) {
-    let (value, carry, _) = adc(a, !b, false);
+    let (value, carry, _) = adc(a, !b, true);
     (value, carry)
 }
 
@@ -43,8 +44,9 @@ pub fn eor(a: u8, b: u8) -> u8 {
     a ^ b
 }
 
+/// Returns (a >> 1, carry_out) where carry_out is the old bit 0 of `a'
 pub fn lsr(a: u8) -> (u8, bool) {
-    a.overflowing_shr(1)
+    (a >> 1, a & 1 == 1)
 }
 
 pub fn inc(a: u8) -> u8 {
@@ -66,7 +68,7 @@ pub fn rol(a: u8, carry_in: bool) -> (u8, bool) {
 /// returning (shifted_a, carry_out)
 /// where carry_out is the old bit 0 of `a'
 pub fn ror(a: u8, carry_in: bool) -> (u8, bool) {
-    let (value, carry_out) = a.overflowing_shr(1);
+    let (value, carry_out) = lsr(a);
     let carry = if carry_in {0b10000000} else {0};
     (value | carry, carry_out)
 }
@@ -1,6 +1,9 @@
 use super::register::Flags;
 
-#[derive(Debug)]
+use Instruction::*;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy,Clone,Debug)]
 pub(crate) enum Instruction {
     ADC(AddressMode),
     AND(AddressMode),
@@ -53,6 +56,50 @@ pub(crate) enum Instruction {
     TXA,
     TXS,
     TYA,
+
+    // Unofficial instructions. Names follow the NESdev wiki where there's any disagreement
+    /// AND #i, then copy N into C
+    ANC(AddressMode),
+    /// AND #i, then LSR A
+    ALR(AddressMode),
+    /// AND #i, then ROR A, with C and V set from bits 6 and 5 of the result
+    ARR(AddressMode),
+    /// X = (A & X) - #i, setting flags like CMP
+    AXS(AddressMode),
+    /// DEC then CMP
+    DCP(AddressMode),
+    /// A NOP which reads its operand, with all the timing (and side effects) of doing so
+    IGN(AddressMode),
+    /// INC then SBC
+    ISC(AddressMode),
+    /// Halts the CPU until it is reset
+    JAM,
+    /// A, X and S = memory & S
+    LAS(AddressMode),
+    /// LDA then TAX
+    LAX(AddressMode),
+    /// A = X = (A | magic) & #i. Unstable on real hardware
+    LXA(AddressMode),
+    /// ROL then AND
+    RLA(AddressMode),
+    /// ROR then ADC
+    RRA(AddressMode),
+    /// Stores A & X
+    SAX(AddressMode),
+    /// Stores A & X & (high byte of address + 1). Unstable on real hardware
+    SHA(AddressMode),
+    /// Stores X & (high byte of address + 1). Unstable on real hardware
+    SHX(AddressMode),
+    /// Stores Y & (high byte of address + 1). Unstable on real hardware
+    SHY(AddressMode),
+    /// ASL then ORA
+    SLO(AddressMode),
+    /// LSR then EOR
+    SRE(AddressMode),
+    /// S = A & X, then stores S & (high byte of address + 1). Unstable on real hardware
+    TAS(AddressMode),
+    /// A = (A | magic) & X & #i. Unstable on real hardware
+    XAA(AddressMode),
 }
 
 #[derive(Copy,Clone,Debug)]
@@ -71,161 +118,325 @@ pub(crate) enum AddressMode {
     ZeroPageY,
 }
 
+impl Instruction {
+    /// The assembler mnemonic, using the names from nestest.log for the unofficial instructions
+    pub fn mnemonic(&self) -> String {
+        match self {
+            CLR(flag) if *flag == Flags::C => "CLC".to_string(),
+            CLR(flag) if *flag == Flags::D => "CLD".to_string(),
+            CLR(flag) if *flag == Flags::I => "CLI".to_string(),
+            CLR(_) => "CLV".to_string(),
+            SET(flag) if *flag == Flags::C => "SEC".to_string(),
+            SET(flag) if *flag == Flags::D => "SED".to_string(),
+            SET(_) => "SEI".to_string(),
+            IGN(_) => "NOP".to_string(),
+            ISC(_) => "ISB".to_string(),
+            // Everything else is named after its variant, so strip the address mode from the debug output
+            _ => {
+                let name = format!("{:?}", self);
+                name.split('(').next().unwrap().to_string()
+            }
+        }
+    }
+
+    /// How the operand bytes are used. Implied instructions, which have no operand, return None
+    pub fn address_mode(&self) -> Option<AddressMode> {
+        match *self {
+            BCC | BCS | BEQ | BMI | BNE | BPL | BVC | BVS => Some(AddressMode::Relative),
+            JSR => Some(AddressMode::Absolute),
+            ADC(mode) | AND(mode) | ASL(mode) | BIT(mode) | CMP(mode) | CPX(mode) | CPY(mode) |
+            DEC(mode) | EOR(mode) | INC(mode) | JMP(mode) | LDA(mode) | LDX(mode) | LDY(mode) |
+            LSR(mode) | ORA(mode) | ROL(mode) | ROR(mode) | SBC(mode) | STA(mode) | STX(mode) |
+            STY(mode) | ALR(mode) | ANC(mode) | ARR(mode) | AXS(mode) | DCP(mode) | IGN(mode) |
+            ISC(mode) | LAS(mode) | LAX(mode) | LXA(mode) | RLA(mode) | RRA(mode) | SAX(mode) |
+            SHA(mode) | SHX(mode) | SHY(mode) | SLO(mode) | SRE(mode) | TAS(mode) | XAA(mode) => Some(mode),
+            _ => None,
+        }
+    }
+
+    /// Length in bytes of the instruction, including the opcode
+    pub fn length(&self) -> u16 {
+        match self.address_mode() {
+            None | Some(AddressMode::Accumulator) => 1,
+            Some(AddressMode::Absolute) | Some(AddressMode::AbsoluteX) |
+            Some(AddressMode::AbsoluteY) | Some(AddressMode::Indirect) => 3,
+            Some(_) => 2,
+        }
+    }
+}
+
+/// Whether the opcode is one of the undocumented ones, which MOS never intended to be used
+pub(crate) fn is_unofficial(opcode: u8) -> bool {
+    match decode(opcode) {
+        NOP => opcode != 0xEA,
+        SBC(_) => opcode == 0xEB,
+        ALR(_) | ANC(_) | ARR(_) | AXS(_) | DCP(_) | IGN(_) | ISC(_) | JAM | LAS(_) | LAX(_) |
+        LXA(_) | RLA(_) | RRA(_) | SAX(_) | SHA(_) | SHX(_) | SHY(_) | SLO(_) | SRE(_) | TAS(_) |
+        XAA(_) => true,
+        _ => false,
+    }
+}
+
+/// Every one of the 256 opcodes does something on the NMOS 6502.
+/// The unofficial ones fall out of the way the decode ROM combines the official instruction
+/// groups, and are used by enough games and test ROMs that they need to be emulated too
 pub(crate) fn decode(opcode: u8) -> Instruction {
     match opcode {
         0x00 => Instruction::BRK,
         0x01 => Instruction::ORA(AddressMode::XIndirect),
+        0x02 => Instruction::JAM,
+        0x03 => Instruction::SLO(AddressMode::XIndirect),
+        0x04 => Instruction::IGN(AddressMode::ZeroPage),
         0x05 => Instruction::ORA(AddressMode::ZeroPage),
         0x06 => Instruction::ASL(AddressMode::ZeroPage),
+        0x07 => Instruction::SLO(AddressMode::ZeroPage),
         0x08 => Instruction::PHP,
         0x09 => Instruction::ORA(AddressMode::Immediate),
         0x0A => Instruction::ASL(AddressMode::Accumulator),
+        0x0B => Instruction::ANC(AddressMode::Immediate),
+        0x0C => Instruction::IGN(AddressMode::Absolute),
         0x0D => Instruction::ORA(AddressMode::Absolute),
-        0x0E => Instruction::ASL(AddressMode::Accumulator),
+        0x0E => Instruction::ASL(AddressMode::Absolute),
+        0x0F => Instruction::SLO(AddressMode::Absolute),
         0x10 => Instruction::BPL,
         0x11 => Instruction::ORA(AddressMode::IndirectY),
+        0x12 => Instruction::JAM,
+        0x13 => Instruction::SLO(AddressMode::IndirectY),
+        0x14 => Instruction::IGN(AddressMode::ZeroPageX),
         0x15 => Instruction::ORA(AddressMode::ZeroPageX),
         0x16 => Instruction::ASL(AddressMode::ZeroPageX),
+        0x17 => Instruction::SLO(AddressMode::ZeroPageX),
         0x18 => Instruction::CLR(Flags::C),
         0x19 => Instruction::ORA(AddressMode::AbsoluteY),
+        0x1A => Instruction::NOP,
+        0x1B => Instruction::SLO(AddressMode::AbsoluteY),
+        0x1C => Instruction::IGN(AddressMode::AbsoluteX),
         0x1D => Instruction::ORA(AddressMode::AbsoluteX),
         0x1E => Instruction::ASL(AddressMode::AbsoluteX),
+        0x1F => Instruction::SLO(AddressMode::AbsoluteX),
         0x20 => Instruction::JSR,
         0x21 => Instruction::AND(AddressMode::XIndirect),
+        0x22 => Instruction::JAM,
+        0x23 => Instruction::RLA(AddressMode::XIndirect),
         0x24 => Instruction::BIT(AddressMode::ZeroPage),
         0x25 => Instruction::AND(AddressMode::ZeroPage),
         0x26 => Instruction::ROL(AddressMode::ZeroPage),
+        0x27 => Instruction::RLA(AddressMode::ZeroPage),
         0x28 => Instruction::PLP,
         0x29 => Instruction::AND(AddressMode::Immediate),
         0x2A => Instruction::ROL(AddressMode::Accumulator),
+        0x2B => Instruction::ANC(AddressMode::Immediate),
         0x2C => Instruction::BIT(AddressMode::Absolute),
         0x2D => Instruction::AND(AddressMode::Absolute),
         0x2E => Instruction::ROL(AddressMode::Absolute),
+        0x2F => Instruction::RLA(AddressMode::Absolute),
         0x30 => Instruction::BMI,
         0x31 => Instruction::AND(AddressMode::IndirectY),
+        0x32 => Instruction::JAM,
+        0x33 => Instruction::RLA(AddressMode::IndirectY),
+        0x34 => Instruction::IGN(AddressMode::ZeroPageX),
         0x35 => Instruction::AND(AddressMode::ZeroPageX),
         0x36 => Instruction::ROL(AddressMode::ZeroPageX),
+        0x37 => Instruction::RLA(AddressMode::ZeroPageX),
         0x38 => Instruction::SET(Flags::C),
         0x39 => Instruction::AND(AddressMode::AbsoluteY),
+        0x3A => Instruction::NOP,
+        0x3B => Instruction::RLA(AddressMode::AbsoluteY),
+        0x3C => Instruction::IGN(AddressMode::AbsoluteX),
         0x3D => Instruction::AND(AddressMode::AbsoluteX),
         0x3E => Instruction::ROL(AddressMode::AbsoluteX),
+        0x3F => Instruction::RLA(AddressMode::AbsoluteX),
         0x40 => Instruction::RTI,
         0x41 => Instruction::EOR(AddressMode::XIndirect),
+        0x42 => Instruction::JAM,
+        0x43 => Instruction::SRE(AddressMode::XIndirect),
+        0x44 => Instruction::IGN(AddressMode::ZeroPage),
         0x45 => Instruction::EOR(AddressMode::ZeroPage),
         0x46 => Instruction::LSR(AddressMode::ZeroPage),
+        0x47 => Instruction::SRE(AddressMode::ZeroPage),
         0x48 => Instruction::PHA,
         0x49 => Instruction::EOR(AddressMode::Immediate),
         0x4A => Instruction::LSR(AddressMode::Accumulator),
+        0x4B => Instruction::ALR(AddressMode::Immediate),
         0x4C => Instruction::JMP(AddressMode::Absolute),
         0x4D => Instruction::EOR(AddressMode::Absolute),
         0x4E => Instruction::LSR(AddressMode::Absolute),
+        0x4F => Instruction::SRE(AddressMode::Absolute),
         0x50 => Instruction::BVC,
         0x51 => Instruction::EOR(AddressMode::IndirectY),
+        0x52 => Instruction::JAM,
+        0x53 => Instruction::SRE(AddressMode::IndirectY),
+        0x54 => Instruction::IGN(AddressMode::ZeroPageX),
         0x55 => Instruction::EOR(AddressMode::ZeroPageX),
         0x56 => Instruction::LSR(AddressMode::ZeroPageX),
+        0x57 => Instruction::SRE(AddressMode::ZeroPageX),
         0x58 => Instruction::CLR(Flags::I),
         0x59 => Instruction::EOR(AddressMode::AbsoluteY),
+        0x5A => Instruction::NOP,
+        0x5B => Instruction::SRE(AddressMode::AbsoluteY),
+        0x5C => Instruction::IGN(AddressMode::AbsoluteX),
         0x5D => Instruction::EOR(AddressMode::AbsoluteX),
         0x5E => Instruction::LSR(AddressMode::AbsoluteX),
+        0x5F => Instruction::SRE(AddressMode::AbsoluteX),
         0x60 => Instruction::RTS,
         0x61 => Instruction::ADC(AddressMode::XIndirect),
+        0x62 => Instruction::JAM,
+        0x63 => Instruction::RRA(AddressMode::XIndirect),
+        0x64 => Instruction::IGN(AddressMode::ZeroPage),
         0x65 => Instruction::ADC(AddressMode::ZeroPage),
         0x66 => Instruction::ROR(AddressMode::ZeroPage),
+        0x67 => Instruction::RRA(AddressMode::ZeroPage),
         0x68 => Instruction::PLA,
         0x69 => Instruction::ADC(AddressMode::Immediate),
         0x6A => Instruction::ROR(AddressMode::Accumulator),
+        0x6B => Instruction::ARR(AddressMode::Immediate),
         0x6C => Instruction::JMP(AddressMode::Indirect),
         0x6D => Instruction::ADC(AddressMode::Absolute),
         0x6E => Instruction::ROR(AddressMode::Absolute),
+        0x6F => Instruction::RRA(AddressMode::Absolute),
         0x70 => Instruction::BVS,
         0x71 => Instruction::ADC(AddressMode::IndirectY),
+        0x72 => Instruction::JAM,
+        0x73 => Instruction::RRA(AddressMode::IndirectY),
+        0x74 => Instruction::IGN(AddressMode::ZeroPageX),
         0x75 => Instruction::ADC(AddressMode::ZeroPageX),
         0x76 => Instruction::ROR(AddressMode::ZeroPageX),
+        0x77 => Instruction::RRA(AddressMode::ZeroPageX),
         0x78 => Instruction::SET(Flags::I),
         0x79 => Instruction::ADC(AddressMode::AbsoluteY),
+        0x7A => Instruction::NOP,
+        0x7B => Instruction::RRA(AddressMode::AbsoluteY),
+        0x7C => Instruction::IGN(AddressMode::AbsoluteX),
         0x7D => Instruction::ADC(AddressMode::AbsoluteX),
         0x7E => Instruction::ROR(AddressMode::AbsoluteX),
+        0x7F => Instruction::RRA(AddressMode::AbsoluteX),
+        0x80 => Instruction::IGN(AddressMode::Immediate),
         0x81 => Instruction::STA(AddressMode::XIndirect),
+        0x82 => Instruction::IGN(AddressMode::Immediate),
+        0x83 => Instruction::SAX(AddressMode::XIndirect),
         0x84 => Instruction::STY(AddressMode::ZeroPage),
         0x85 => Instruction::STA(AddressMode::ZeroPage),
         0x86 => Instruction::STX(AddressMode::ZeroPage),
+        0x87 => Instruction::SAX(AddressMode::ZeroPage),
         0x88 => Instruction::DEY,
+        0x89 => Instruction::IGN(AddressMode::Immediate),
         0x8A => Instruction::TXA,
+        0x8B => Instruction::XAA(AddressMode::Immediate),
         0x8C => Instruction::STY(AddressMode::Absolute),
         0x8D => Instruction::STA(AddressMode::Absolute),
         0x8E => Instruction::STX(AddressMode::Absolute),
+        0x8F => Instruction::SAX(AddressMode::Absolute),
         0x90 => Instruction::BCC,
         0x91 => Instruction::STA(AddressMode::IndirectY),
+        0x92 => Instruction::JAM,
+        0x93 => Instruction::SHA(AddressMode::IndirectY),
         0x94 => Instruction::STY(AddressMode::ZeroPageX),
         0x95 => Instruction::STA(AddressMode::ZeroPageX),
         0x96 => Instruction::STX(AddressMode::ZeroPageY),
+        0x97 => Instruction::SAX(AddressMode::ZeroPageY),
         0x98 => Instruction::TYA,
         0x99 => Instruction::STA(AddressMode::AbsoluteY),
         0x9A => Instruction::TXS,
+        0x9B => Instruction::TAS(AddressMode::AbsoluteY),
+        0x9C => Instruction::SHY(AddressMode::AbsoluteX),
         0x9D => Instruction::STA(AddressMode::AbsoluteX),
+        0x9E => Instruction::SHX(AddressMode::AbsoluteY),
+        0x9F => Instruction::SHA(AddressMode::AbsoluteY),
         0xA0 => Instruction::LDY(AddressMode::Immediate),
         0xA1 => Instruction::LDA(AddressMode::XIndirect),
         0xA2 => Instruction::LDX(AddressMode::Immediate),
+        0xA3 => Instruction::LAX(AddressMode::XIndirect),
         0xA4 => Instruction::LDY(AddressMode::ZeroPage),
         0xA5 => Instruction::LDA(AddressMode::ZeroPage),
         0xA6 => Instruction::LDX(AddressMode::ZeroPage),
+        0xA7 => Instruction::LAX(AddressMode::ZeroPage),
         0xA8 => Instruction::TAY,
         0xA9 => Instruction::LDA(AddressMode::Immediate),
         0xAA => Instruction::TAX,
+        0xAB => Instruction::LXA(AddressMode::Immediate),
         0xAC => Instruction::LDY(AddressMode::Absolute),
         0xAD => Instruction::LDA(AddressMode::Absolute),
         0xAE => Instruction::LDX(AddressMode::Absolute),
+        0xAF => Instruction::LAX(AddressMode::Absolute),
         0xB0 => Instruction::BCS,
         0xB1 => Instruction::LDA(AddressMode::IndirectY),
+        0xB2 => Instruction::JAM,
+        0xB3 => Instruction::LAX(AddressMode::IndirectY),
         0xB4 => Instruction::LDY(AddressMode::ZeroPageX),
         0xB5 => Instruction::LDA(AddressMode::ZeroPageX),
         0xB6 => Instruction::LDX(AddressMode::ZeroPageY),
+        0xB7 => Instruction::LAX(AddressMode::ZeroPageY),
         0xB8 => Instruction::CLR(Flags::V),
         0xB9 => Instruction::LDA(AddressMode::AbsoluteY),
         0xBA => Instruction::TSX,
+        0xBB => Instruction::LAS(AddressMode::AbsoluteY),
         0xBC => Instruction::LDY(AddressMode::AbsoluteX),
         0xBD => Instruction::LDA(AddressMode::AbsoluteX),
         0xBE => Instruction::LDX(AddressMode::AbsoluteY),
+        0xBF => Instruction::LAX(AddressMode::AbsoluteY),
         0xC0 => Instruction::CPY(AddressMode::Immediate),
         0xC1 => Instruction::CMP(AddressMode::XIndirect),
+        0xC2 => Instruction::IGN(AddressMode::Immediate),
+        0xC3 => Instruction::DCP(AddressMode::XIndirect),
         0xC4 => Instruction::CPY(AddressMode::ZeroPage),
         0xC5 => Instruction::CMP(AddressMode::ZeroPage),
         0xC6 => Instruction::DEC(AddressMode::ZeroPage),
+        0xC7 => Instruction::DCP(AddressMode::ZeroPage),
         0xC8 => Instruction::INY,
         0xC9 => Instruction::CMP(AddressMode::Immediate),
         0xCA => Instruction::DEX,
+        0xCB => Instruction::AXS(AddressMode::Immediate),
         0xCC => Instruction::CPY(AddressMode::Absolute),
         0xCD => Instruction::CMP(AddressMode::Absolute),
         0xCE => Instruction::DEC(AddressMode::Absolute),
+        0xCF => Instruction::DCP(AddressMode::Absolute),
         0xD0 => Instruction::BNE,
         0xD1 => Instruction::CMP(AddressMode::IndirectY),
+        0xD2 => Instruction::JAM,
+        0xD3 => Instruction::DCP(AddressMode::IndirectY),
+        0xD4 => Instruction::IGN(AddressMode::ZeroPageX),
         0xD5 => Instruction::CMP(AddressMode::ZeroPageX),
         0xD6 => Instruction::DEC(AddressMode::ZeroPageX),
+        0xD7 => Instruction::DCP(AddressMode::ZeroPageX),
         0xD8 => Instruction::CLR(Flags::D),
         0xD9 => Instruction::CMP(AddressMode::AbsoluteY),
+        0xDA => Instruction::NOP,
+        0xDB => Instruction::DCP(AddressMode::AbsoluteY),
+        0xDC => Instruction::IGN(AddressMode::AbsoluteX),
         0xDD => Instruction::CMP(AddressMode::AbsoluteX),
         0xDE => Instruction::DEC(AddressMode::AbsoluteX),
+        0xDF => Instruction::DCP(AddressMode::AbsoluteX),
         0xE0 => Instruction::CPX(AddressMode::Immediate),
         0xE1 => Instruction::SBC(AddressMode::XIndirect),
+        0xE2 => Instruction::IGN(AddressMode::Immediate),
+        0xE3 => Instruction::ISC(AddressMode::XIndirect),
         0xE4 => Instruction::CPX(AddressMode::ZeroPage),
         0xE5 => Instruction::SBC(AddressMode::ZeroPage),
         0xE6 => Instruction::INC(AddressMode::ZeroPage),
+        0xE7 => Instruction::ISC(AddressMode::ZeroPage),
         0xE8 => Instruction::INX,
         0xE9 => Instruction::SBC(AddressMode::Immediate),
         0xEA => Instruction::NOP,
+        0xEB => Instruction::SBC(AddressMode::Immediate),
         0xEC => Instruction::CPX(AddressMode::Absolute),
         0xED => Instruction::SBC(AddressMode::Absolute),
         0xEE => Instruction::INC(AddressMode::Absolute),
+        0xEF => Instruction::ISC(AddressMode::Absolute),
         0xF0 => Instruction::BEQ,
         0xF1 => Instruction::SBC(AddressMode::IndirectY),
+        0xF2 => Instruction::JAM,
+        0xF3 => Instruction::ISC(AddressMode::IndirectY),
+        0xF4 => Instruction::IGN(AddressMode::ZeroPageX),
         0xF5 => Instruction::SBC(AddressMode::ZeroPageX),
         0xF6 => Instruction::INC(AddressMode::ZeroPageX),
+        0xF7 => Instruction::ISC(AddressMode::ZeroPageX),
         0xF8 => Instruction::SET(Flags::D),
-        0xF9 => Instruction::SBC(AddressMode::AbsoluteX),
+        0xF9 => Instruction::SBC(AddressMode::AbsoluteY),
+        0xFA => Instruction::NOP,
+        0xFB => Instruction::ISC(AddressMode::AbsoluteY),
+        0xFC => Instruction::IGN(AddressMode::AbsoluteX),
         0xFD => Instruction::SBC(AddressMode::AbsoluteX),
         0xFE => Instruction::INC(AddressMode::AbsoluteX),
-        _ => panic!("Unsupported instruction"),
+        0xFF => Instruction::ISC(AddressMode::AbsoluteX),
     }
 }
-
-
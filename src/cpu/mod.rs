@@ -1,23 +1,59 @@
 mod instructions;
 mod register;
 mod alu;
+mod trace;
 
 use std::convert::TryInto;
 
+use crate::battery::BatteryError;
 use crate::ines::Cartridge;
-use crate::memory::MemoryBus;
+use crate::memory::{IrqSource, MemoryBus, WatchHit, Watchpoint};
+use crate::apu::APU;
+use crate::controller::{Port, SharedInputDevice};
+use crate::ppu::PPU;
+use crate::region::Region;
+use crate::savestate::{self, StateError, StateReader, StateWriter};
+
+pub use register::Registers;
+pub use trace::Trace;
 
 use register::{Flags, RegisterBank};
 use instructions::{AddressMode, Instruction};
 use instructions::AddressMode::*;
 use instructions::Instruction::*;
-use alu::*;
+
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+
+/// XAA and LXA OR A with a value which depends on the individual chip and its temperature,
+/// before doing the AND. This is the most commonly observed value
+const UNSTABLE_MAGIC: u8 = 0xee;
 
 #[inline]
 fn le_address_16(low: u8, high: u8) -> u16 {
     ((high as u16) << 8) | (low as u16)
 }
 
+/// The interrupts which can be raised by hardware outside the CPU.
+/// RESET is handled separately, since it abandons whatever the CPU was doing
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Interrupt {
+    NMI,
+    IRQ,
+}
+
+type TraceHook = Box<dyn FnMut(&Trace)>;
+
+/// How an instruction uses its effective address, which decides how many cycles the addressing takes
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
 #[allow(non_snake_case)]
 pub struct CPU {
     registers: RegisterBank,
@@ -29,9 +65,20 @@ pub struct CPU {
     is_running: bool,
 
     address_line: u16,
+    /// Whether indexing carried into the high byte of address_line
+    page_crossed: bool,
 
     opcode: u8,
     next_opcode: u8,
+
+    /// An interrupt which was detected when polling at the end of the last instruction,
+    /// and which will be serviced instead of fetching the next one
+    pending_interrupt: Option<Interrupt>,
+    /// Set by the JAM instructions. The CPU stops executing until it is reset
+    jammed: bool,
+
+    /// Called before every instruction is executed, if set
+    trace_hook: Option<TraceHook>,
 }
 
 impl CPU {
@@ -41,27 +88,264 @@ impl CPU {
             PC: 0,
 
             memory: MemoryBus::init(),
-            cartridge: cartridge,
+            cartridge,
 
             is_running: false,
 
             address_line: 0,
+            page_crossed: false,
 
             opcode: 0,
             next_opcode: 0,
+
+            pending_interrupt: None,
+            jammed: false,
+
+            trace_hook: None,
         };
 
         // Load cartridge into locations 0x8000 -> 0xffff
         cpu.memory.load_cartridge(&cpu.cartridge);
 
-        // Location of the so-called reset vector
-        let low = cpu.memory.read_byte(0xfffc);
-        let high = cpu.memory.read_byte(0xfffd);
-        cpu.PC = ((high as u16) << 8) | (low as u16);
+        cpu.reset();
 
         cpu
     }
 
+    /// Powers on as normal, then overwrites the registers once the reset sequence is over.
+    /// This is mostly useful for test ROMs like nestest, which have an automated mode that is only
+    /// reached by starting at an address other than the one in the reset vector
+    pub fn init_with_registers(cartridge: Cartridge, registers: Registers) -> Self {
+        let mut cpu = Self::init(cartridge);
+        cpu.set_registers(registers);
+        cpu
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.PC,
+            a: self.registers.A,
+            x: self.registers.X,
+            y: self.registers.Y,
+            s: self.registers.S,
+            p: u8::from(self.registers.P),
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.PC = registers.pc;
+        self.registers.A = registers.a;
+        self.registers.X = registers.x;
+        self.registers.Y = registers.y;
+        self.registers.S = registers.s;
+        self.registers.P = (Flags::from(registers.p) - Flags::B) | Flags::U;
+    }
+
+    /// Reads from the CPU's address space without side effects, for debugging
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+
+    /// Writes to the CPU's address space as an instruction would, side effects and all, but without taking
+    /// any time, for the debugger
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory.write_byte(address, value);
+    }
+
+    /// Disassembles the instruction at `address`, returning its bytes and the assembler text.
+    /// The addresses and values shown for its operand are worked out from the registers as they are now
+    pub fn disassemble(&self, address: u16) -> (Vec<u8>, String) {
+        let memory = &self.memory;
+        trace::disassemble(|address| memory.peek(address), address, &self.registers())
+    }
+
+    /// Sets the addresses to watch for reads and writes. Execute watchpoints are ignored here, since the
+    /// debugger checks those itself before each instruction
+    pub fn set_watchpoints(&mut self, watchpoints: &[Watchpoint]) {
+        self.memory.set_watchpoints(watchpoints);
+    }
+
+    /// The first read or write a watchpoint caught since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.memory.take_watch_hit()
+    }
+
+    /// Sets a function to be called with the state of the machine before each instruction is executed.
+    /// Interrupts aren't traced, but the first instruction of the handler is
+    pub fn set_trace_hook<F: FnMut(&Trace) + 'static>(&mut self, hook: F) {
+        self.trace_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    /// Runs the RESET sequence, as if the reset button had been pressed.
+    /// This takes 7 cycles, like any other interrupt. The CPU goes through the motions of pushing the
+    /// return address and status, but the write line is held inactive so the stack is only read from,
+    /// leaving S three lower than it was. A, X and Y are left untouched.
+    pub fn reset(&mut self) {
+        self.pending_interrupt = None;
+        self.jammed = false;
+        self.memory.read(self.PC);
+        self.memory.read(self.PC);
+        for _ in 0..3 {
+            self.memory.read(self.stack_address());
+            self.registers.S = alu::dec(self.registers.S);
+        }
+        self.registers.P.insert(Flags::I);
+        self.PC = self.read_vector(RESET_VECTOR);
+    }
+
+    /// Switches the console off and on again. Everything starts over as it does in `init`, apart from the
+    /// battery backed RAM, which the battery keeps, and what's plugged into the ports
+    pub fn power_cycle(&mut self) {
+        let battery_ram = self.battery_ram();
+        self.memory.power_cycle(&self.cartridge);
+        self.load_battery_ram(&battery_ram).expect("The same cartridge has the same battery backed RAM");
+        self.registers = RegisterBank::init();
+        self.PC = 0;
+        self.address_line = 0;
+        self.page_crossed = false;
+        self.opcode = 0;
+        self.next_opcode = 0;
+        self.reset();
+    }
+
+    /// Whether a JAM instruction has locked up the CPU
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    /// Number of CPU cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.memory.cycles()
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        self.memory.ppu()
+    }
+
+    pub fn apu(&self) -> &APU {
+        self.memory.apu()
+    }
+
+    /// For the frontend to set the sample rate and collect samples
+    pub fn apu_mut(&mut self) -> &mut APU {
+        self.memory.apu_mut()
+    }
+
+    /// Plugs a device into a controller port, or unplugs whatever was there
+    pub fn connect(&mut self, port: Port, device: Option<SharedInputDevice>) {
+        self.memory.connect(port, device);
+    }
+
+    pub fn region(&self) -> Region {
+        self.memory.ppu().region()
+    }
+
+    /// Changes the timing of the console, overriding the region the cartridge asked for
+    pub fn set_region(&mut self, region: Region) {
+        self.memory.set_region(region);
+    }
+
+    /// Sets or releases the IRQ line on behalf of `source`.
+    /// IRQ is level triggered, so the CPU will keep being interrupted (while the I flag is clear)
+    /// until every source has released it
+    pub fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        self.memory.set_irq(source, asserted);
+    }
+
+    /// How much of the cartridge's PRG-RAM is kept by a battery, and so should be saved between runs.
+    /// NES 2.0 headers give the size, while iNES headers only say there's a battery, meaning all of it
+    pub fn battery_ram_size(&self) -> usize {
+        self.cartridge.header.prg_nvram_size.min(self.memory.prg_ram().len())
+    }
+
+    /// A copy of the battery backed RAM, which is the end of PRG-RAM if there's volatile RAM too
+    pub fn battery_ram(&self) -> Vec<u8> {
+        let ram = self.memory.prg_ram();
+        ram[ram.len() - self.battery_ram_size()..].to_vec()
+    }
+
+    /// Restores the battery backed RAM, e.g. from a .sav file. `data` has to be exactly the right size,
+    /// since anything else is probably from a different game, or a different dump of this one
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), BatteryError> {
+        let size = self.battery_ram_size();
+        if data.len() != size {
+            return Err(BatteryError::WrongSize { expected: size, found: data.len() });
+        }
+        let offset = self.memory.prg_ram().len() - size;
+        self.memory.load_prg_ram(offset, data);
+        Ok(())
+    }
+
+    /// Saves everything about the running machine, between two instructions, as a save state.
+    /// The cartridge's ROM isn't included, just a fingerprint of it to check the state is loaded into the same game
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.section(b"CART", |state| state.u64(savestate::fingerprint(&self.cartridge.prg_rom_data)));
+        state.section(b"CPU ", |state| {
+            state.u16(self.PC);
+            state.u8(self.registers.A);
+            state.u8(self.registers.X);
+            state.u8(self.registers.Y);
+            state.u8(self.registers.S);
+            state.u8(u8::from(self.registers.P));
+            state.u16(self.address_line);
+            state.bool(self.page_crossed);
+            state.u8(self.opcode);
+            state.u8(self.next_opcode);
+            state.u8(match self.pending_interrupt {
+                None => 0,
+                Some(Interrupt::NMI) => 1,
+                Some(Interrupt::IRQ) => 2,
+            });
+            state.bool(self.jammed);
+        });
+        self.memory.save_state(&mut state);
+        state.finish()
+    }
+
+    /// Restores a state from `save_state`. If the state can't be loaded, the machine carries on as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let state = StateReader::new(data)?;
+        let fingerprint = state.section(b"CART")?.u64()?;
+        if fingerprint != savestate::fingerprint(&self.cartridge.prg_rom_data) {
+            return Err(StateError::WrongCartridge);
+        }
+
+        let backup = self.save_state();
+        if let Err(e) = self.load_sections(&state) {
+            let backup = StateReader::new(&backup).expect("Couldn't read back a state we just saved");
+            self.load_sections(&backup).expect("Couldn't restore a state we just saved");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_sections(&mut self, state: &StateReader) -> Result<(), StateError> {
+        let mut cpu = state.section(b"CPU ")?;
+        self.PC = cpu.u16()?;
+        self.registers.A = cpu.u8()?;
+        self.registers.X = cpu.u8()?;
+        self.registers.Y = cpu.u8()?;
+        self.registers.S = cpu.u8()?;
+        self.registers.P = Flags::from(cpu.u8()?);
+        self.address_line = cpu.u16()?;
+        self.page_crossed = cpu.bool()?;
+        self.opcode = cpu.u8()?;
+        self.next_opcode = cpu.u8()?;
+        self.pending_interrupt = match cpu.u8()? {
+            0 => None,
+            1 => Some(Interrupt::NMI),
+            2 => Some(Interrupt::IRQ),
+            _ => return Err(cpu.invalid()),
+        };
+        self.jammed = cpu.bool()?;
+        self.memory.load_state(state)
+    }
+
     pub fn run(&mut self) -> () {
         self.is_running = true;
         println!("A: {}, S: {}, X: {}, Y: {}, P: {:b}",
@@ -77,22 +361,95 @@ impl CPU {
     }
 
     pub fn step(&mut self) {
-        let pc = self.PC;
+        if self.jammed {
+            // The rest of the system keeps running while the CPU is stuck reading $FFFF
+            self.memory.read(0xffff_u16);
+            return;
+        }
+
+        if let Some(interrupt) = self.pending_interrupt.take() {
+            self.interrupt(interrupt);
+            return;
+        }
+
+        if self.trace_hook.is_some() {
+            self.trace();
+        }
+
         self.opcode = self.next();
         // The 6502 always read two bytes at a time.
         self.next_opcode = self.next();
         let instruction = instructions::decode(self.opcode);
-        //println!("{:x}: {:x} {:x}> {:?}", pc, self.opcode, self.next_opcode, instruction);
+
+        // CLI, SEI and PLP change the I flag after the CPU has already polled for interrupts,
+        // so the poll sees the old value and the change only takes effect after the next instruction
+        let irq_disabled_before = self.registers.P.contains(Flags::I);
+        let delays_irq_poll = matches!(instruction, CLR(_) | SET(_) | PLP);
         self.execute(instruction);
-        /*
-        println!("A: {}, S: {}, X: {}, Y: {}, P: {:b}",
-                self.registers.A,
-                self.registers.S,
-                self.registers.X,
-                self.registers.Y,
-                self.registers.P.bits(),
-            );
-        */
+        let irq_disabled = match delays_irq_poll {
+            true => irq_disabled_before,
+            false => self.registers.P.contains(Flags::I),
+        };
+        self.poll_interrupts(irq_disabled);
+    }
+
+    fn trace(&mut self) {
+        let registers = self.registers();
+        let (bytes, disassembly) = self.disassemble(self.PC);
+        let trace = Trace {
+            pc: self.PC,
+            bytes,
+            disassembly,
+            unofficial: instructions::is_unofficial(self.memory.peek(self.PC)),
+            registers,
+            scanline: self.memory.ppu().scanline(),
+            dot: self.memory.ppu().dot(),
+            cycles: self.memory.cycles(),
+        };
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(&trace);
+        }
+    }
+
+    /// Interrupts are checked for at the end of each instruction.
+    /// NMI takes priority, and can't be masked; IRQ is ignored while the I flag is set
+    fn poll_interrupts(&mut self, irq_disabled: bool) {
+        if self.memory.take_nmi() {
+            self.pending_interrupt = Some(Interrupt::NMI);
+        }
+        else if self.memory.irq_line() && !irq_disabled {
+            self.pending_interrupt = Some(Interrupt::IRQ);
+        }
+    }
+
+    /// Services a hardware interrupt. This behaves like a BRK which has been forced into the
+    /// instruction register, taking 7 cycles, except that PC isn't incremented and B is clear on the stack
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        // The opcode fetch happens as normal, but is thrown away
+        self.memory.read(self.PC);
+        self.memory.read(self.PC);
+        self.enter_interrupt(self.registers.P - Flags::B, interrupt);
+    }
+
+    /// The common tail of BRK, NMI and IRQ: push PC and the given status, then jump through the vector.
+    /// The vector isn't chosen until after the pushes, so an NMI which occurs while a BRK or IRQ is
+    /// pushing hijacks it, and the NMI handler runs instead (with B still showing on the stack for BRK)
+    fn enter_interrupt(&mut self, status: Flags, interrupt: Interrupt) {
+        self.push16(self.PC);
+        self.push(u8::from(status | Flags::U));
+        self.registers.P.insert(Flags::I);
+        let vector = match interrupt {
+            Interrupt::NMI => NMI_VECTOR,
+            Interrupt::IRQ if self.memory.take_nmi() => NMI_VECTOR,
+            Interrupt::IRQ => IRQ_VECTOR,
+        };
+        self.PC = self.read_vector(vector);
+    }
+
+    fn read_vector(&mut self, vector: u16) -> u16 {
+        let low = self.memory.read(vector);
+        let high = self.memory.read(vector + 1);
+        le_address_16(low, high)
     }
 
     #[inline]
@@ -102,26 +459,37 @@ impl CPU {
     /// When we come to timing, I might change how this works (to require 2 byte instructions that don't use
     /// the operand to explicitly throw it away)
     fn prev(&mut self) -> () {
-        self.PC -= 1;
+        self.PC = self.PC.wrapping_sub(1);
     }
 
     #[inline]
     fn next(&mut self) -> u8 {
         let ret = self.memory.read(self.PC);
-        self.PC += 1;
+        self.PC = self.PC.wrapping_add(1);
         ret
     }
 
-    // Handles common timing logic, fetching and storing of results,
-    // without maintaining any extra state
-    fn rmw<F>(&mut self, address_mode: AddressMode, mut action: F)
-        where F: FnMut(&mut CPU, u8) -> u8 
+    /// Read-modify-write instructions read the old value, write it straight back while the ALU works,
+    /// and then write the new value. The dummy write is visible to hardware, and some mappers rely on it.
+    /// Returns the new value
+    fn rmw<F>(&mut self, address_mode: AddressMode, action: F) -> u8
+        where F: FnOnce(&mut CPU, u8) -> u8
     {
-        let input = self.load_input(address_mode);
-        let value = action(self, input);
         match address_mode {
-            Accumulator => self.registers.A = value,
-            _ => self.memory.write(self.address_line, value),
+            Accumulator => {
+                let value = action(self, self.registers.A);
+                self.registers.A = value;
+                self.prev(); // One byte instruction
+                value
+            },
+            _ => {
+                self.calculate_address(address_mode, Access::ReadModifyWrite);
+                let input = self.memory.read(self.address_line);
+                self.memory.write(self.address_line, input);
+                let value = action(self, input);
+                self.memory.write(self.address_line, value);
+                value
+            },
         }
     }
 
@@ -130,12 +498,12 @@ impl CPU {
         match address_mode {
             Immediate => {
                 self.next_opcode
-            },              
+            },
             Accumulator => {
                 self.registers.A
             },
             _=> {
-                self.calculate_address(address_mode);
+                self.calculate_address(address_mode, Access::Read);
                 self.memory.read(self.address_line)
             }
         }
@@ -145,119 +513,81 @@ impl CPU {
     fn execute(&mut self, instruction: Instruction) {
         match instruction {
             ADC(address_mode) => {
-                let carry_in = self.registers.P.contains(Flags::C);
-                let (value, carry, overflow) = alu::adc(self.registers.A, self.load_input(address_mode), carry_in);
-                self.registers.A = value;
-                self.update_flags_ZNCV(value, carry, overflow);
+                let input = self.load_input(address_mode);
+                self.add(input);
             },
-            AND(address_mode) => {               
+            AND(address_mode) => {
                 let value = alu::and(self.registers.A, self.load_input(address_mode));
                 self.registers.A = value;
                 self.update_flags_ZN(value);
             },
             ASL(address_mode) => {
-                let (value, carry) = asl(self.load_input(address_mode));
-                self.update_flags_ZNC(value, carry);
-                match address_mode {
-                    Accumulator => self.registers.A = value,
-                    _ => self.memory.write(self.address_line, value),
-                }
-            },
-            BCC => {
-                if !self.registers.P.contains(Flags::C) {
-                    self.branch();
-                }
-            },
-            BCS => {
-                if self.registers.P.contains(Flags::C) {
-                    self.branch();
-                }
-            }
-            BEQ => {
-                if self.registers.P.contains(Flags::Z) {
-                    self.branch();
-                }
-            },
+                self.rmw(address_mode, |cpu, input| {
+                    let (value, carry) = alu::asl(input);
+                    cpu.update_flags_ZNC(value, carry);
+                    value
+                });
+            },
+            BCC => self.branch(!self.registers.P.contains(Flags::C)),
+            BCS => self.branch(self.registers.P.contains(Flags::C)),
+            BEQ => self.branch(self.registers.P.contains(Flags::Z)),
             BIT(address_mode) => {
                 // Do an and between A and the contents of memory
                 // The idea is that A contains a mask
                 // Then the zero flags tells you if the bit was not set
                 // So zero flag not being set tells an application that the bit _was_ set
-                // We then set the V and C flags depending on the values in bits 6 and 7 of the memory value
-
+                // We then set the N and V flags depending on the values in bits 7 and 6 of the memory value
                 let input = self.load_input(address_mode);
-                let value = alu::and(self.registers.A, self.load_input(address_mode));
-                self.registers.P.remove(Flags::V | Flags::C);
-                let memory_value = Flags::from(input) & (Flags::V | Flags::C);
+                let value = alu::and(self.registers.A, input);
+                self.registers.P.remove(Flags::N | Flags::V);
+                let memory_value = Flags::from(input) & (Flags::N | Flags::V);
                 self.registers.P.insert(memory_value);
-                self.update_flags_ZN(value);
-            },
-            BMI => {
-                if self.registers.P.contains(Flags::N) {
-                    self.branch();
-                }
-            },
-            BNE => {
-                if !self.registers.P.contains(Flags::Z) {
-                    self.branch();
-                }
-            },
-            BPL => {
-                if !self.registers.P.contains(Flags::N) {
-                    self.branch();
-                }
+                self.registers.P.set(Flags::Z, value == 0);
             },
+            BMI => self.branch(self.registers.P.contains(Flags::N)),
+            BNE => self.branch(!self.registers.P.contains(Flags::Z)),
+            BPL => self.branch(!self.registers.P.contains(Flags::N)),
             BRK => {
-                self.push(self.registers.P.into());
-                self.push16(self.PC);
-                let low = self.memory.read(0xfffe_u16);
-                let high = self.memory.read(0xffff_u16);
-                self.PC = ((high as u16) << 8) & (low as u16);
-                self.registers.P.insert(Flags::B);
-            },
-            BVC => {
-                if !self.registers.P.contains(Flags::V) {
-                    self.branch();
-                }
-            },
-            BVS => {
-                if self.registers.P.contains(Flags::V) {
-                    self.branch();
-                }
+                // BRK is really a two byte instruction; the byte after the opcode is skipped over,
+                // so the return address is the opcode's address + 2
+                self.enter_interrupt(self.registers.P | Flags::B, Interrupt::IRQ);
             },
+            BVC => self.branch(!self.registers.P.contains(Flags::V)),
+            BVS => self.branch(self.registers.P.contains(Flags::V)),
             CLR(flag) => {
                 self.registers.P.remove(flag);
                 self.prev(); // One byte instruction
             },
             CMP(address_mode) => {
-                let (value, carry) = alu::cmp(self.registers.A, self.load_input(address_mode));
-                self.update_flags_ZNC(value, carry);
+                let input = self.load_input(address_mode);
+                self.compare(self.registers.A, input);
             },
             CPX(address_mode) => {
-                let (value, carry) = alu::cmp(self.registers.X, self.load_input(address_mode));
-                self.update_flags_ZNC(value, carry);
+                let input = self.load_input(address_mode);
+                self.compare(self.registers.X, input);
             },
             CPY(address_mode) => {
-                let (value, carry) = alu::cmp(self.registers.Y, self.load_input(address_mode));
-                self.update_flags_ZNC(value, carry);
+                let input = self.load_input(address_mode);
+                self.compare(self.registers.Y, input);
             },
             DEC(address_mode) => {
-                let value = alu::dec(self.load_input(address_mode));
-                self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.rmw(address_mode, |cpu, input| {
+                    let value = alu::dec(input);
+                    cpu.update_flags_ZN(value);
+                    value
+                });
             },
             DEX => {
                 let value = alu::dec(self.registers.X);
                 self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.registers.X = value;
+                self.prev(); // One byte instruction
             },
             DEY => {
                 let value = alu::dec(self.registers.Y);
                 self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.registers.Y = value;
+                self.prev(); // One byte instruction
             },
             EOR(address_mode) => {
                 let value = alu::eor(self.registers.A, self.load_input(address_mode));
@@ -265,30 +595,32 @@ impl CPU {
                 self.registers.A = value;
             },
             INC(address_mode) => {
-                let value = alu::inc(self.load_input(address_mode));
-                self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.rmw(address_mode, |cpu, input| {
+                    let value = alu::inc(input);
+                    cpu.update_flags_ZN(value);
+                    value
+                });
             },
             INX => {
                 let value = alu::inc(self.registers.X);
                 self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.registers.X = value;
+                self.prev(); // One byte instruction
             },
             INY => {
                 let value = alu::inc(self.registers.Y);
                 self.update_flags_ZN(value);
-                self.memory.write(self.address_line, value);
-                self.prev();
+                self.registers.Y = value;
+                self.prev(); // One byte instruction
             },
             JMP(address_mode) => self.jmp(address_mode),
             JSR => {
-                // It's important here to read the jump address from JSR first so that the PC is then 
-                // correctly pointing at the return address
-                self.calculate_address(Absolute);
+                // JSR pushes the return address before it has fetched the high byte of the target,
+                // so what goes on the stack is the address of the last byte of the JSR. RTS adds the 1 back on
+                self.memory.read(self.stack_address()); // Internal operation, S is only read
                 self.push16(self.PC);
-                self.PC = self.address_line;
+                let high = self.next();
+                self.PC = le_address_16(self.next_opcode, high);
             },
             LDA(address_mode) => {
                 self.registers.A = self.load(address_mode);
@@ -300,12 +632,11 @@ impl CPU {
                 self.registers.Y = self.load(address_mode);
             },
             LSR(address_mode) => {
-                let (value, carry) = alu::lsr(self.load_input(address_mode));
-                self.update_flags_ZNC(value, carry);
-                match address_mode {
-                    Accumulator => self.registers.A = value,
-                    _ => self.memory.write(self.address_line, value),
-                }
+                self.rmw(address_mode, |cpu, input| {
+                    let (value, carry) = alu::lsr(input);
+                    cpu.update_flags_ZNC(value, carry);
+                    value
+                });
             },
             ORA(address_mode) => {
                 let value = alu::or(self.registers.A, self.load_input(address_mode));
@@ -313,54 +644,58 @@ impl CPU {
                 self.registers.A = value;
             },
             NOP => {
-
+                self.prev(); // One byte instruction
             },
             PHA => {
                 self.push(self.registers.A);
+                self.prev(); // One byte instruction
             },
             PHP => {
-                self.push(u8::from(self.registers.P));
+                self.push(u8::from(self.registers.P | Flags::B | Flags::U));
+                self.prev(); // One byte instruction
             },
             PLA => {
-                self.registers.A = self.pull();
+                self.memory.read(self.stack_address()); // Dummy read while S is incremented
+                let value = self.pull();
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+                self.prev(); // One byte instruction
             },
             PLP => {
-                self.registers.P = Flags::from(self.pull());
+                self.memory.read(self.stack_address()); // Dummy read while S is incremented
+                self.pull_status();
+                self.prev(); // One byte instruction
             },
             ROL(address_mode) => {
-                let carry_in = self.registers.P.contains(Flags::C);
-                let (value, carry) = alu::rol(self.load_input(address_mode), carry_in);
-                self.update_flags_ZNC(value, carry);
-
-                match address_mode {
-                    Accumulator => self.registers.A = value,
-                    _ => self.memory.write(self.address_line, value),
-                }
+                self.rmw(address_mode, |cpu, input| {
+                    let carry_in = cpu.registers.P.contains(Flags::C);
+                    let (value, carry) = alu::rol(input, carry_in);
+                    cpu.update_flags_ZNC(value, carry);
+                    value
+                });
             },
             ROR(address_mode) => {
-                let carry_in = self.registers.P.contains(Flags::C);
-                let (value, carry) = alu::ror(self.load_input(address_mode), carry_in);
-                self.update_flags_ZNC(value, carry);
-
-                match address_mode {
-                    Accumulator => self.registers.A = value,
-                    _ => self.memory.write(self.address_line, value),
-                }
+                self.rmw(address_mode, |cpu, input| {
+                    let carry_in = cpu.registers.P.contains(Flags::C);
+                    let (value, carry) = alu::ror(input, carry_in);
+                    cpu.update_flags_ZNC(value, carry);
+                    value
+                });
             },
             RTI => {
-                self.registers.P = Flags::from(self.pull());
+                self.memory.read(self.stack_address()); // Dummy read while S is incremented
+                self.pull_status();
                 self.PC = self.pull16();
             },
             RTS => {
-                // TODO: Check if I'm doing this right.
-                // In what order does my fetch cycle pull the PC and increment it?
+                self.memory.read(self.stack_address()); // Dummy read while S is incremented
                 self.PC = self.pull16();
+                // JSR pushed the address of its last byte, so step over it
+                self.next();
             },
             SBC(address_mode) => {
-                let carry_in = self.registers.P.contains(Flags::C);
-                let (value, carry, overflow) = alu::sbc(self.registers.A, self.load_input(address_mode), carry_in);
-                self.registers.A = value;
-                self.update_flags_ZNCV(value, carry, overflow);
+                let input = self.load_input(address_mode);
+                self.subtract(input);
             },
             SET(flag) => {
                 self.registers.P.insert(flag);
@@ -377,72 +712,235 @@ impl CPU {
             },
             TAX => {
                 self.registers.X = self.registers.A;
+                self.update_flags_ZN(self.registers.X);
                 self.prev(); // One byte instruction
             },
             TAY => {
                 self.registers.Y = self.registers.A;
+                self.update_flags_ZN(self.registers.Y);
                 self.prev(); // One byte instruction
             },
             TSX => {
                 self.registers.X = self.registers.S;
+                self.update_flags_ZN(self.registers.X);
                 self.prev(); // One byte instruction
             },
             TXA => {
                 self.registers.A = self.registers.X;
+                self.update_flags_ZN(self.registers.A);
                 self.prev(); // One byte instruction
             },
             TXS => {
+                // The only transfer which doesn't affect the flags
                 self.registers.S = self.registers.X;
                 self.prev(); // One byte instruction
             },
             TYA => {
                 self.registers.A = self.registers.Y;
+                self.update_flags_ZN(self.registers.A);
                 self.prev(); // One byte instruction
-            }
+            },
+
+            // Unofficial instructions
+            ALR(address_mode) => {
+                let input = alu::and(self.registers.A, self.load_input(address_mode));
+                let (value, carry) = alu::lsr(input);
+                self.update_flags_ZNC(value, carry);
+                self.registers.A = value;
+            },
+            ANC(address_mode) => {
+                let value = alu::and(self.registers.A, self.load_input(address_mode));
+                self.update_flags_ZNC(value, value & 0x80 == 0x80);
+                self.registers.A = value;
+            },
+            ARR(address_mode) => {
+                // The AND and ROR happen in the same pass through the ALU, which is why
+                // C and V come out of the middle of the result instead of the usual places
+                let carry_in = self.registers.P.contains(Flags::C);
+                let input = alu::and(self.registers.A, self.load_input(address_mode));
+                let (value, _) = alu::ror(input, carry_in);
+                let bit_6 = value & 0x40 == 0x40;
+                let bit_5 = value & 0x20 == 0x20;
+                self.update_flags_ZNCV(value, bit_6, bit_6 ^ bit_5);
+                self.registers.A = value;
+            },
+            AXS(address_mode) => {
+                let input = self.load_input(address_mode);
+                let (value, carry) = alu::cmp(alu::and(self.registers.A, self.registers.X), input);
+                self.update_flags_ZNC(value, carry);
+                self.registers.X = value;
+            },
+            DCP(address_mode) => {
+                let value = self.rmw(address_mode, |_, input| alu::dec(input));
+                self.compare(self.registers.A, value);
+            },
+            IGN(address_mode) => {
+                self.load_input(address_mode);
+            },
+            ISC(address_mode) => {
+                let value = self.rmw(address_mode, |_, input| alu::inc(input));
+                self.subtract(value);
+            },
+            JAM => {
+                // The CPU gets stuck part way through the instruction with the address bus at $FFFF.
+                // Only a reset will get it going again
+                self.jammed = true;
+            },
+            LAS(address_mode) => {
+                let value = alu::and(self.load_input(address_mode), self.registers.S);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+                self.registers.X = value;
+                self.registers.S = value;
+            },
+            LAX(address_mode) => {
+                let value = self.load(address_mode);
+                self.registers.A = value;
+                self.registers.X = value;
+            },
+            LXA(address_mode) => {
+                let input = self.load_input(address_mode);
+                let value = alu::and(alu::or(self.registers.A, UNSTABLE_MAGIC), input);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+                self.registers.X = value;
+            },
+            RLA(address_mode) => {
+                let value = self.rmw(address_mode, |cpu, input| {
+                    let carry_in = cpu.registers.P.contains(Flags::C);
+                    let (value, carry) = alu::rol(input, carry_in);
+                    cpu.registers.P.set(Flags::C, carry);
+                    value
+                });
+                let value = alu::and(self.registers.A, value);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+            },
+            RRA(address_mode) => {
+                let value = self.rmw(address_mode, |cpu, input| {
+                    let carry_in = cpu.registers.P.contains(Flags::C);
+                    let (value, carry) = alu::ror(input, carry_in);
+                    cpu.registers.P.set(Flags::C, carry);
+                    value
+                });
+                self.add(value);
+            },
+            SAX(address_mode) => {
+                self.store(address_mode, alu::and(self.registers.A, self.registers.X));
+            },
+            SHA(address_mode) => {
+                self.store_high_and(address_mode, alu::and(self.registers.A, self.registers.X));
+            },
+            SHX(address_mode) => {
+                self.store_high_and(address_mode, self.registers.X);
+            },
+            SHY(address_mode) => {
+                self.store_high_and(address_mode, self.registers.Y);
+            },
+            SLO(address_mode) => {
+                let value = self.rmw(address_mode, |cpu, input| {
+                    let (value, carry) = alu::asl(input);
+                    cpu.registers.P.set(Flags::C, carry);
+                    value
+                });
+                let value = alu::or(self.registers.A, value);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+            },
+            SRE(address_mode) => {
+                let value = self.rmw(address_mode, |cpu, input| {
+                    let (value, carry) = alu::lsr(input);
+                    cpu.registers.P.set(Flags::C, carry);
+                    value
+                });
+                let value = alu::eor(self.registers.A, value);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+            },
+            TAS(address_mode) => {
+                self.registers.S = alu::and(self.registers.A, self.registers.X);
+                self.store_high_and(address_mode, self.registers.S);
+            },
+            XAA(address_mode) => {
+                let input = self.load_input(address_mode);
+                let value = alu::and(alu::and(alu::or(self.registers.A, UNSTABLE_MAGIC), self.registers.X), input);
+                self.update_flags_ZN(value);
+                self.registers.A = value;
+            },
         }
     }
 
+    fn add(&mut self, input: u8) {
+        let carry_in = self.registers.P.contains(Flags::C);
+        let (value, carry, overflow) = alu::adc(self.registers.A, input, carry_in);
+        self.registers.A = value;
+        self.update_flags_ZNCV(value, carry, overflow);
+    }
+
+    fn subtract(&mut self, input: u8) {
+        let carry_in = self.registers.P.contains(Flags::C);
+        let (value, carry, overflow) = alu::sbc(self.registers.A, input, carry_in);
+        self.registers.A = value;
+        self.update_flags_ZNCV(value, carry, overflow);
+    }
+
+    fn compare(&mut self, register: u8, input: u8) {
+        let (value, carry) = alu::cmp(register, input);
+        self.update_flags_ZNC(value, carry);
+    }
+
     #[inline]
     fn jmp(&mut self, address_mode: AddressMode) {
         match address_mode {
             Absolute | Indirect => {
-                self.calculate_address(address_mode);
+                self.calculate_address(address_mode, Access::Read);
             },
             _ => panic!("Internal processor error: Tried to jump with incorrect AddressMode"),
         }
         self.PC = self.address_line;
     }
 
-    fn branch(&mut self) {
-        self.calculate_address(Relative);
-        self.PC = self.address_line;
+    /// Branches take 2 cycles if not taken, 3 if taken, and 4 if the target is on a different page
+    fn branch(&mut self, condition: bool) {
+        if condition {
+            self.calculate_address(Relative, Access::Read);
+            self.PC = self.address_line;
+        }
+    }
+
+    /// The stack lives in page 1, with S as the low byte of the address
+    #[inline]
+    fn stack_address(&self) -> u16 {
+        0x100 | (self.registers.S as u16)
     }
 
     #[inline]
     fn push(&mut self, value: u8) {
-        self.memory.write(self.registers.S as u16, value);
+        self.memory.write(self.stack_address(), value);
         self.registers.S = alu::dec(self.registers.S);
     }
 
     fn push16(&mut self, value: u16) {
-        self.memory.write(self.registers.S as u16, (value >> 8).try_into().unwrap());
-        self.registers.S = alu::dec(self.registers.S);
-        self.memory.write(self.registers.S as u16, (value & 0xff).try_into().unwrap());
-        self.registers.S = alu::dec(self.registers.S);
+        self.push((value >> 8).try_into().unwrap());
+        self.push((value & 0xff).try_into().unwrap());
     }
 
     #[inline]
     fn pull(&mut self) -> u8 {
         self.registers.S = alu::inc(self.registers.S);
-        self.memory.read(self.registers.S as u16)
+        self.memory.read(self.stack_address())
     }
 
     fn pull16(&mut self) -> u16 {
-        self.registers.S = alu::inc(self.registers.S);
-        let low = self.memory.read(self.registers.S as u16);
-        self.registers.S = alu::inc(self.registers.S);
-        let high = self.memory.read(self.registers.S as u16);
-        ((high as u16) << 8) | (low as u16)
+        let low = self.pull();
+        let high = self.pull();
+        le_address_16(low, high)
+    }
+
+    /// B and the unused bit don't exist in the status register itself, so they are ignored when pulled
+    fn pull_status(&mut self) {
+        let status = Flags::from(self.pull());
+        self.registers.P = (status - Flags::B) | Flags::U;
     }
 
     #[inline]
@@ -451,23 +949,33 @@ impl CPU {
         match address_mode {
             Accumulator => self.registers.A = value,
             Immediate   => panic!("Attempt to store to an immediate operand!"),
-            _ => {        
-                self.calculate_address(address_mode);
+            _ => {
+                self.calculate_address(address_mode, Access::Write);
                 self.memory.write(self.address_line, value)
             },
         }
     }
 
+    /// SHA, SHX, SHY and TAS put the value on the bus at the same time as the high byte of the
+    /// address + 1, so the value which gets written is ANDed with it. When the indexing crosses a page,
+    /// the high byte of the address is then corrupted in the same way
+    fn store_high_and(&mut self, address_mode: AddressMode, value: u8) {
+        self.calculate_address(address_mode, Access::Write);
+        let high = (self.address_line >> 8) as u8;
+        let base_high = match self.page_crossed {
+            true => high.wrapping_sub(1),
+            false => high,
+        };
+        let value = value & base_high.wrapping_add(1);
+        if self.page_crossed {
+            self.address_line = (self.address_line & 0xff) | ((value as u16) << 8);
+        }
+        self.memory.write(self.address_line, value);
+    }
+
     #[inline]
     fn load(&mut self, address_mode: AddressMode) -> u8 {
-        let value = match address_mode {
-            Accumulator => self.registers.A,
-            Immediate   => self.next_opcode,
-            _ => {        
-                self.calculate_address(address_mode);
-                self.memory.read(self.address_line)
-            },
-        };
+        let value = self.load_input(address_mode);
         self.update_flags_ZN(value);
         value
     }
@@ -485,7 +993,7 @@ impl CPU {
         self.update_flags_ZN(value);
         self.registers.P.set(Flags::C, carry);
     }
-    
+
     #[inline]
     #[allow(non_snake_case)]
     fn update_flags_ZN(&mut self, value: u8) {
@@ -493,8 +1001,23 @@ impl CPU {
         self.registers.P.set(Flags::N, value & 0x80 == 0x80);
     }
 
+    /// Indexed addressing adds the index to the low byte of the base address first,
+    /// and reads from the address that gives before the carry has been applied to the high byte.
+    /// Reads only pay for that extra cycle if a page boundary was crossed (the first read was from
+    /// the right place), but writes can't be undone, so they always wait for the fixed address.
+    fn index(&mut self, low: u8, high: u8, index: u8, access: Access) {
+        let (new_low, carry, _) = alu::adc(low, index, false);
+        self.page_crossed = carry;
+        if carry || access != Access::Read {
+            self.memory.read(le_address_16(new_low, high));
+        }
+        let (new_high, _, _) = alu::adc(high, 0, carry);
+        self.address_line = le_address_16(new_low, new_high);
+    }
+
     #[inline]
-    fn calculate_address(&mut self, address_mode: AddressMode) {
+    fn calculate_address(&mut self, address_mode: AddressMode, access: Access) {
+        self.page_crossed = false;
         match address_mode {
             Accumulator => {
                 panic!("Tried to calculate memory address for an accumulator instruction")
@@ -523,14 +1046,16 @@ impl CPU {
                 self.address_line = self.next_opcode.wrapping_add(self.registers.Y) as u16;
             },
             Relative => {
-                let (low, carry, _) = alu::adc(self.next_opcode, (self.PC & 0xff) as u8, false);
-                // Sign extend the 8 bit signed offset
-                let a = match self.next_opcode & 0x80 {
-                    0x80 => 0xff,
-                    _    => 0x00
-                };
-                let (high, _, _) = alu::adc(a, (self.PC >> 8) as u8, carry);
-                self.address_line = le_address_16(low, high);
+                // Only used by taken branches. The opcode after the branch has already been fetched
+                // while the offset was added to PCL, and if that carried there's another read while PCH is fixed
+                self.memory.read(self.PC);
+                let offset = self.next_opcode as i8 as u16;
+                let target = self.PC.wrapping_add(offset);
+                if target & 0xff00 != self.PC & 0xff00 {
+                    self.page_crossed = true;
+                    self.memory.read((self.PC & 0xff00) | (target & 0xff));
+                }
+                self.address_line = target;
             },
             Absolute => {
                 // Operand is a 16-bit instruction
@@ -546,42 +1071,35 @@ impl CPU {
                 // is read from.
                 // If there was a carry when adding X, the processor then applies the carry and reads from the correct
                 // address as calculated by the 16 bit addition of HHLL + XX, where HH and LL are the high and low bytes
-                // of the operand and XX is the contents of the X register. 
+                // of the operand and XX is the contents of the X register.
                 // So when a page boundary is crossed, effectively this takes an extra cycle.
 
                 // Little-endian read
                 let low = self.next_opcode; let high = self.next();
-                let (new_low, carry, _) = alu::adc(low, self.registers.X, false);
-                let first_location = le_address_16(new_low, high);
-                self.memory.read(first_location);
-                let (new_high, _, _) = alu::adc(high, 0, carry);
-                self.address_line = le_address_16(new_low, new_high);
+                self.index(low, high, self.registers.X, access);
             },
             AbsoluteY => {
                 // Exactly like (Absolute,X) addressing, but uses the Y register as the index
                 let low = self.next_opcode; let high = self.next();
-                let (new_low, carry, _) = alu::adc(low, self.registers.Y, false);
-                let first_location = le_address_16(new_low, high);
-                self.memory.read(first_location);
-                let (new_high, _, _) = alu::adc(high, 0, carry);
-                self.address_line = le_address_16(new_low, new_high);
+                self.index(low, high, self.registers.Y, access);
             },
             Indirect => {
                 // The immediate 16-bit operand points to the memory location of the effective address!
+                // The 6502 doesn't carry into the high byte of the pointer, so a pointer at $xxFF
+                // reads its high byte from $xx00 rather than the next page
                 let low = self.next_opcode; let high = self.next();
-                let indirect = le_address_16(low, high);
-                let low = self.memory.read(indirect);
-                let high = self.memory.read(indirect.wrapping_add(1));
+                let low = self.memory.read(le_address_16(low, high));
+                let high = self.memory.read(le_address_16(self.next_opcode.wrapping_add(1), high));
                 self.address_line = le_address_16(low, high);
             },
             XIndirect => {
                 // Take an 8-bit operand and add to the X-register, discarding the carry
                 // This is a pointer to the zero page
-                // It points to the LSB of the effective address, and the next location in the zero page points to 
+                // It points to the LSB of the effective address, and the next location in the zero page points to
                 // the MSB of the effective address
                 let indirect = self.next_opcode;
-                self.memory.read(indirect as u16); //TODO: Really not sure on this. Only one source mentions it.
-                let pointer = indirect.wrapping_add(self.registers.X) as u16;
+                self.memory.read(indirect as u16); // Dummy read while X is added
+                let pointer = indirect.wrapping_add(self.registers.X);
                 let low = self.memory.read(pointer);
                 let high = self.memory.read(pointer.wrapping_add(1));
                 self.address_line = le_address_16(low, high);
@@ -595,13 +1113,10 @@ impl CPU {
                 //
                 // So essentially the effective address is the 16-bit number pointed to in the zero page
                 // added to the Y register.
-                let pointer = self.next_opcode as u16;
+                let pointer = self.next_opcode;
                 let low = self.memory.read(pointer);
                 let high = self.memory.read(pointer.wrapping_add(1));
-                let (new_low, carry, _) = alu::adc(low, self.registers.Y, false);
-                self.memory.read(le_address_16(new_low, high));
-                let (new_high, _, _) = alu::adc(high, 0, carry);
-                self.address_line = le_address_16(new_low, new_high);
+                self.index(low, high, self.registers.Y, access);
             },
         }
     }
@@ -24,21 +24,37 @@ impl RegisterBank {
             X: 0,
             Y: 0,
             S: 0,
-            P: Flags::from(0),
+            P: Flags::I | Flags::U,
         }
     }
 }
 
+/// A copy of the programmer-visible registers, used to inspect or set up the state of the CPU
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// Stack pointer
+    pub s: u8,
+    /// Processor status
+    pub p: u8,
+}
+
 bitflags! {
     /// Represents the bit layout of the Flags register
     pub(crate) struct Flags: u8 {
-        const N = 0b00000001;
-        const V = 0b00000010;
-        const B = 0b00001000;
-        const D = 0b00010000;
-        const I = 0b00100000;
-        const Z = 0b01000000;
-        const C = 0b10000000;
+        const N = 0b10000000;
+        const V = 0b01000000;
+        /// Unused bit. It has no latch in the processor, so always reads back as set
+        const U = 0b00100000;
+        /// Only exists on the stack: set when P is pushed by BRK or PHP, clear when pushed by NMI or IRQ
+        const B = 0b00010000;
+        const D = 0b00001000;
+        const I = 0b00000100;
+        const Z = 0b00000010;
+        const C = 0b00000001;
     }
 }
 
@@ -0,0 +1,108 @@
+use std::fmt;
+
+use super::instructions::{self, AddressMode};
+use super::register::Registers;
+use super::le_address_16;
+
+/// The state of the machine just before an instruction is executed, as handed to a trace hook.
+/// Displaying it gives a line in the format of nestest.log, so a trace can be diffed against
+/// the logs produced by other emulators
+#[derive(Clone, Debug)]
+pub struct Trace {
+    /// Address of the instruction
+    pub pc: u16,
+    /// The opcode followed by any operand bytes
+    pub bytes: Vec<u8>,
+    /// The instruction in assembler syntax, including the memory it will access
+    pub disassembly: String,
+    /// Whether the opcode is one of the undocumented ones
+    pub unofficial: bool,
+    pub registers: Registers,
+    pub scanline: u16,
+    pub dot: u16,
+    /// CPU cycles since power on
+    pub cycles: u64,
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc,
+            bytes.join(" "),
+            if self.unofficial { '*' } else { ' ' },
+            self.disassembly,
+            self.registers.a,
+            self.registers.x,
+            self.registers.y,
+            self.registers.p,
+            self.registers.s,
+            self.scanline,
+            self.dot,
+            self.cycles,
+        )
+    }
+}
+
+/// Disassembles the instruction at `pc`, returning its bytes and the assembler text.
+/// `peek` must read memory without side effects. The addresses and values it shows are the ones the
+/// instruction would use given the current `registers`, as nestest.log does
+pub(crate) fn disassemble<F: Fn(u16) -> u8>(peek: F, pc: u16, registers: &Registers) -> (Vec<u8>, String) {
+    let opcode = peek(pc);
+    let instruction = instructions::decode(opcode);
+    let bytes: Vec<u8> = (0..instruction.length()).map(|i| peek(pc.wrapping_add(i))).collect();
+    let mnemonic = instruction.mnemonic();
+
+    let operand = bytes.get(1).copied().unwrap_or(0);
+    let address = le_address_16(operand, bytes.get(2).copied().unwrap_or(0));
+    let peek16_zero_page = |pointer: u8| le_address_16(peek(pointer as u16), peek(pointer.wrapping_add(1) as u16));
+
+    let text = match instruction.address_mode() {
+        None => mnemonic,
+        Some(AddressMode::Accumulator) => format!("{} A", mnemonic),
+        Some(AddressMode::Immediate) => format!("{} #${:02X}", mnemonic, operand),
+        Some(AddressMode::ZeroPage) => format!("{} ${:02X} = {:02X}", mnemonic, operand, peek(operand as u16)),
+        Some(AddressMode::ZeroPageX) => {
+            let effective = operand.wrapping_add(registers.x);
+            format!("{} ${:02X},X @ {:02X} = {:02X}", mnemonic, operand, effective, peek(effective as u16))
+        },
+        Some(AddressMode::ZeroPageY) => {
+            let effective = operand.wrapping_add(registers.y);
+            format!("{} ${:02X},Y @ {:02X} = {:02X}", mnemonic, operand, effective, peek(effective as u16))
+        },
+        Some(AddressMode::Relative) => {
+            let target = pc.wrapping_add(2).wrapping_add(operand as i8 as u16);
+            format!("{} ${:04X}", mnemonic, target)
+        },
+        // Jumps don't access the memory at their target, so there is no value to show
+        Some(AddressMode::Absolute) if mnemonic == "JMP" || mnemonic == "JSR" => {
+            format!("{} ${:04X}", mnemonic, address)
+        },
+        Some(AddressMode::Absolute) => format!("{} ${:04X} = {:02X}", mnemonic, address, peek(address)),
+        Some(AddressMode::AbsoluteX) => {
+            let effective = address.wrapping_add(registers.x as u16);
+            format!("{} ${:04X},X @ {:04X} = {:02X}", mnemonic, address, effective, peek(effective))
+        },
+        Some(AddressMode::AbsoluteY) => {
+            let effective = address.wrapping_add(registers.y as u16);
+            format!("{} ${:04X},Y @ {:04X} = {:02X}", mnemonic, address, effective, peek(effective))
+        },
+        Some(AddressMode::Indirect) => {
+            // Including the bug where the pointer's high byte doesn't carry
+            let high_address = (address & 0xff00) | (address.wrapping_add(1) & 0xff);
+            let target = le_address_16(peek(address), peek(high_address));
+            format!("{} (${:04X}) = {:04X}", mnemonic, address, target)
+        },
+        Some(AddressMode::XIndirect) => {
+            let pointer = operand.wrapping_add(registers.x);
+            let effective = peek16_zero_page(pointer);
+            format!("{} (${:02X},X) @ {:02X} = {:04X} = {:02X}", mnemonic, operand, pointer, effective, peek(effective))
+        },
+        Some(AddressMode::IndirectY) => {
+            let base = peek16_zero_page(operand);
+            let effective = base.wrapping_add(registers.y as u16);
+            format!("{} (${:02X}),Y = {:04X} @ {:04X} = {:02X}", mnemonic, operand, base, effective, peek(effective))
+        },
+    };
+    (bytes, text)
+}
@@ -0,0 +1,543 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::{Registers, CPU};
+use crate::memory::{WatchHit, WatchKind, Watchpoint};
+
+const JSR: u8 = 0x20;
+
+/// How many instructions the disassembly shows before and after the address it's centred on
+const LISTING_BEFORE: usize = 4;
+const LISTING_AFTER: usize = 6;
+/// Bytes shown by `memory` when no length is given
+const DEFAULT_MEMORY_LENGTH: u16 = 0x40;
+
+const HELP: &str = "\
+step [N]                 Run N instructions, or 1 (s)
+next                     Run one instruction, treating a JSR and its subroutine as one (n)
+continue                 Run until a breakpoint or watchpoint (c)
+finish                   Run until the current subroutine returns
+frame [N]                Run to the end of N frames, or 1
+break ADDR [if COND]     Stop before the instruction at ADDR, if COND holds (b)
+break if COND            Stop before any instruction where COND holds
+watch [rwx] START[-END]  Stop on reads, writes or execution in a range, reads and writes if not given (w)
+delete [N]               Remove breakpoint or watchpoint N, or all of them (d)
+list                     Show the breakpoints and watchpoints (l)
+registers                Show the registers (r)
+set REG VALUE            Change a register, a x y s p pc, or a flag, n v d i z c
+memory ADDR [LEN]        Show LEN bytes from ADDR (m)
+poke ADDR VALUE...       Write bytes from ADDR, as the CPU would
+disassemble [ADDR]       Disassemble around ADDR, or PC (u)
+help                     Show this (h)
+quit                     Stop debugging (q)
+
+Numbers are hex, with or without a $. COND compares registers and flags with == != < <= > >=,
+joined by &&, e.g. a == 0 && x >= 10. An empty line repeats the last step, next, continue, finish or frame
+";
+
+/// The flags in the status register, from bit 7 down, with - for the bit which isn't one
+const FLAG_NAMES: &[u8; 8] = b"nv-bdizc";
+
+/// The value of a register, or of a flag as 0 or 1. None if there's no such register
+fn register(registers: &Registers, name: &str) -> Option<u16> {
+    let value = match name {
+        "a" => registers.a as u16,
+        "x" => registers.x as u16,
+        "y" => registers.y as u16,
+        "s" | "sp" => registers.s as u16,
+        "p" => registers.p as u16,
+        "pc" => registers.pc,
+        _ => return flag_bit(name).map(|bit| (registers.p >> bit) as u16 & 1),
+    };
+    Some(value)
+}
+
+fn is_register(name: &str) -> bool {
+    matches!(name, "a" | "x" | "y" | "s" | "sp" | "p" | "pc") || flag_bit(name).is_some()
+}
+
+fn flag_bit(name: &str) -> Option<usize> {
+    match name.as_bytes() {
+        [letter] if *letter != b'-' && *letter != b'b' => {
+            FLAG_NAMES.iter().position(|flag| flag == letter).map(|i| 7 - i)
+        },
+        _ => None,
+    }
+}
+
+/// Parses a hex number, with or without a $ or 0x in front
+fn number(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a hex number", text))
+}
+
+fn byte(text: &str) -> Result<u8, String> {
+    match number(text)? {
+        value @ 0..=0xff => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", text)),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A condition on the registers, like `a == 0 && x >= 10`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    /// As it was typed, for showing in the list
+    text: String,
+    /// Register or flag name, how it's compared, and what with
+    terms: Vec<(String, Comparison, u16)>,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let terms = text.split("&&").map(|term| {
+            let start = term.find(['=', '!', '<', '>']).ok_or_else(|| format!("No comparison in {}", term.trim()))?;
+            let (name, rest) = term.split_at(start);
+            let (comparison, value) = match rest.get(..2) {
+                Some("==") => (Comparison::Equal, &rest[2..]),
+                Some("!=") => (Comparison::NotEqual, &rest[2..]),
+                Some("<=") => (Comparison::LessOrEqual, &rest[2..]),
+                Some(">=") => (Comparison::GreaterOrEqual, &rest[2..]),
+                _ if rest.starts_with('<') => (Comparison::Less, &rest[1..]),
+                _ if rest.starts_with('>') => (Comparison::Greater, &rest[1..]),
+                _ => return Err(format!("Unknown comparison in {}", term.trim())),
+            };
+            let name = name.trim().to_lowercase();
+            if !is_register(&name) {
+                return Err(format!("Unknown register {}", name));
+            }
+            Ok((name, comparison, number(value.trim())?))
+        }).collect::<Result<_, String>>()?;
+        Ok(Condition {
+            text: text.trim().to_string(),
+            terms,
+        })
+    }
+
+    pub fn holds(&self, registers: &Registers) -> bool {
+        self.terms.iter().all(|(name, comparison, value)| {
+            let register = register(registers, name).expect("Checked when parsed");
+            match comparison {
+                Comparison::Equal => register == *value,
+                Comparison::NotEqual => register != *value,
+                Comparison::Less => register < *value,
+                Comparison::LessOrEqual => register <= *value,
+                Comparison::Greater => register > *value,
+                Comparison::GreaterOrEqual => register >= *value,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+enum Stop {
+    /// At an address, whenever a condition holds, or both
+    Breakpoint {
+        address: Option<u16>,
+        condition: Option<Condition>,
+    },
+    Watchpoint(Watchpoint),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint { address, condition } => {
+                write!(f, "break")?;
+                if let Some(address) = address {
+                    write!(f, " at ${:04X}", address)?;
+                }
+                if let Some(condition) = condition {
+                    write!(f, " if {}", condition)?;
+                }
+                Ok(())
+            },
+            Stop::Watchpoint(watchpoint) => {
+                let kinds: String = [(WatchKind::READ, 'r'), (WatchKind::WRITE, 'w'), (WatchKind::EXECUTE, 'x')].iter()
+                    .filter(|(kind, _)| watchpoint.kind.contains(*kind))
+                    .map(|(_, letter)| letter)
+                    .collect();
+                write!(f, "watch {} ${:04X}", kinds, watchpoint.start)?;
+                if watchpoint.end != watchpoint.start {
+                    write!(f, "-${:04X}", watchpoint.end)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Why running stopped
+enum Stopped {
+    /// The command ran as far as it was meant to
+    Done,
+    Breakpoint(usize),
+    Watchpoint(usize, WatchHit),
+    Jammed,
+}
+
+/// An interactive debugger: breakpoints, watchpoints, stepping, and looking at and changing registers
+/// and memory, driven by typed commands. See HELP for the commands.
+///
+/// Breakpoints and execute watchpoints are checked before each instruction. Read and write watchpoints
+/// are checked on the bus, and stop once the instruction which made the access has finished
+pub struct Debugger {
+    /// Numbered from 1, in the order they were added
+    stops: Vec<(usize, Stop)>,
+    next_id: usize,
+    /// Repeated by an empty line
+    last_command: Option<String>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            stops: Vec::new(),
+            next_id: 1,
+            last_command: None,
+        }
+    }
+
+    /// Reads commands from `input` until it ends or says quit, with a prompt before each one
+    pub fn repl<R: BufRead, W: Write>(&mut self, cpu: &mut CPU, input: R, mut output: W) -> io::Result<()> {
+        write!(output, "{}", location(cpu))?;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(neks) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            if !self.command(cpu, &line, &mut output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Carries out one command, writing what it has to say to `output`. Returns false once told to quit
+    pub fn command<W: Write>(&mut self, cpu: &mut CPU, line: &str, output: &mut W) -> io::Result<bool> {
+        let line = match (line.trim(), &self.last_command) {
+            ("", Some(last)) => last.clone(),
+            (line, _) => line.to_string(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(true),
+        };
+        if matches!(name, "s" | "step" | "n" | "next" | "c" | "continue" | "finish" | "frame") {
+            self.last_command = Some(line.clone());
+        }
+        match name {
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => write!(output, "{}", HELP)?,
+            _ => match self.execute(cpu, name, args) {
+                Ok(text) => write!(output, "{}", text)?,
+                Err(message) => writeln!(output, "{}", message)?,
+            },
+        }
+        Ok(true)
+    }
+
+    fn execute(&mut self, cpu: &mut CPU, name: &str, args: &[&str]) -> Result<String, String> {
+        match name {
+            "s" | "step" => {
+                let count = args.first().map_or(Ok(1), |count| number(count))?;
+                let mut steps = 0;
+                Ok(self.run(cpu, |_| {
+                    steps += 1;
+                    steps >= count
+                }))
+            },
+            "n" | "next" => {
+                let registers = cpu.registers();
+                let (pc, s) = (registers.pc, registers.s);
+                match cpu.peek(pc) {
+                    // Back at the instruction after the JSR, with the stack as it was
+                    JSR => Ok(self.run(cpu, |cpu| {
+                        let registers = cpu.registers();
+                        registers.pc == pc.wrapping_add(3) && registers.s == s
+                    })),
+                    _ => Ok(self.run(cpu, |_| true)),
+                }
+            },
+            "c" | "continue" => Ok(self.run(cpu, |_| false)),
+            // Returning is the only thing which takes the stack pointer above where it is in the subroutine,
+            // short of pulling more than was pushed
+            "finish" => {
+                let s = cpu.registers().s;
+                Ok(self.run(cpu, |cpu| cpu.registers().s > s))
+            },
+            "frame" => {
+                let count = args.first().map_or(Ok(1), |count| number(count))?;
+                let frame = cpu.ppu().frame() + count as u64;
+                Ok(self.run(cpu, |cpu| cpu.ppu().frame() >= frame))
+            },
+            "b" | "break" => {
+                let (address, condition) = match args {
+                    [] => return Err("break needs an address or a condition".to_string()),
+                    ["if", condition @ ..] => (None, Some(Condition::parse(&condition.join(" "))?)),
+                    [address] => (Some(number(address)?), None),
+                    [address, "if", condition @ ..] => {
+                        (Some(number(address)?), Some(Condition::parse(&condition.join(" "))?))
+                    },
+                    _ => return Err("Expected break ADDR, break ADDR if COND or break if COND".to_string()),
+                };
+                Ok(self.add(cpu, Stop::Breakpoint { address, condition }))
+            },
+            "w" | "watch" => {
+                let (kinds, range) = match args {
+                    [range] => ("rw", *range),
+                    [kinds, range] => (*kinds, *range),
+                    _ => return Err("Expected watch [rwx] START[-END]".to_string()),
+                };
+                let kind = kinds.chars().try_fold(WatchKind::empty(), |kind, letter| match letter {
+                    'r' => Ok(kind | WatchKind::READ),
+                    'w' => Ok(kind | WatchKind::WRITE),
+                    'x' => Ok(kind | WatchKind::EXECUTE),
+                    _ => Err(format!("Unknown kind of access {}, expected r, w or x", letter)),
+                })?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    None => (number(range)?, number(range)?),
+                };
+                if end < start {
+                    return Err(format!("{} ends before it starts", range));
+                }
+                Ok(self.add(cpu, Stop::Watchpoint(Watchpoint { start, end, kind })))
+            },
+            "d" | "delete" => {
+                match args.first() {
+                    Some(id) => {
+                        let id = id.parse().map_err(|_| format!("{} isn't a breakpoint number", id))?;
+                        let before = self.stops.len();
+                        self.stops.retain(|(stop_id, _)| *stop_id != id);
+                        if self.stops.len() == before {
+                            return Err(format!("There's no breakpoint or watchpoint {}", id));
+                        }
+                    },
+                    None => self.stops.clear(),
+                }
+                self.update_watchpoints(cpu);
+                Ok(String::new())
+            },
+            "l" | "list" => match self.stops.is_empty() {
+                true => Ok("No breakpoints or watchpoints\n".to_string()),
+                false => Ok(self.stops.iter().map(|(id, stop)| format!("{}: {}\n", id, stop)).collect()),
+            },
+            "r" | "registers" => Ok(registers(cpu)),
+            "set" => {
+                let (name, value) = match args {
+                    [name, value] => (name.to_lowercase(), number(value)?),
+                    _ => return Err("Expected set REG VALUE".to_string()),
+                };
+                let mut registers = cpu.registers();
+                let byte = || match value {
+                    0..=0xff => Ok(value as u8),
+                    _ => Err(format!("{:X} doesn't fit in {}", value, name)),
+                };
+                match name.as_str() {
+                    "a" => registers.a = byte()?,
+                    "x" => registers.x = byte()?,
+                    "y" => registers.y = byte()?,
+                    "s" | "sp" => registers.s = byte()?,
+                    "p" => registers.p = byte()?,
+                    "pc" => registers.pc = value,
+                    _ => match flag_bit(&name) {
+                        Some(bit) if value <= 1 => registers.p = (registers.p & !(1 << bit)) | (value as u8) << bit,
+                        Some(_) => return Err("Flags can only be set to 0 or 1".to_string()),
+                        None => return Err(format!("Unknown register {}", name)),
+                    },
+                }
+                cpu.set_registers(registers);
+                Ok(self::registers(cpu))
+            },
+            "m" | "memory" => {
+                let (address, length) = match args {
+                    [address] => (number(address)?, DEFAULT_MEMORY_LENGTH),
+                    [address, length] => (number(address)?, number(length)?),
+                    _ => return Err("Expected memory ADDR [LEN]".to_string()),
+                };
+                let mut text = String::new();
+                for row in (0..length as u32).step_by(16) {
+                    let start = address.wrapping_add(row as u16);
+                    let bytes: Vec<String> = (0..16.min(length as u32 - row) as u16)
+                        .map(|i| format!("{:02X}", cpu.peek(start.wrapping_add(i))))
+                        .collect();
+                    text += &format!("{:04X}: {}\n", start, bytes.join(" "));
+                }
+                Ok(text)
+            },
+            "poke" => {
+                let (address, values) = match args {
+                    [address, values @ ..] if !values.is_empty() => (number(address)?, values),
+                    _ => return Err("Expected poke ADDR VALUE...".to_string()),
+                };
+                let values = values.iter().map(|value| byte(value)).collect::<Result<Vec<u8>, String>>()?;
+                for (i, value) in values.iter().enumerate() {
+                    cpu.poke(address.wrapping_add(i as u16), *value);
+                }
+                Ok(String::new())
+            },
+            "u" | "disassemble" => {
+                let address = args.first().map_or(Ok(cpu.registers().pc), |address| number(address))?;
+                Ok(disassembly(cpu, address))
+            },
+            _ => Err(format!("Unknown command {}, try help", name)),
+        }
+    }
+
+    fn add(&mut self, cpu: &mut CPU, stop: Stop) -> String {
+        let id = self.next_id;
+        self.next_id += 1;
+        let text = format!("{}: {}\n", id, stop);
+        self.stops.push((id, stop));
+        self.update_watchpoints(cpu);
+        text
+    }
+
+    fn update_watchpoints(&self, cpu: &mut CPU) {
+        let watchpoints: Vec<Watchpoint> = self.stops.iter()
+            .filter_map(|(_, stop)| match stop {
+                Stop::Watchpoint(watchpoint) => Some(*watchpoint),
+                Stop::Breakpoint { .. } => None,
+            })
+            .collect();
+        cpu.set_watchpoints(&watchpoints);
+    }
+
+    /// Runs at least one instruction, until `done` says to stop or something else stops it,
+    /// returning what stopped it and where the CPU is now
+    fn run<F: FnMut(&CPU) -> bool>(&mut self, cpu: &mut CPU, mut done: F) -> String {
+        let stopped = loop {
+            cpu.step();
+            if let Some(hit) = cpu.take_watch_hit() {
+                break Stopped::Watchpoint(self.watchpoint_for(&hit), hit);
+            }
+            if done(cpu) {
+                break Stopped::Done;
+            }
+            if let Some(id) = self.breakpoint_hit(cpu) {
+                break Stopped::Breakpoint(id);
+            }
+            if cpu.is_jammed() {
+                break Stopped::Jammed;
+            }
+        };
+        let reason = match stopped {
+            Stopped::Done => String::new(),
+            Stopped::Breakpoint(id) => format!("Stopped at {}\n", id),
+            Stopped::Watchpoint(id, hit) => {
+                let access = match hit.kind {
+                    WatchKind::WRITE => "Wrote",
+                    _ => "Read",
+                };
+                format!("Stopped at {}: {} ${:02X} at ${:04X}\n", id, access, hit.value, hit.address)
+            },
+            Stopped::Jammed => "The CPU has jammed\n".to_string(),
+        };
+        reason + &location(cpu)
+    }
+
+    /// The first breakpoint or execute watchpoint which applies to the instruction about to run
+    fn breakpoint_hit(&self, cpu: &CPU) -> Option<usize> {
+        let registers = cpu.registers();
+        self.stops.iter().find(|(_, stop)| match stop {
+            Stop::Breakpoint { address, condition } => {
+                address.is_none_or(|address| address == registers.pc)
+                    && condition.as_ref().is_none_or(|condition| condition.holds(&registers))
+            },
+            Stop::Watchpoint(watchpoint) => watchpoint.matches(registers.pc, WatchKind::EXECUTE),
+        }).map(|(id, _)| *id)
+    }
+
+    fn watchpoint_for(&self, hit: &WatchHit) -> usize {
+        self.stops.iter()
+            .find(|(_, stop)| matches!(stop, Stop::Watchpoint(watchpoint) if watchpoint.matches(hit.address, hit.kind)))
+            .map_or(0, |(id, _)| *id)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The next instruction to run
+fn location(cpu: &CPU) -> String {
+    instruction_line(cpu, cpu.registers().pc)
+}
+
+fn instruction_line(cpu: &CPU, address: u16) -> String {
+    let (bytes, text) = cpu.disassemble(address);
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("{:04X}  {:<8}  {}\n", address, bytes.join(" "), text)
+}
+
+fn registers(cpu: &CPU) -> String {
+    let registers = cpu.registers();
+    let flags: String = FLAG_NAMES.iter().enumerate().map(|(i, flag)| {
+        match (*flag, registers.p & (0x80 >> i)) {
+            (b'-', _) => '-',
+            (flag, 0) => flag as char,
+            (flag, _) => flag.to_ascii_uppercase() as char,
+        }
+    }).collect();
+    format!("PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} {}  CYC:{} PPU:{},{} FRAME:{}\n",
+        registers.pc, registers.a, registers.x, registers.y, registers.p, registers.s, flags,
+        cpu.cycles(), cpu.ppu().scanline(), cpu.ppu().dot(), cpu.ppu().frame())
+}
+
+/// A few instructions either side of `address`, with PC marked. There's no telling for certain where the
+/// instructions before it start, so this tries starting further and further back, and takes the furthest
+/// start which runs exactly into `address`
+fn disassembly(cpu: &CPU, address: u16) -> String {
+    let length = |address: u16| cpu.disassemble(address).0.len() as u16;
+    let mut start = address;
+    for back in (1..=LISTING_BEFORE as u16 * 3).rev() {
+        let candidate = address.wrapping_sub(back);
+        let mut starts = Vec::new();
+        let mut offset = 0;
+        while offset < back {
+            starts.push(candidate.wrapping_add(offset));
+            offset += length(candidate.wrapping_add(offset));
+        }
+        if offset == back {
+            start = starts[starts.len().saturating_sub(LISTING_BEFORE)];
+            break;
+        }
+    }
+
+    let pc = cpu.registers().pc;
+    let mut text = String::new();
+    let mut current = start;
+    let mut after = 0;
+    while after <= LISTING_AFTER {
+        if current == address || after > 0 {
+            after += 1;
+        }
+        text += match current == pc {
+            true => "> ",
+            false => "  ",
+        };
+        text += &instruction_line(cpu, current);
+        current = current.wrapping_add(length(current));
+    }
+    text
+}
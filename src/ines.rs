@@ -3,27 +3,147 @@ use std::io::Error;
 use std::fs::File;
 use std::path::Path;
 use std::ops::Index;
+use std::convert::TryInto;
+use std::fmt;
 
 use bitflags::*;
 
-use nom::IResult;
-use nom::error::VerboseError;
-use nom::multi::count;
-use nom::sequence::tuple;
-use nom::number::complete::le_u8;
-use nom::bytes::complete::{tag, take};
+use crate::mapper;
 
-type CartridgeError<'a> = VerboseError<&'a [u8]>;
+const MAGIC: &[u8] = b"NES\x1A";
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+/// NES 2.0's exponent-multiplier form can describe ROMs far bigger than any cartridge, or than fits in memory.
+/// Anything over this is taken to be a broken header
+const MAX_ROM_SIZE: usize = 1 << 30;
 
+/// Why a ROM image couldn't be loaded. Offsets are in bytes from the start of the image
+#[derive(Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The image doesn't start with "NES<EOF>", so probably isn't an iNES image at all
+    BadMagic,
+    /// The image ends part way through the 16 byte header
+    TruncatedHeader { length: usize },
+    /// The header says there is a trainer, but the image ends before the end of it
+    TrainerMissing { offset: usize, length: usize },
+    TruncatedPrg { offset: usize, expected: usize, found: usize },
+    TruncatedChr { offset: usize, expected: usize, found: usize },
+    UnsupportedMapper { mapper: u16, submapper: u8 },
+    /// The header gives an amount of PRG-ROM which the board can't have, such as none at all
+    BadPrgSize { mapper: u16, size: usize },
+    /// There is data after the end of the ROMs the header describes
+    OversizeImage { offset: usize, extra: usize },
+    /// A NES 2.0 ROM size, in the header byte at `offset`, which is too big to be real
+    OversizeRom { offset: usize },
+    /// A NES 2.0 header whose battery bit disagrees with its non-volatile RAM sizes, so there's no telling
+    /// whether the game expects its saves to be kept
+    BatteryMismatch { battery: bool, nvram_size: usize },
+    /// An iNES 1.0 header with something other than zeros in bytes 12-15, usually the name of
+    /// the tool which dumped it (e.g. "DiskDude!"), which means bytes 7-15 can't be trusted
+    HeaderGarbage { offset: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::BadMagic => write!(f, "Not an iNES ROM image: it doesn't start with \"NES\\x1A\""),
+            CartridgeError::TruncatedHeader { length } => {
+                write!(f, "The image is only {} bytes long, which is too short for a {} byte header", length, HEADER_SIZE)
+            },
+            CartridgeError::TrainerMissing { offset, length } => {
+                write!(f, "The header says there is a {} byte trainer at offset {:#x}, but the image ends at {:#x}",
+                    TRAINER_SIZE, offset, length)
+            },
+            CartridgeError::TruncatedPrg { offset, expected, found } => {
+                write!(f, "PRG-ROM at offset {:#x} should be {} bytes long, but the image only has {} bytes left",
+                    offset, expected, found)
+            },
+            CartridgeError::TruncatedChr { offset, expected, found } => {
+                write!(f, "CHR-ROM at offset {:#x} should be {} bytes long, but the image only has {} bytes left",
+                    offset, expected, found)
+            },
+            CartridgeError::UnsupportedMapper { mapper, submapper } => {
+                write!(f, "Mapper {} (submapper {}) isn't supported", mapper, submapper)
+            },
+            CartridgeError::BadPrgSize { mapper, size } => {
+                write!(f, "The header says there are {} bytes of PRG-ROM, which mapper {} can't use", size, mapper)
+            },
+            CartridgeError::OversizeImage { offset, extra } => {
+                write!(f, "There are {} unexpected bytes after the end of the ROM data at offset {:#x}", extra, offset)
+            },
+            CartridgeError::OversizeRom { offset } => {
+                write!(f, "Header byte {} gives a ROM size over {} bytes, which can't be right", offset, MAX_ROM_SIZE)
+            },
+            CartridgeError::BatteryMismatch { battery: true, .. } => {
+                write!(f, "The header says there is a battery, but gives no non-volatile RAM for it to keep")
+            },
+            CartridgeError::BatteryMismatch { battery: false, nvram_size } => {
+                write!(f, "The header gives {} bytes of non-volatile RAM, but says there is no battery", nvram_size)
+            },
+            CartridgeError::HeaderGarbage { offset } => {
+                write!(f, "Header byte {} should be zero; bytes 7-15 look like they were overwritten (e.g. by \"DiskDude!\"), \
+                    so the mapper number can't be trusted. Clean up the header and try again", offset)
+            },
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+/// Which revision of the header format a ROM image uses
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The original iNES format, plus the de facto extensions to bytes 8-10
+    INes,
+    Nes20,
+}
+
+/// The CPU/PPU timing the cartridge was made for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Works on consoles of any region
+    MultiRegion,
+    Dendy,
+}
+
+/// The kind of console the cartridge runs on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleType {
+    /// A regular NES or Famicom
+    Nes,
+    /// A Vs. System arcade board, with the type of its PPU and of the hardware around it
+    VsSystem { ppu: u8, hardware: u8 },
+    Playchoice10,
+    /// One of the NES 2.0 extended console types, e.g. 3 is a VT01 famiclone
+    Extended(u8),
+}
+
+/// The decoded 16 byte header at the start of an iNES or NES 2.0 ROM image.
+/// Sizes are in bytes, whichever format they were encoded in
 #[derive(Debug)]
 pub struct Header {
-    pub prg_rom_size: u8,
-    pub chr_rom_size: u8,
+    pub format: Format,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    /// The mapper number, which identifies the type of cartridge board. iNES only has room for 8 bits of it
+    pub mapper: u16,
+    /// Distinguishes variants of a board which share a mapper number. Always 0 for iNES
+    pub submapper: u8,
     pub flags_6: Flags6,
-    pub flags_7: u8,
-    pub flags_8: u8,
-    pub flags_9: u8,
-    pub flags_10: u8,
+    /// Volatile PRG-RAM
+    pub prg_ram_size: usize,
+    /// Battery backed PRG-RAM, or EEPROM
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    /// Number of miscellaneous ROMs after the CHR-ROM, such as a PlayChoice-10 INST-ROM
+    pub misc_roms: u8,
+    /// The input device the game expects to be plugged in, using the NES 2.0 numbering. 0 means unspecified
+    pub expansion_device: u8,
 }
 
 bitflags! {
@@ -31,40 +151,133 @@ bitflags! {
         const mirroring = 0b00000001;
         const persistent_ram = 0b00000010;
         const trainer_present = 0b00000100;
+        const four_screen = 0b00001000;
+    }
+}
+
+/// NES 2.0 ROM sizes are either a count of units in 12 bits, or when the top nibble is all ones,
+/// an exponent and multiplier packed into the low byte: 2^E * (MM * 2 + 1). None if that's over MAX_ROM_SIZE
+fn nes20_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    match msb {
+        0xf => {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            2_usize.checked_pow(exponent)
+                .and_then(|power| power.checked_mul(multiplier))
+                .filter(|size| *size <= MAX_ROM_SIZE)
+        },
+        _ => Some((((msb as usize) << 8) | lsb as usize) * unit),
+    }
+}
+
+/// NES 2.0 RAM sizes are shift counts, giving 64 << shift bytes. A shift of 0 means there is none
+fn nes20_ram_size(shift: u8) -> usize {
+    match shift {
+        0 => 0,
+        _ => 64 << shift,
     }
 }
 
-fn parse_header(input: &[u8]) -> IResult<&[u8], Header, CartridgeError> {
-    match tuple((
-        tag(b"NES\x1A"),
-        le_u8, le_u8,
-        le_u8, le_u8,
-        le_u8, le_u8,
-        le_u8,
-        take(5usize), // padding bytes, unused in iNES
-    ))(input)
-    {
-        Ok((remaining_input, (
-            _, //TAG
-            prg_size,
-            chr_size,
-            flags_6,
-            flags_7,
-            flags_8,
-            flags_9,
-            flags_10,
-            _,
-        ))) => Ok((remaining_input, Header {
-            prg_rom_size: prg_size,
-            chr_rom_size: chr_size,
-            flags_6: Flags6::from_bits_truncate(flags_6),
-            flags_7: flags_7,
-            flags_8: flags_8,
-            flags_9: flags_9,
-            flags_10: flags_10,
-    })),
-        Err(e) => Err(e),
-    } 
+impl Header {
+    /// Decodes bytes 4-15 of the header, everything after the magic number
+    fn decode(bytes: [u8; 12]) -> Result<Header, CartridgeError> {
+        let [prg_size, chr_size, flags_6, flags_7, flags_8, flags_9,
+            flags_10, flags_11, flags_12, flags_13, flags_14, flags_15] = bytes;
+        let flags = Flags6::from_bits_truncate(flags_6);
+        // The low nibble of the mapper number is in the high nibble of flags 6, and the next nibble in flags 7
+        let mapper = ((flags_7 & 0xf0) | (flags_6 >> 4)) as u16;
+
+        // NES 2.0 is marked by bits 2-3 of flags 7 being 10
+        if flags_7 & 0x0c == 0x08 {
+            let console_type = match flags_7 & 0b11 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem { ppu: flags_13 & 0x0f, hardware: flags_13 >> 4 },
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Extended(flags_13 & 0x0f),
+            };
+            let timing = match flags_12 & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            };
+            let prg_rom_size = nes20_rom_size(prg_size, flags_9 & 0x0f, 0x4000)
+                .ok_or(CartridgeError::OversizeRom { offset: 4 })?;
+            let chr_rom_size = nes20_rom_size(chr_size, flags_9 >> 4, 0x2000)
+                .ok_or(CartridgeError::OversizeRom { offset: 5 })?;
+            // The battery bit covers any non-volatile memory, so battery backed CHR-RAM counts too
+            let battery = flags.contains(Flags6::persistent_ram);
+            let nvram_size = nes20_ram_size(flags_10 >> 4) + nes20_ram_size(flags_11 >> 4);
+            if battery != (nvram_size > 0) {
+                return Err(CartridgeError::BatteryMismatch { battery, nvram_size });
+            }
+            Ok(Header {
+                format: Format::Nes20,
+                prg_rom_size,
+                chr_rom_size,
+                mapper: mapper | (((flags_8 & 0x0f) as u16) << 8),
+                submapper: flags_8 >> 4,
+                flags_6: flags,
+                prg_ram_size: nes20_ram_size(flags_10 & 0x0f),
+                prg_nvram_size: nes20_ram_size(flags_10 >> 4),
+                chr_ram_size: nes20_ram_size(flags_11 & 0x0f),
+                chr_nvram_size: nes20_ram_size(flags_11 >> 4),
+                timing,
+                console_type,
+                misc_roms: flags_14 & 0b11,
+                expansion_device: flags_15 & 0x3f,
+            })
+        }
+        else {
+            let console_type = match flags_7 & 0b11 {
+                1 => ConsoleType::VsSystem { ppu: 0, hardware: 0 },
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Nes,
+            };
+            // iNES only says whether the PRG-RAM is battery backed, and its size in 8KB units with 0 meaning 8KB
+            let prg_ram_size = flags_8.max(1) as usize * 0x2000;
+            let (prg_ram_size, prg_nvram_size) = match flags.contains(Flags6::persistent_ram) {
+                true => (0, prg_ram_size),
+                false => (prg_ram_size, 0),
+            };
+            Ok(Header {
+                format: Format::INes,
+                prg_rom_size: prg_size as usize * 0x4000,
+                chr_rom_size: chr_size as usize * 0x2000,
+                mapper,
+                submapper: 0,
+                flags_6: flags,
+                prg_ram_size,
+                prg_nvram_size,
+                // Boards without CHR-ROM have CHR-RAM instead
+                chr_ram_size: if chr_size == 0 { 0x2000 } else { 0 },
+                chr_nvram_size: 0,
+                timing: match flags_9 & 1 == 1 {
+                    true => Timing::Pal,
+                    false => Timing::Ntsc,
+                },
+                console_type,
+                misc_roms: 0,
+                expansion_device: 0,
+            })
+        }
+    }
+}
+
+fn parse_header(input: &[u8]) -> Result<Header, CartridgeError> {
+    if !input.starts_with(MAGIC) {
+        return Err(CartridgeError::BadMagic);
+    }
+    if input.len() < HEADER_SIZE {
+        return Err(CartridgeError::TruncatedHeader { length: input.len() });
+    }
+    let bytes = &input[MAGIC.len()..HEADER_SIZE];
+    // Some old dumping tools wrote their name over the end of the header. NES 2.0 uses those bytes
+    let nes20 = bytes[3] & 0x0c == 0x08;
+    if let Some(index) = bytes[8..].iter().position(|&b| b != 0).filter(|_| !nes20) {
+        return Err(CartridgeError::HeaderGarbage { offset: MAGIC.len() + 8 + index });
+    }
+    Header::decode(bytes.try_into().unwrap())
 }
 
 pub struct Cartridge {
@@ -72,34 +285,62 @@ pub struct Cartridge {
     pub trainer: [u8; 512],
     pub prg_rom_data: Vec<u8>,
     pub chr_rom_data: Vec<u8>,
+    /// Any ROMs after the CHR-ROM, one after another. Only NES 2.0 headers can say there are some
+    pub misc_rom_data: Vec<u8>,
 }
 
-fn parse_file(input: &[u8]) -> IResult<&[u8], Cartridge, CartridgeError> {
-    let (i, header) = parse_header(input)?;
-    let (i, trainer) = match header.flags_6.contains(Flags6::trainer_present) {
-        true => {
-            let (i, o) = take(512usize)(i)?;
-            (i, Some(o))
-        },
-        false => (i, None),
+fn parse_file(input: &[u8]) -> Result<Cartridge, CartridgeError> {
+    let header = parse_header(input)?;
+    if !mapper::is_supported(&header) {
+        return Err(CartridgeError::UnsupportedMapper { mapper: header.mapper, submapper: header.submapper });
+    }
+    if !mapper::is_valid_prg_size(&header) {
+        return Err(CartridgeError::BadPrgSize { mapper: header.mapper, size: header.prg_rom_size });
+    }
+    let mut offset = HEADER_SIZE;
+
+    let mut trainer = [0; TRAINER_SIZE];
+    if header.flags_6.contains(Flags6::trainer_present) {
+        match input.get(offset..offset + TRAINER_SIZE) {
+            Some(bytes) => trainer.copy_from_slice(bytes),
+            None => return Err(CartridgeError::TrainerMissing { offset, length: input.len() }),
+        }
+        offset += TRAINER_SIZE;
+    }
+
+    let prg_rom_data = match input.get(offset..offset + header.prg_rom_size) {
+        Some(bytes) => bytes.to_vec(),
+        None => return Err(CartridgeError::TruncatedPrg {
+            offset,
+            expected: header.prg_rom_size,
+            found: input.len() - offset,
+        }),
     };
-    let prg_size = header.prg_rom_size as usize * 16384usize; // 16KB chunks
-    let chr_size = header.chr_rom_size as usize * 8192usize; // 8KB chunks
-    let (i, prg_rom_data) = count(le_u8, prg_size)(i)?;
-    let (i, chr_rom_data) = count(le_u8, chr_size)(i)?;
-    let mut file = (i, Cartridge {
-        header: header,
-        trainer: [0; 512],
-        prg_rom_data: prg_rom_data,
-        chr_rom_data: chr_rom_data,
-    });
+    offset += header.prg_rom_size;
 
-    match trainer {
-        Some(bytes) => file.1.trainer.copy_from_slice(bytes),
-        None => (),
+    let chr_rom_data = match input.get(offset..offset + header.chr_rom_size) {
+        Some(bytes) => bytes.to_vec(),
+        None => return Err(CartridgeError::TruncatedChr {
+            offset,
+            expected: header.chr_rom_size,
+            found: input.len() - offset,
+        }),
+    };
+    offset += header.chr_rom_size;
+
+    // The sizes of miscellaneous ROMs aren't in the header, they just take up the rest of the image
+    let misc_rom_data = input[offset..].to_vec();
+    if header.misc_roms == 0 && !misc_rom_data.is_empty() {
+        return Err(CartridgeError::OversizeImage { offset, extra: misc_rom_data.len() });
     }
 
-    Ok(file)
+    Ok(Cartridge {
+        header,
+        trainer,
+        prg_rom_data,
+        chr_rom_data,
+        misc_rom_data,
+    })
 }
 
 /// This is just a data structure which owns the ROM data to be parsed
@@ -109,7 +350,7 @@ pub struct RomFileParser {
 }
 
 impl RomFileParser {
-    /// The only way to create a RomFileParser - to load data from a file somewhere
+    /// Create a RomFileParser by loading data from a file somewhere
     pub fn load<P: AsRef<Path>>(path: P) -> Result<RomFileParser, Error> {
         let mut parser = RomFileParser {
             // ROM files are likely to be at least 32KB,
@@ -124,8 +365,15 @@ impl RomFileParser {
         })
     }
 
-    /// Parses an open file, returning the result
-    pub fn parse(&self) -> IResult<&[u8], Cartridge, CartridgeError> {
+    /// Create a RomFileParser from a ROM image which is already in memory
+    pub fn from_bytes(data: Vec<u8>) -> RomFileParser {
+        RomFileParser {
+            data,
+        }
+    }
+
+    /// Parses the ROM image into a cartridge, which has its own copy of the data
+    pub fn parse(&self) -> Result<Cartridge, CartridgeError> {
         parse_file(&self.data)
     }
 }
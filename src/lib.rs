@@ -1,4 +1,16 @@
 pub mod ines; // ines is the predominant ROM file format for NES, this implements reading the format
 pub mod cpu;  // CPU functionality
 pub mod memory; // Memory access functionality
-pub mod ppu; // The picture processing unit
\ No newline at end of file
+pub mod ppu; // The picture processing unit
+pub mod apu; // The audio processing unit
+pub mod mapper; // The circuitry on cartridge boards
+pub mod region; // NTSC, PAL and Dendy timing
+pub mod palette; // Turning the PPU's colours into RGB
+pub mod controller; // Joypads and the ports they plug into
+pub mod config; // Settings for the frontend, loaded from a TOML file
+pub mod console; // Running a console without a frontend
+pub mod savestate; // Saving and restoring the whole machine
+pub mod battery; // Keeping battery backed RAM in .sav files
+pub mod rewind; // Running the game backwards
+pub mod movie; // Recording and playing back input, in FCEUX's FM2 format
+pub mod debugger; // Stepping through code, with breakpoints and watchpoints
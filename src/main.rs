@@ -1,21 +1,375 @@
 const VERSION: i64 = 1;
+/// How much audio to keep queued, in seconds. Less risks running dry, more adds latency
+const AUDIO_LATENCY: f64 = 0.05;
+/// The furthest the sample rate is bent to keep the audio queue at the right length
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+/// Number of numbered save state slots
+const SAVE_SLOTS: usize = 10;
+/// How often battery backed RAM is written out, if it's changed, so a crash doesn't lose much
+const BATTERY_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
 
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::rc::Rc;
 use std::cell::RefCell;
 use structopt::StructOpt;
 
+use sdl2::audio::AudioSpecDesired;
+use sdl2::controller::{Button, GameController};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{KeyboardState, Scancode};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::video::FullscreenType;
 
-use neks::ines::RomFileParser;
+use neks::apu::DEFAULT_SAMPLE_RATE;
+use neks::battery::{self, SaveFile};
+use neks::config::{Binding, Config, Hotkeys, Pacing, PlayerBindings, Scaling, Video};
+use neks::controller::{Buttons, Joypad, Port};
+use neks::console::{write_png, Console};
+use neks::ines::{Cartridge, RomFileParser};
 use neks::cpu::CPU;
+use neks::debugger::Debugger;
+use neks::movie::{Commands, Movie, Playback, Recording};
+use neks::palette::Palette;
+use neks::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use neks::region::Region;
+use neks::rewind::Rewind;
+
+/// One player's bindings, with the names from the config turned into SDL's keys and buttons
+struct InputMap {
+    keyboard: Vec<(Scancode, Binding)>,
+    controller: Vec<(Button, Binding)>,
+}
+
+impl InputMap {
+    fn new(bindings: &PlayerBindings) -> Result<InputMap, String> {
+        let keyboard = bindings.keyboard.entries().into_iter()
+            .map(|(name, binding)| Ok((scancode(name)?, binding)))
+            .collect::<Result<_, String>>()?;
+        let controller = bindings.controller.entries().into_iter()
+            .map(|(name, binding)| match Button::from_string(name) {
+                Some(button) => Ok((button, binding)),
+                None => Err(format!("Unknown controller button {} in config", name)),
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(InputMap {
+            keyboard,
+            controller,
+        })
+    }
+
+    /// The buttons held on this player's joypad. `turbo` is whether turbo buttons are pressed this frame
+    fn buttons(&self, keyboard: &KeyboardState, controller: Option<&GameController>, turbo: bool) -> Buttons {
+        let held = |binding: &Binding| match *binding {
+            Binding::Held(buttons) => buttons,
+            Binding::Turbo(buttons) if turbo => buttons,
+            Binding::Turbo(_) => Buttons::empty(),
+        };
+        let mut buttons = self.keyboard.iter()
+            .filter(|(scancode, _)| keyboard.is_scancode_pressed(*scancode))
+            .fold(Buttons::empty(), |buttons, (_, binding)| buttons | held(binding));
+        if let Some(controller) = controller {
+            buttons = self.controller.iter()
+                .filter(|(button, _)| controller.button(*button))
+                .fold(buttons, |buttons, (_, binding)| buttons | held(binding));
+        }
+        buttons
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Hotkey {
+    Pause,
+    Reset,
+    SaveState,
+    LoadState,
+    SelectSlot(usize),
+    ExportState,
+    FastForward,
+    Rewind,
+    Screenshot,
+    Fullscreen,
+    Quit,
+}
+
+fn scancode(name: &str) -> Result<Scancode, String> {
+    Scancode::from_name(name).ok_or_else(|| format!("Unknown key {} in config", name))
+}
+
+fn hotkeys(hotkeys: &Hotkeys) -> Result<Vec<(Scancode, Hotkey)>, String> {
+    let mut keys = vec![
+        (scancode(&hotkeys.pause)?, Hotkey::Pause),
+        (scancode(&hotkeys.reset)?, Hotkey::Reset),
+        (scancode(&hotkeys.save_state)?, Hotkey::SaveState),
+        (scancode(&hotkeys.load_state)?, Hotkey::LoadState),
+        (scancode(&hotkeys.export_state)?, Hotkey::ExportState),
+        (scancode(&hotkeys.fast_forward)?, Hotkey::FastForward),
+        (scancode(&hotkeys.rewind)?, Hotkey::Rewind),
+        (scancode(&hotkeys.screenshot)?, Hotkey::Screenshot),
+        (scancode(&hotkeys.fullscreen)?, Hotkey::Fullscreen),
+        (scancode(&hotkeys.quit)?, Hotkey::Quit),
+    ];
+    for (slot, name) in hotkeys.select_slot.iter().enumerate().take(SAVE_SLOTS) {
+        keys.push((scancode(name)?, Hotkey::SelectSlot(slot)));
+    }
+    Ok(keys)
+}
+
+/// Save state slots are kept next to the ROM, as game.ss0 to game.ss9
+fn slot_path(rom: &Path, slot: usize) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+/// Exported states are named after the ROM and the time, so they never overwrite each other
+fn export_path(rom: &Path) -> PathBuf {
+    let stem = rom.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    rom.with_file_name(format!("{}-{}.state", stem, time))
+}
+
+/// Screenshots are kept next to the ROM, numbered from game-1.png on, skipping any which are already there
+fn screenshot_path(rom: &Path) -> PathBuf {
+    let stem = rom.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    (1..)
+        .map(|number| rom.with_file_name(format!("{}-{}.png", stem, number)))
+        .find(|path| !path.exists())
+        .expect("Ran out of screenshot numbers")
+}
+
+fn screenshot(cpu: &CPU, palette: &Palette, path: &Path) -> Result<(), String> {
+    File::create(path)
+        .and_then(|file| write_png(cpu.ppu().framebuffer(), palette, BufWriter::new(file)))
+        .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+}
+
+fn run_frame(cpu: &mut CPU) {
+    let frame = cpu.ppu().frame();
+    while cpu.ppu().frame() == frame {
+        cpu.step();
+    }
+}
+
+/// Errors are only reported, since there's nothing better to do while the game is running
+fn flush_battery(save_file: &mut Option<SaveFile>, cpu: &CPU) {
+    if let Some(save_file) = save_file {
+        if let Err(e) = save_file.flush(cpu) {
+            println!("{} ({})", e, save_file.path().display());
+        }
+    }
+}
+
+fn start_playback(cpu: &mut CPU, path: &Path) -> Result<Playback, String> {
+    let movie = Movie::load(path).map_err(|e| format!("{} ({})", e, path.display()))?;
+    Playback::start(cpu, movie).map_err(|e| format!("{} ({})", e, path.display()))
+}
+
+fn save_state(cpu: &CPU, path: &Path) -> Result<(), String> {
+    fs::write(path, cpu.save_state()).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+}
+
+fn load_state(cpu: &mut CPU, path: &Path) -> Result<(), String> {
+    let state = fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    cpu.load_state(&state).map_err(|e| format!("{} ({})", e, path.display()))
+}
+
+/// The part of the framebuffer which is shown, after cropping the overscan
+fn visible_area(video: &Video) -> Rect {
+    let top = video.overscan_top.min(SCREEN_HEIGHT as u32 / 2);
+    let bottom = video.overscan_bottom.min(SCREEN_HEIGHT as u32 / 2);
+    Rect::new(0, top as i32, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32 - top - bottom)
+}
+
+/// Where the picture goes in a window of the given size
+fn picture_rect(video: &Video, window: (u32, u32)) -> Rect {
+    let visible = visible_area(video);
+    let width = visible.width() as f64 * video.pixel_aspect.ratio();
+    let height = visible.height() as f64;
+    let (window_width, window_height) = (window.0 as f64, window.1 as f64);
+    let fit = (window_width / width).min(window_height / height);
+    let (width, height) = match video.scaling {
+        Scaling::Stretch => (window_width, window_height),
+        Scaling::Fit => (width * fit, height * fit),
+        // Whole multiples of the height, so every line is the same size. With square pixels the
+        // columns are too, but 8:7 pixels can't all be the same width anyway
+        Scaling::Integer => {
+            let scale = fit.floor().max(1.0);
+            (width * scale, height * scale)
+        },
+    };
+    let x = ((window_width - width) / 2.0).round() as i32;
+    let y = ((window_height - height) / 2.0).round() as i32;
+    Rect::new(x, y, width.round() as u32, height.round() as u32)
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neks", about = "NES emulator")]
 struct CommandLineOptions {
     #[structopt(parse(from_os_str))]
     input: PathBuf,
+
+    /// Run as an NTSC, PAL or Dendy console, whatever the ROM says
+    #[structopt(long)]
+    region: Option<Region>,
+
+    /// Config file to use. It's created with the default settings if it doesn't exist
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// A .pal file to use instead of the built in palette
+    #[structopt(long, parse(from_os_str))]
+    palette: Option<PathBuf>,
+
+    /// Start from a save state, e.g. one exported while playing
+    #[structopt(long, parse(from_os_str))]
+    state: Option<PathBuf>,
+
+    /// Record the input to an FM2 movie, from power on, or from --state if it's given. It's written on exit
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["play", "headless"])]
+    record: Option<PathBuf>,
+
+    /// Play back an FM2 movie, then carry on with the player's input. Headless, the movie is run to the end
+    /// instead of for --frames, and a desync is an error
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["state", "until-jam", "until-pc", "until-memory"])]
+    play: Option<PathBuf>,
+
+    /// Step through the game in a debugger on the command line, without a window or sound
+    #[structopt(long, conflicts_with_all = &["headless", "record", "play"])]
+    debug: bool,
+
+    /// Run without a window, sound or input, then exit. The config file isn't used
+    #[structopt(long)]
+    headless: bool,
+
+    /// Headless: how many frames to run, or the most to wait for an --until condition
+    #[structopt(long, default_value = "600")]
+    frames: u64,
+
+    /// Headless: stop once the CPU executes a JAM instruction
+    #[structopt(long)]
+    until_jam: bool,
+
+    /// Headless: stop once the CPU is about to execute the instruction at this address, in hex
+    #[structopt(long, parse(try_from_str = parse_hex_u16))]
+    until_pc: Option<u16>,
+
+    /// Headless: stop once memory holds a value, given as ADDRESS=VALUE in hex, e.g. 6000=00
+    #[structopt(long, parse(try_from_str = parse_memory_condition))]
+    until_memory: Option<(u16, u8)>,
+
+    /// Headless: write the last frame as a PNG
+    #[structopt(long, parse(from_os_str))]
+    png: Option<PathBuf>,
+
+    /// Headless: write everything the APU played as a WAV
+    #[structopt(long, parse(from_os_str))]
+    wav: Option<PathBuf>,
+
+    /// Headless: write the CPU's registers and RAM as text
+    #[structopt(long, parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+}
+
+fn parse_hex_u16(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("{} isn't a hex address: {}", s, e))
+}
+
+fn parse_memory_condition(s: &str) -> Result<(u16, u8), String> {
+    let mut parts = s.splitn(2, '=');
+    let address = parse_hex_u16(parts.next().unwrap_or(""))?;
+    let value = parts.next().ok_or_else(|| format!("Expected ADDRESS=VALUE, not {}", s))?;
+    let value = u8::from_str_radix(value.trim_start_matches('$'), 16)
+        .map_err(|e| format!("{} isn't a hex byte: {}", value, e))?;
+    Ok((address, value))
+}
+
+/// Runs the ROM with no SDL at all, for CI. Fails if an --until condition was given but never met, or a movie
+/// desynced, after writing out whatever was asked for, which is often the most useful thing to look at then
+fn run_headless(opt: &CommandLineOptions, cartridge: Cartridge) -> Result<(), String> {
+    let palette = match &opt.palette {
+        Some(path) => Palette::load(path).map_err(|e| format!("{} ({})", e, path.display()))?,
+        None => Palette::default(),
+    };
+    let mut console = Console::new(cartridge);
+    if let Some(region) = opt.region {
+        console.cpu_mut().set_region(region);
+    }
+    if let Some(path) = &opt.state {
+        load_state(console.cpu_mut(), path)?;
+    }
+    let mut playback = match &opt.play {
+        Some(path) => Some(start_playback(console.cpu_mut(), path)?),
+        None => None,
+    };
+    console.set_record_audio(opt.wav.is_some());
+
+    let mut desync = None;
+    let has_condition = opt.until_jam || opt.until_pc.is_some() || opt.until_memory.is_some();
+    let met = match (&mut playback, has_condition) {
+        (Some(playback), _) => {
+            let joypads = [Joypad::shared(), Joypad::shared()];
+            console.cpu_mut().connect(Port::One, Some(joypads[0].clone()));
+            console.cpu_mut().connect(Port::Two, Some(joypads[1].clone()));
+            while let Some(frame) = playback.begin_frame(console.cpu_mut()) {
+                for (joypad, buttons) in joypads.iter().zip(frame.buttons.iter()) {
+                    joypad.borrow_mut().force_buttons(*buttons);
+                }
+                console.run_frame();
+                if let Err(e) = playback.end_frame(console.cpu()) {
+                    desync.get_or_insert(e);
+                }
+            }
+            true
+        },
+        (None, true) => console.run_until(opt.frames, |cpu| {
+            (opt.until_jam && cpu.is_jammed())
+                || opt.until_pc.is_some_and(|pc| cpu.registers().pc == pc)
+                || opt.until_memory.is_some_and(|(address, value)| cpu.peek(address) == value)
+        }),
+        (None, false) => {
+            console.run_frames(opt.frames);
+            true
+        },
+    };
+    println!("Stopped after {} frames, {} cycles", console.frame(), console.cpu().cycles());
+
+    let create = |path: &PathBuf| {
+        File::create(path).map(BufWriter::new).map_err(|e| format!("Couldn't create {}: {}", path.display(), e))
+    };
+    if let Some(path) = &opt.png {
+        console.write_png(&palette, create(path)?).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+    if let Some(path) = &opt.wav {
+        console.write_wav(create(path)?).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+    if let Some(path) = &opt.snapshot {
+        console.write_snapshot(create(path)?).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+
+    if let Some(desync) = desync {
+        return Err(desync.to_string());
+    }
+    match met {
+        true => Ok(()),
+        false => Err(format!("Condition wasn't met within {} frames", opt.frames)),
+    }
+}
+
+/// Runs the debugger on stdin and stdout, starting just after power on, or from --state
+fn run_debugger(opt: &CommandLineOptions, cartridge: Cartridge) -> Result<(), String> {
+    let mut cpu = CPU::init(cartridge);
+    if let Some(region) = opt.region {
+        cpu.set_region(region);
+    }
+    if let Some(path) = &opt.state {
+        load_state(&mut cpu, path)?;
+    }
+    println!("Type help for the commands");
+    Debugger::new().repl(&mut cpu, io::stdin().lock(), io::stdout()).map_err(|e| e.to_string())
 }
 
 fn main() -> Result<(), String> {
@@ -24,43 +378,321 @@ fn main() -> Result<(), String> {
     let opt = CommandLineOptions::from_args();
     println!("Found file: {:?}", opt.input);
 
-    let cartridge = RomFileParser::load(opt.input)
-                    .unwrap()
+    let cartridge = RomFileParser::load(&opt.input)
+                    .map_err(|e| format!("Couldn't read {}: {}", opt.input.display(), e))?
                     .parse()
-                    .map(|(_remaining, cartridge)| cartridge)
-                    .unwrap();
+                    .map_err(|e| format!("Couldn't load {}: {}", opt.input.display(), e))?;
+    if opt.headless {
+        return run_headless(&opt, cartridge);
+    }
+    if opt.debug {
+        return run_debugger(&opt, cartridge);
+    }
+
+    let config_path = opt.config.unwrap_or_else(Config::default_path);
+    let config = Config::load_or_create(&config_path)
+        .map_err(|e| format!("{} ({})", e, config_path.display()))?;
+    let input_maps = [InputMap::new(&config.player_1)?, InputMap::new(&config.player_2)?];
+    let hotkeys = hotkeys(&config.hotkeys)?;
+    let fast_forward_key = scancode(&config.hotkeys.fast_forward)?;
+    let rewind_key = scancode(&config.hotkeys.rewind)?;
+    let palette = match opt.palette.as_ref().or(config.video.palette.as_ref()) {
+        Some(path) => Palette::load(path).map_err(|e| format!("{} ({})", e, path.display()))?,
+        None => Palette::default(),
+    };
 
     let mut cpu = CPU::init(cartridge);
+    if let Some(region) = opt.region {
+        cpu.set_region(region);
+    }
+    // Movies have to start from the same cartridge wherever they're played, so the game's own save is left out
+    let movie_active = opt.record.is_some() || opt.play.is_some();
+    let sav_path = battery::sav_path(&opt.input);
+    let mut save_file = match movie_active {
+        true => {
+            println!("Battery backed RAM isn't loaded or saved while recording or playing a movie");
+            None
+        },
+        false => SaveFile::open(&mut cpu, &sav_path).map_err(|e| format!("{} ({})", e, sav_path.display()))?,
+    };
+    if let Some(path) = &opt.state {
+        load_state(&mut cpu, path)?;
+    }
+    let rom_name = opt.input.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let mut recording = match (&opt.record, &opt.state) {
+        (Some(_), Some(_)) => Some(Recording::from_state(&cpu, &rom_name)),
+        (Some(_), None) => Some(Recording::from_power_on(&mut cpu, &rom_name)),
+        (None, _) => None,
+    };
+    let mut playback = match &opt.play {
+        Some(path) => Some(start_playback(&mut cpu, path)?),
+        None => None,
+    };
+    let mut desynced = false;
+    let mut last_flush = Instant::now();
+    println!("Region: {}", cpu.region());
+    let frame_duration = Duration::from_secs_f64(1.0 / cpu.region().frame_rate());
+    let mut next_frame = Instant::now() + frame_duration;
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
+    let audio_subsystem = sdl_context.audio()?;
+    let controller_subsystem = sdl_context.game_controller()?;
+
+    // The first game controller is player 1, and the second is player 2
+    let joypads = [Joypad::shared(), Joypad::shared()];
+    cpu.connect(Port::One, Some(joypads[0].clone()));
+    cpu.connect(Port::Two, Some(joypads[1].clone()));
+    let mut controllers: Vec<GameController> = Vec::new();
 
-    let window = video_subsystem.window("Emulator", 800, 600)
+    let desired_spec = AudioSpecDesired {
+        freq: Some(DEFAULT_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: Some(512),
+    };
+    let audio_queue = audio_subsystem.open_queue::<f32, _>(None, &desired_spec)?;
+    let sample_rate = audio_queue.spec().freq as u32;
+    cpu.apu_mut().set_sample_rate(sample_rate);
+    let target_queued = sample_rate as f64 * AUDIO_LATENCY;
+    let mut samples = Vec::new();
+    audio_queue.resume();
+
+    let visible = visible_area(&config.video);
+    let scale = config.video.scale.max(1) as f64;
+    let window_width = visible.width() as f64 * config.video.pixel_aspect.ratio() * scale;
+    let window_height = visible.height() as f64 * scale;
+    let mut window = video_subsystem.window("Neks", window_width.round() as u32, window_height.round() as u32)
         .position_centered()
-        .opengl()
+        .resizable()
         .build()
         .map_err(|e| e.to_string())?;
+    if config.video.fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop)?;
+    }
 
-    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let mut canvas = match config.video.pacing {
+        Pacing::Vsync => window.into_canvas().present_vsync(),
+        Pacing::Audio | Pacing::Timer => window.into_canvas(),
+    }.build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGBA32, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .map_err(|e| e.to_string())?;
+    let mut rgba = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
     let mut event_pump = sdl_context.event_pump()?;
+    let mut paused = false;
+    let mut slot = 0;
+    let mut rewind = match config.rewind.enabled {
+        true => {
+            let frames = config.rewind.seconds * cpu.region().frame_rate();
+            Some(Rewind::new(config.rewind.interval, frames as usize, config.rewind.memory << 20))
+        },
+        false => None,
+    };
+    // The history has no sound, so silence is played while rewinding, for as long as a frame lasts
+    let silence = vec![0.0; (sample_rate as f64 / cpu.region().frame_rate()) as usize];
 
     'running: loop {
+        if last_flush.elapsed() >= BATTERY_FLUSH_INTERVAL {
+            flush_battery(&mut save_file, &cpu);
+            last_flush = Instant::now();
+        }
 
         for event in event_pump.poll_iter() {
             match event {
-                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), ..} => {
-                    break 'running
+                Event::Quit {..} => break 'running,
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                    let hotkey = hotkeys.iter().find(|(key, _)| *key == scancode).map(|(_, hotkey)| *hotkey);
+                    match hotkey {
+                        Some(Hotkey::Quit) => break 'running,
+                        Some(Hotkey::Pause) => paused = !paused,
+                        Some(Hotkey::Reset) => match (&mut recording, &playback) {
+                            (_, Some(_)) => println!("Can't reset while playing a movie"),
+                            // At the start of the next frame, as part of the movie
+                            (Some(recording), None) => recording.reset(false),
+                            (None, None) => {
+                                cpu.reset();
+                                rewind.iter_mut().for_each(Rewind::mark_discontinuity);
+                            },
+                        },
+                        Some(Hotkey::SaveState) => match save_state(&cpu, &slot_path(&opt.input, slot)) {
+                            Ok(()) => println!("Saved state {}", slot),
+                            Err(e) => println!("{}", e),
+                        },
+                        Some(Hotkey::LoadState) if recording.is_some() || playback.is_some() => {
+                            println!("Can't load a state while recording or playing a movie");
+                        },
+                        Some(Hotkey::LoadState) => match load_state(&mut cpu, &slot_path(&opt.input, slot)) {
+                            Ok(()) => {
+                                println!("Loaded state {}", slot);
+                                rewind.iter_mut().for_each(Rewind::mark_discontinuity);
+                            },
+                            Err(e) => println!("{}", e),
+                        },
+                        Some(Hotkey::SelectSlot(selected)) => {
+                            slot = selected;
+                            println!("Save state slot {}", slot);
+                        },
+                        Some(Hotkey::ExportState) => {
+                            let path = export_path(&opt.input);
+                            match save_state(&cpu, &path) {
+                                Ok(()) => println!("Exported state to {}", path.display()),
+                                Err(e) => println!("{}", e),
+                            }
+                        },
+                        Some(Hotkey::Screenshot) => {
+                            let path = screenshot_path(&opt.input);
+                            match screenshot(&cpu, &palette, &path) {
+                                Ok(()) => println!("Saved screenshot to {}", path.display()),
+                                Err(e) => println!("{}", e),
+                            }
+                        },
+                        Some(Hotkey::Fullscreen) => {
+                            let window = canvas.window_mut();
+                            let fullscreen = match window.fullscreen_state() {
+                                FullscreenType::Off => FullscreenType::Desktop,
+                                _ => FullscreenType::Off,
+                            };
+                            window.set_fullscreen(fullscreen)?;
+                        },
+                        // Held rather than pressed, so checked every frame
+                        Some(Hotkey::FastForward) | Some(Hotkey::Rewind) | None => (),
+                    }
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Ok(controller) = controller_subsystem.open(which) {
+                        println!("Connected {}", controller.name());
+                        controllers.push(controller);
+                    }
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.retain(|controller| controller.instance_id() != which);
                 },
                 _ => {}
             }
         }
 
-        cpu.step();
-        // Want to render here
+        let keyboard = event_pump.keyboard_state();
+        let fast_forward = keyboard.is_scancode_pressed(fast_forward_key);
+        let rewinding = keyboard.is_scancode_pressed(rewind_key);
+        if paused {
+            thread::sleep(frame_duration);
+            next_frame = Instant::now() + frame_duration;
+            continue;
+        }
+
+        if let Some(finished) = playback.as_ref().filter(|playback| playback.is_finished()) {
+            println!("Movie finished after {} frames", finished.frame());
+            playback = None;
+        }
+
+        let frame = cpu.ppu().frame();
+        samples.clear();
+        match &mut rewind {
+            Some(rewind) if rewinding => {
+                let stepped_back = rewind.step_back(&mut cpu, |cpu, input| {
+                    for (joypad, buttons) in joypads.iter().zip(input.iter()) {
+                        joypad.borrow_mut().force_buttons(*buttons);
+                    }
+                    run_frame(cpu);
+                });
+                if stepped_back {
+                    recording.iter_mut().for_each(Recording::step_back);
+                    playback.iter_mut().for_each(Playback::step_back);
+                }
+                samples.extend(&silence);
+            },
+            _ => {
+                let mut commands = Commands::empty();
+                match playback.as_mut().and_then(|playback| playback.begin_frame(&mut cpu)) {
+                    Some(movie_frame) => {
+                        commands = movie_frame.commands;
+                        for (joypad, buttons) in joypads.iter().zip(movie_frame.buttons.iter()) {
+                            joypad.borrow_mut().force_buttons(*buttons);
+                        }
+                    },
+                    None => {
+                        let turbo = config.turbo.pressed(frame, cpu.region().frame_rate());
+                        for (player, (joypad, input_map)) in joypads.iter().zip(&input_maps).enumerate() {
+                            let buttons = input_map.buttons(&keyboard, controllers.get(player), turbo);
+                            joypad.borrow_mut().set_buttons(buttons);
+                        }
+                    },
+                }
+                let buttons = [joypads[0].borrow().buttons(), joypads[1].borrow().buttons()];
+                if let Some(recording) = &mut recording {
+                    commands = recording.begin_frame(&mut cpu, buttons);
+                }
+                if let Some(rewind) = &mut rewind {
+                    // Replaying the buttons wouldn't redo a reset
+                    if !commands.is_empty() {
+                        rewind.mark_discontinuity();
+                    }
+                    rewind.record(&cpu, buttons);
+                }
+
+                run_frame(&mut cpu);
+                recording.iter_mut().for_each(|recording| recording.end_frame(&cpu));
+                if let Some(Err(desync)) = playback.as_ref().map(|playback| playback.end_frame(&cpu)) {
+                    // Every frame after the first desync is likely to be off too
+                    if !desynced {
+                        println!("{}", desync);
+                        desynced = true;
+                    }
+                }
+                cpu.apu_mut().read_samples(&mut samples);
+            },
+        }
+        // Fast forwarding would fill the queue far quicker than it plays, so the sound is dropped,
+        // and only every fourth frame is drawn in case presenting waits for vsync
+        if fast_forward {
+            next_frame = Instant::now();
+            if frame & 3 != 0 {
+                continue;
+            }
+        }
+        else {
+            audio_queue.queue(&samples);
+        }
 
+        palette.convert(cpu.ppu().framebuffer(), &mut rgba);
+        texture.update(None, &rgba, SCREEN_WIDTH * 4).map_err(|e| e.to_string())?;
         canvas.clear();
+        let destination = picture_rect(&config.video, canvas.output_size()?);
+        canvas.copy(&texture, visible, destination)?;
         canvas.present();
+
+        if fast_forward {
+            continue;
+        }
+        let queued = || audio_queue.size() as f64 / std::mem::size_of::<f32>() as f64;
+        match config.video.pacing {
+            // The sound card plays at exactly the sample rate, so waiting for it keeps time
+            Pacing::Audio => {
+                while queued() > target_queued {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            },
+            Pacing::Vsync | Pacing::Timer => {
+                // Dynamic rate control: make a few more samples when the queue is running low, and a few less
+                // when it's filling up, so the emulator's clock and the sound card's never drift apart
+                let error = ((target_queued - queued()) / target_queued).clamp(-1.0, 1.0);
+                cpu.apu_mut().set_rate_adjustment(1.0 + error * MAX_RATE_ADJUSTMENT);
+            },
+        }
+        if config.video.pacing == Pacing::Timer {
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            }
+            next_frame += frame_duration;
+        }
     }
 
+    flush_battery(&mut save_file, &cpu);
+    if let (Some(recording), Some(path)) = (recording, &opt.record) {
+        recording.movie().save(path).map_err(|e| format!("{} ({})", e, path.display()))?;
+        println!("Saved {} frames of movie to {}", recording.movie().frames.len(), path.display());
+    }
     Ok(())
 }
\ No newline at end of file
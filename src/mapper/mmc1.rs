@@ -0,0 +1,223 @@
+use crate::ines::Cartridge;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use super::{chr_memory, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
+/// PRG-ROM bigger than this is split into two halves, with CHR bank bit 4 choosing between them (SUROM, SXROM)
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+/// Mapper 1, the Nintendo MMC1 (SxROM boards).
+///
+/// The registers are written one bit at a time through a serial port at $8000-$FFFF: the CPU writes
+/// bit 0 of five consecutive writes, and the address of the fifth write decides which register is loaded.
+/// Writing any value with bit 7 set resets the port instead.
+///
+/// The variants with a lot of PRG-ROM or PRG-RAM have no use for the high bits of the CHR bank registers,
+/// since they use 8KB of CHR-RAM, so they borrow them for extra address lines:
+/// SUROM and SXROM use bit 4 to pick which 256KB half of PRG-ROM is visible, SOROM uses bit 3 to pick an 8KB bank
+/// of its 16KB of PRG-RAM, and SXROM uses bits 2 and 3 for its 32KB, with bit 2 as the low bit of the bank.
+pub(crate) struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    /// Bits written so far through the serial port, shifted in from the top
+    shift: u8,
+    /// Number of bits in `shift`
+    shift_count: u8,
+    /// Counts up every CPU cycle since the last write, so that writes on consecutive cycles can be ignored
+    cycles_since_write: u8,
+
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn init(cartridge: &Cartridge, prg_ram_size: usize) -> Self {
+        let (chr, chr_is_ram) = chr_memory(cartridge);
+        Self {
+            prg_rom: cartridge.prg_rom_data.clone(),
+            prg_ram: vec![0; prg_ram_size],
+            chr,
+            chr_is_ram,
+
+            shift: 0,
+            shift_count: 0,
+            cycles_since_write: 0xff,
+
+            // The last PRG bank is fixed at $C000 on power up, so that the reset vector can be found
+            control: 0x0c,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        // Active low
+        !self.prg_ram.is_empty() && self.prg_bank & 0x10 == 0
+    }
+
+    fn prg_ram_address(&self, address: u16) -> usize {
+        // On SXROM, CHR bank bit 2 is PRG-RAM A13 and bit 3 is A14, while SOROM only has bit 3, as A13.
+        // With CHR-ROM these are CHR address lines instead. Only CHR bank 0 is used here; in 4KB CHR mode,
+        // hardware uses whichever register is driving CHR at the time, but no known game relies on that
+        let bank = match (self.chr_is_ram, self.prg_ram.len()) {
+            (true, size) if size > 2 * PRG_RAM_BANK_SIZE => (self.chr_bank_0 as usize >> 2) & 3,
+            (true, size) if size > PRG_RAM_BANK_SIZE => (self.chr_bank_0 as usize >> 3) & 1,
+            _ => 0,
+        };
+        (bank * PRG_RAM_BANK_SIZE + (address as usize & 0x1fff)) % self.prg_ram.len()
+    }
+
+    fn prg_rom_address(&self, address: u16) -> usize {
+        let bank = (self.prg_bank & 0x0f) as usize;
+        // Within the 256KB half, so that ROMs which aren't a power of two in size still get their real last bank
+        let last_bank = self.prg_rom.len().min(PRG_OUTER_BANK_SIZE) / PRG_BANK_SIZE - 1;
+        let upper = address >= 0xc000;
+        let bank = match (self.control >> 2) & 0b11 {
+            // 32KB mode, ignoring the low bit of the bank number
+            0 | 1 => (bank & !1) | upper as usize,
+            // First bank fixed at $8000, switch $C000
+            2 => if upper { bank } else { 0 },
+            // Last bank fixed at $C000, switch $8000
+            _ => if upper { last_bank } else { bank },
+        };
+        let outer = match self.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            true => ((self.chr_bank_0 as usize >> 4) & 1) * PRG_OUTER_BANK_SIZE,
+            false => 0,
+        };
+        (outer + bank * PRG_BANK_SIZE + (address as usize & 0x3fff)) % self.prg_rom.len()
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let upper = address >= 0x1000;
+        let bank = match self.control & 0x10 {
+            // A single 8KB bank, ignoring the low bit of the bank number
+            0 => (self.chr_bank_0 as usize & !1) | upper as usize,
+            // Two separate 4KB banks
+            _ => if upper { self.chr_bank_1 as usize } else { self.chr_bank_0 as usize },
+        };
+        (bank * CHR_BANK_SIZE + (address as usize & 0x0fff)) % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled() => Some(self.prg_ram[self.prg_ram_address(address)]),
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_rom_address(address)]),
+            _ => None, // Nothing is connected here
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                let address = self.prg_ram_address(address);
+                self.prg_ram[address] = value;
+            },
+            0x8000..=0xffff => {
+                // Read-modify-write instructions write twice on consecutive cycles, and the MMC1 only sees the
+                // first. Games use this to reset the port with e.g. INC on a ROM byte of $FF
+                let consecutive = self.cycles_since_write < 2;
+                self.cycles_since_write = 0;
+                if consecutive {
+                    return;
+                }
+
+                if value & 0x80 == 0x80 {
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.control |= 0x0c;
+                    return;
+                }
+                self.shift = (self.shift >> 1) | ((value & 1) << 4);
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    self.write_register(address, self.shift);
+                    self.shift = 0;
+                    self.shift_count = 0;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn read_chr(&self, address: u16) -> u8 {
+        self.chr[self.chr_address(address)]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_is_ram {
+            let address = self.chr_address(address);
+            self.chr[address] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_tick(&mut self) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(1);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+        state.u8(self.shift);
+        state.u8(self.shift_count);
+        state.u8(self.cycles_since_write);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        self.shift = state.u8()?;
+        self.shift_count = state.u8()?;
+        self.cycles_since_write = state.u8()?;
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        Ok(())
+    }
+}
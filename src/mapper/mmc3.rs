@@ -0,0 +1,285 @@
+use crate::ines::Cartridge;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use super::{chr_memory, header_mirroring, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// How many CPU cycles PPU A12 has to stay low before a rise is counted as a new scanline.
+/// The MMC3 filters A12 like this so that it only sees one rise per scanline, even though A12
+/// toggles during the sprite fetches
+const A12_FILTER_CYCLES: u64 = 3;
+
+/// The revisions of the MMC3 differ in when the scanline counter raises an IRQ
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mmc3Revision {
+    /// MMC3A, and some of the MMC3Bs made by NEC. Reloading the counter with 0 only raises an IRQ
+    /// when the reload was requested by a write to $C001, so a latch of 0 gives a single IRQ
+    A,
+    /// MMC3B and MMC3C made by Sharp, which are in most cartridges. An IRQ is raised whenever the
+    /// counter is 0 after being clocked, so a latch of 0 gives an IRQ every scanline
+    B,
+}
+
+/// Mapper 4, the Nintendo MMC3 (TxROM boards).
+///
+/// PRG-ROM is switched in 8KB banks with the last bank fixed, and CHR in two 2KB banks and four 1KB banks.
+/// Either half of each can be swapped around, which is what the PRG and CHR inversion modes do.
+/// The MMC3 also counts scanlines by watching PPU A12, which rises once per line when the background
+/// and sprites use different pattern tables, and can raise an IRQ when the count runs out
+pub(crate) struct Mmc3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    four_screen: bool,
+
+    /// R0-R7: two 2KB CHR banks, four 1KB CHR banks, then two 8KB PRG banks
+    banks: [u8; 8],
+    /// Which of the bank registers the next write to $8001 goes to
+    bank_select: u8,
+    prg_inversion: bool,
+    chr_inversion: bool,
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+
+    revision: Mmc3Revision,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    /// CPU cycles since power on, used to time how long A12 has been low
+    cycles: u64,
+    a12: bool,
+    a12_fell_at: u64,
+}
+
+impl Mmc3 {
+    pub fn init(cartridge: &Cartridge, prg_ram_size: usize, revision: Mmc3Revision) -> Self {
+        // The last two banks are fixed, so prg_rom_address needs there to be at least two
+        assert!(cartridge.prg_rom_data.len() >= 2 * PRG_BANK_SIZE,
+            "MMC3 needs at least 16KB of PRG-ROM, which parsing should have checked");
+        let (chr, chr_is_ram) = chr_memory(cartridge);
+        let mirroring = header_mirroring(cartridge);
+        Self {
+            prg_rom: cartridge.prg_rom_data.clone(),
+            prg_ram: vec![0; prg_ram_size],
+            chr,
+            chr_is_ram,
+            four_screen: mirroring == Mirroring::FourScreen,
+
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            bank_select: 0,
+            prg_inversion: false,
+            chr_inversion: false,
+            mirroring,
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+
+            revision,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+
+            cycles: 0,
+            a12: false,
+            a12_fell_at: 0,
+        }
+    }
+
+    fn prg_rom_address(&self, address: u16) -> usize {
+        let bank_count = self.prg_rom.len() / PRG_BANK_SIZE;
+        let second_last = bank_count - 2;
+        let bank = match ((address - 0x8000) / 0x2000, self.prg_inversion) {
+            (0, false) | (2, true) => self.banks[6] as usize,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.banks[7] as usize,
+            _ => bank_count - 1,
+        };
+        ((bank % bank_count) * PRG_BANK_SIZE) + (address as usize & 0x1fff)
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        // In 1KB units. Inversion swaps the 2KB banks in the first half with the 1KB banks in the second
+        let slot = (address as usize >> 10) ^ if self.chr_inversion { 4 } else { 0 };
+        let bank = match slot {
+            0 => self.banks[0] & !1,
+            1 => self.banks[0] | 1,
+            2 => self.banks[1] & !1,
+            3 => self.banks[1] | 1,
+            _ => self.banks[slot - 2],
+        };
+        (bank as usize * CHR_BANK_SIZE + (address as usize & 0x3ff)) % self.chr.len()
+    }
+
+    /// The counter is clocked once per scanline, by the filtered rising edge of PPU A12
+    fn clock_irq_counter(&mut self) {
+        let previous = self.irq_counter;
+        let reloaded = self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        }
+        else {
+            self.irq_counter -= 1;
+        }
+        self.irq_reload = false;
+
+        let fire = match self.revision {
+            Mmc3Revision::A => self.irq_counter == 0 && (previous != 0 || reloaded),
+            Mmc3Revision::B => self.irq_counter == 0,
+        };
+        if fire && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()])
+            },
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_rom_address(address)]),
+            _ => None, // Nothing is connected here
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        let even = address & 1 == 0;
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled && !self.prg_ram_write_protect && !self.prg_ram.is_empty() => {
+                let index = (address as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = value;
+            },
+            0x6000..=0x7fff => (),
+            0x8000..=0x9fff if even => {
+                self.bank_select = value & 0b111;
+                self.prg_inversion = value & 0x40 == 0x40;
+                self.chr_inversion = value & 0x80 == 0x80;
+            },
+            0x8000..=0x9fff => self.banks[self.bank_select as usize] = value,
+            // Boards with four screen mirroring have the mirroring hard-wired
+            0xa000..=0xbfff if even && self.four_screen => (),
+            0xa000..=0xbfff if even => {
+                self.mirroring = match value & 1 {
+                    0 => Mirroring::Vertical,
+                    _ => Mirroring::Horizontal,
+                };
+            },
+            0xa000..=0xbfff => {
+                self.prg_ram_enabled = value & 0x80 == 0x80;
+                self.prg_ram_write_protect = value & 0x40 == 0x40;
+            },
+            0xc000..=0xdfff if even => self.irq_latch = value,
+            0xc000..=0xdfff => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            0xe000..=0xffff if even => {
+                // Disabling also acknowledges any pending IRQ
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            0xe000..=0xffff => self.irq_enabled = true,
+            _ => (),
+        }
+    }
+
+    fn read_chr(&self, address: u16) -> u8 {
+        self.chr[self.chr_address(address)]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_is_ram {
+            let address = self.chr_address(address);
+            self.chr[address] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn cpu_tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = address & 0x1000 == 0x1000;
+        if a12 && !self.a12 && self.cycles - self.a12_fell_at >= A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_fell_at = self.cycles;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+        state.bytes(&self.banks);
+        state.u8(self.bank_select);
+        state.bool(self.prg_inversion);
+        state.bool(self.chr_inversion);
+        // Only switchable between these two. Four screen boards ignore $A000, so keep what they have
+        state.bool(self.mirroring == Mirroring::Horizontal);
+        state.bool(self.prg_ram_enabled);
+        state.bool(self.prg_ram_write_protect);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+        state.u64(self.cycles);
+        state.bool(self.a12);
+        state.u64(self.a12_fell_at);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        state.bytes_into(&mut self.banks)?;
+        self.bank_select = state.u8()?;
+        self.prg_inversion = state.bool()?;
+        self.chr_inversion = state.bool()?;
+        let horizontal = state.bool()?;
+        if !self.four_screen {
+            self.mirroring = if horizontal { Mirroring::Horizontal } else { Mirroring::Vertical };
+        }
+        self.prg_ram_enabled = state.bool()?;
+        self.prg_ram_write_protect = state.bool()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        self.cycles = state.u64()?;
+        self.a12 = state.bool()?;
+        self.a12_fell_at = state.u64()?;
+        Ok(())
+    }
+}
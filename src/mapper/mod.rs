@@ -0,0 +1,145 @@
+mod nrom;
+mod mmc1;
+mod mmc3;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::ines::{Cartridge, Flags6, Header};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use nrom::Nrom;
+use mmc1::Mmc1;
+use mmc3::Mmc3;
+
+pub use mmc3::Mmc3Revision;
+
+/// A mapper is shared between the CPU's memory bus and the PPU's, since the cartridge is connected to both
+pub(crate) type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+/// How the PPU's 2KB of nametable RAM is arranged into its four nametables.
+/// The cartridge controls this, through the CIRAM A10 and /CE pins
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mirroring {
+    /// $2000 and $2400 are the same, and $2800 and $2C00 are the same
+    Horizontal,
+    /// $2000 and $2800 are the same, and $2400 and $2C00 are the same
+    Vertical,
+    /// Every nametable is the first 1KB of RAM
+    SingleScreenLower,
+    /// Every nametable is the second 1KB of RAM
+    SingleScreenUpper,
+    /// The cartridge provides another 2KB, so every nametable is separate
+    FourScreen,
+}
+
+/// The circuitry on a cartridge board, which decides what the CPU and PPU see when they access the cartridge.
+/// Anything beyond the simplest boards can switch which banks of the ROMs are visible, and so on
+pub(crate) trait Mapper {
+    /// A CPU read from cartridge space, $4020-$FFFF. None where nothing on the board drives the data bus,
+    /// such as disabled PRG-RAM, so the CPU sees open bus
+    fn read_prg(&self, address: u16) -> Option<u8>;
+    /// A CPU write to cartridge space, $4020-$FFFF
+    fn write_prg(&mut self, address: u16, value: u8);
+    /// A PPU read from the pattern tables, $0000-$1FFF
+    fn read_chr(&self, address: u16) -> u8;
+    /// A PPU write to the pattern tables, $0000-$1FFF. Only does anything on boards with CHR-RAM
+    fn write_chr(&mut self, address: u16, value: u8);
+    fn mirroring(&self) -> Mirroring;
+
+    /// Called once every CPU cycle, for boards which need to keep track of time
+    fn cpu_tick(&mut self) {}
+
+    /// Called with each address the PPU puts on its bus, for boards which watch what the PPU is fetching
+    fn ppu_address(&mut self, _address: u16) {}
+
+    /// Whether the board is asserting the CPU's IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// The board's PRG-RAM at $6000-$7FFF, battery backed or not. Empty if there isn't any
+    fn prg_ram(&self) -> &[u8];
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    /// Writes the board's registers and any RAM on it. ROM isn't saved, since the cartridge will be there on loading
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Mirroring as soldered on the board, which is what the header describes
+fn header_mirroring(cartridge: &Cartridge) -> Mirroring {
+    let flags = cartridge.header.flags_6;
+    if flags.contains(Flags6::four_screen) {
+        Mirroring::FourScreen
+    }
+    else if flags.contains(Flags6::mirroring) {
+        Mirroring::Vertical
+    }
+    else {
+        Mirroring::Horizontal
+    }
+}
+
+/// Boards without CHR-ROM have CHR-RAM instead, 8KB unless the header says otherwise
+fn chr_memory(cartridge: &Cartridge) -> (Vec<u8>, bool) {
+    match cartridge.chr_rom_data.len() {
+        0 => {
+            let size = cartridge.header.chr_ram_size + cartridge.header.chr_nvram_size;
+            (vec![0; size.max(0x2000)], true)
+        },
+        _ => (cartridge.chr_rom_data.clone(), false),
+    }
+}
+
+/// The most PRG-RAM each board can switch into $6000-$7FFF: 32KB on SXROM, and a single 8KB bank otherwise
+fn max_prg_ram_size(mapper: u16) -> usize {
+    match mapper {
+        1 => 0x8000,
+        _ => 0x2000,
+    }
+}
+
+/// The boards we support keep their PRG-RAM at $6000, whether or not it's battery backed.
+/// Where a board has both, the volatile RAM comes first. A header asking for more than the board can reach
+/// only gets what it can reach
+fn prg_ram_size(cartridge: &Cartridge) -> usize {
+    let header = &cartridge.header;
+    (header.prg_ram_size + header.prg_nvram_size).min(max_prg_ram_size(header.mapper))
+}
+
+/// NES 2.0 submapper 4 marks the older MMC3A, and anything else is assumed to have the far more common Sharp chip
+fn mmc3_revision(cartridge: &Cartridge) -> Mmc3Revision {
+    match cartridge.header.submapper {
+        4 => Mmc3Revision::A,
+        _ => Mmc3Revision::B,
+    }
+}
+
+/// Whether `create` can make a mapper for the board the header describes
+pub(crate) fn is_supported(header: &Header) -> bool {
+    matches!(header.mapper, 0 | 1 | 4)
+}
+
+/// Whether the board the header describes can have that much PRG-ROM. The mappers switch PRG-ROM in whole banks,
+/// NROM and MMC1 in 16KB ones and MMC3 in 8KB ones with the last two fixed, so anything else would only fail
+/// once the CPU started reading it
+pub(crate) fn is_valid_prg_size(header: &Header) -> bool {
+    let size = header.prg_rom_size;
+    match header.mapper {
+        0 | 1 => size != 0 && size & 0x3fff == 0,
+        4 => size >= 0x4000 && size & 0x1fff == 0,
+        _ => true,
+    }
+}
+
+/// Creates the mapper for the board given by the iNES mapper number, or None if it isn't supported
+pub(crate) fn create(cartridge: &Cartridge) -> Option<SharedMapper> {
+    let mapper: SharedMapper = match cartridge.header.mapper {
+        0 => Rc::new(RefCell::new(Nrom::init(cartridge, prg_ram_size(cartridge)))),
+        1 => Rc::new(RefCell::new(Mmc1::init(cartridge, prg_ram_size(cartridge)))),
+        4 => Rc::new(RefCell::new(Mmc3::init(cartridge, prg_ram_size(cartridge), mmc3_revision(cartridge)))),
+        _ => return None,
+    };
+    Some(mapper)
+}
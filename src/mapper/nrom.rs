@@ -0,0 +1,88 @@
+use crate::ines::Cartridge;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use super::{chr_memory, header_mirroring, Mapper, Mirroring};
+
+/// Mapper 0. No bank switching at all: 16KB or 32KB of PRG-ROM at $8000, and 8KB of CHR.
+/// A few boards, like Family BASIC's, also have PRG-RAM at $6000
+pub(crate) struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn init(cartridge: &Cartridge, prg_ram_size: usize) -> Self {
+        assert!(!cartridge.prg_rom_data.is_empty(), "NROM needs some PRG-ROM, which parsing should have checked");
+        let (chr, chr_is_ram) = chr_memory(cartridge);
+        Self {
+            prg_rom: cartridge.prg_rom_data.clone(),
+            prg_ram: vec![0; prg_ram_size],
+            chr,
+            chr_is_ram,
+            mirroring: header_mirroring(cartridge),
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()])
+            },
+            // NROM-128 only has 16KB, which is just decoded again at $C000
+            0x8000..=0xffff => Some(self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()]),
+            _ => None, // Nothing is connected here
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                let index = (address as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = value;
+            },
+            _ => (), // ROM can't be written to
+        }
+    }
+
+    fn read_chr(&self, address: u16) -> u8 {
+        self.chr[address as usize & 0x1fff]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_is_ram {
+            self.chr[address as usize & 0x1fff] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
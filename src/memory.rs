@@ -1,40 +1,186 @@
 use std::convert::Into;
 
+use bitflags::*;
+
+use crate::apu::APU;
+use crate::controller::{Port, SharedInputDevice};
 use crate::ines::Cartridge;
+use crate::mapper::{self, SharedMapper};
 use crate::ppu::PPU;
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+bitflags! {
+    /// The devices which can hold the CPU's shared IRQ line low.
+    /// The line is wired-OR, so an IRQ is requested for as long as any one of them is asserting it
+    pub struct IrqSource: u8 {
+        /// Anything outside the console, e.g. the expansion port
+        const EXTERNAL = 0b00000001;
+        const MAPPER = 0b00000010;
+        const APU_FRAME_COUNTER = 0b00000100;
+        const APU_DMC = 0b00001000;
+    }
+}
+
+bitflags! {
+    /// The kinds of access a watchpoint stops on
+    pub struct WatchKind: u8 {
+        const READ = 0b00000001;
+        const WRITE = 0b00000010;
+        /// Checked by the debugger before each instruction, rather than on the bus
+        const EXECUTE = 0b00000100;
+    }
+}
+
+/// Watches an inclusive range of addresses for the given kinds of access
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, kind: WatchKind) -> bool {
+        (self.start..=self.end).contains(&address) && self.kind.intersects(kind)
+    }
+}
 
-const OAMADDR: u16 = 0x2003;
+/// An access which a watchpoint caught
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    /// Either READ or WRITE
+    pub kind: WatchKind,
+    /// The value read or written
+    pub value: u8,
+}
 
 /// A representation of the CPU's access to memory
 pub(crate) struct MemoryBus {
     /// 2kb of RAM
     memory: [u8; 2048],
-    rom_data: [u8; 32768],
+    /// The cartridge's view of $4020-$FFFF. None until a cartridge has been loaded
+    mapper: Option<SharedMapper>,
     ppu: PPU,
-    cycles: u8,
+    apu: APU,
+    /// What's plugged into each controller port
+    ports: [Option<SharedInputDevice>; 2],
+    /// The last value read or written. Addresses nothing drives read back as this
+    data_bus: u8,
+    /// Number of CPU cycles since power on
+    cycles: u64,
+
+    irq: IrqSource,
+    /// Level of the NMI line on the previous cycle, used to detect the falling edge
+    nmi_line: bool,
+    /// Latched by the edge detector, stays set until the CPU services it
+    nmi_pending: bool,
+
+    /// Checked on every read and write the CPU makes, for the debugger
+    watchpoints: Vec<Watchpoint>,
+    /// The first access caught since the debugger last looked
+    watch_hit: Option<WatchHit>,
 }
 
 impl MemoryBus {
     pub fn init() -> Self {
         Self {
             memory: [0; 2048],
-            rom_data: [0; 32768],
+            mapper: None,
             ppu: PPU::init(),
+            apu: APU::init(),
+            ports: [None, None],
+            data_bus: 0,
             cycles: 0,
+
+            irq: IrqSource::empty(),
+            nmi_line: false,
+            nmi_pending: false,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        }
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    /// Reads memory without any of the side effects a real read might have.
+    /// Only RAM and cartridge space can be peeked; anything else reads as $FF
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1fff => self.memory[address as usize & 0x7ff],
+            0x4020..=0xffff => self.peek_cartridge(address),
+            _ => 0xff,
         }
     }
 
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Whether any device is currently asserting IRQ
+    pub fn irq_line(&self) -> bool {
+        !self.irq.is_empty()
+    }
+
+    pub fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        self.irq.set(source, asserted);
+    }
+
+    /// Returns true if an NMI edge has been detected since the last call, acknowledging it
+    pub fn take_nmi(&mut self) -> bool {
+        let pending = self.nmi_pending;
+        self.nmi_pending = false;
+        pending
+    }
+
     pub fn read<T: Into<u16>>(&mut self, address: T) -> u8 {
-        let result = self.read_byte(address.into());
+        let address = address.into();
+        let result = self.read_byte(address);
+        if !self.watchpoints.is_empty() {
+            self.watch(address, WatchKind::READ, result);
+        }
+        self.data_bus = result;
         self.tick();
         result
     }
 
     pub fn write<T: Into<u16>>(&mut self, address: T, value: u8) {
-        self.write_byte(address.into(), value);
+        let address = address.into();
+        if !self.watchpoints.is_empty() {
+            self.watch(address, WatchKind::WRITE, value);
+        }
+        self.write_byte(address, value);
+        self.data_bus = value;
         self.tick();
     }
 
+    pub fn set_watchpoints(&mut self, watchpoints: &[Watchpoint]) {
+        self.watchpoints = watchpoints.to_vec();
+        self.watch_hit = None;
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn watch(&mut self, address: u16, kind: WatchKind, value: u8) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(address, kind)) {
+            self.watch_hit = Some(WatchHit { address, kind, value });
+        }
+    }
+
     pub fn read_byte(&mut self, address: u16) -> u8 {
         // Match syntax is much neater than ifs, but unfortunately exclusive ranges
         // (low <= x < high) are feature-gated, and so only live on nightly
@@ -46,9 +192,12 @@ impl MemoryBus {
             // There are 8 memory-mapped PPU registers, and these are mirrored for the next block
             // Since only 8 values, only the first 3 bits matter, so mask it and provide it to the PPU
             0x2000..=0x3fff => self.ppu.read_register((address & 0x7) as u8),
-            0x8000..=0xffff => self.rom_data[address as usize - 0x8000],
-            _ => panic!(""), // TODO: Do we need to return a Result?
-                             // I don't know if any NES programs ever attempt to read non-valid memory addresses
+            0x4015 => self.apu.read_status(self.data_bus),
+            0x4016 => self.read_port(Port::One),
+            0x4017 => self.read_port(Port::Two),
+            0x4020..=0xffff => self.peek_cartridge(address),
+            // The rest of the APU's registers are write only, and $4018-$401F are normally disabled
+            _ => self.data_bus,
         }
     }
 
@@ -59,11 +208,21 @@ impl MemoryBus {
             0x1000..=0x17ff => self.memory[address as usize - 0x1000] = value,
             0x1800..=0x1fff => self.memory[address as usize - 0x1800] = value,
             // There are 8 memory-mapped PPU registers, and these are mirrored for the next block
-            // Since only 8 values, only the first nibble matters, so mask it and provide it to the PPU
-            0x2000..=0x3fff => self.ppu.write_register((address & 0xf) as u8, value),
-            // Mirrors of 0x2000..0x2007
+            // Since only 8 values, only the first 3 bits matter, so mask it and provide it to the PPU
+            0x2000..=0x3fff => self.ppu.write_register((address & 0x7) as u8, value),
             0x4014 => self.write_dma(value),
-            0x8000..=0xffff => self.rom_data[address as usize - 0x8000] = value, 
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(address, value),
+            // The OUT lines go to both ports
+            0x4016 => {
+                for device in self.ports.iter().flatten() {
+                    device.borrow_mut().write(value);
+                }
+            },
+            0x4020..=0xffff => {
+                if let Some(mapper) = &self.mapper {
+                    mapper.borrow_mut().write_prg(address, value);
+                }
+            },
             _ => (), // If memory isn't mapped, do nothing
         }
     }
@@ -76,7 +235,7 @@ impl MemoryBus {
         if self.cycles % 2 == 1 {
             self.tick();
         }
-        for i in 1..256 {
+        for i in 0..256 {
             let data = self.read_byte(0x100 * (offset as u16) + i);
             self.tick();
             self.ppu.write_oam_data(data);
@@ -84,16 +243,144 @@ impl MemoryBus {
         }
     }
 
+    /// Plugs a device into a controller port, or unplugs whatever was there
+    pub fn connect(&mut self, port: Port, device: Option<SharedInputDevice>) {
+        self.ports[port as usize] = device;
+    }
+
+    /// The port only drives the low 5 bits of the data bus, leaving the rest as they were, which is usually $40
+    /// from the address. Nothing plugged in reads as 0
+    fn read_port(&mut self, port: Port) -> u8 {
+        let value = match &self.ports[port as usize] {
+            Some(device) => device.borrow_mut().read() & 0x1f,
+            None => 0,
+        };
+        (self.data_bus & 0xe0) | value
+    }
+
+    /// Connects the cartridge to both the CPU and PPU buses, through the mapper for its board
     pub fn load_cartridge(&mut self, cartridge: &Cartridge) {
-        let dest = &mut self.rom_data;
-        // Note upper bound one higher than largest index, because it's just how ranges work
-        let rom  = &cartridge.prg_rom_data[0x0000..0x8000]; 
-        dest.copy_from_slice(rom);
+        let mapper = match mapper::create(cartridge) {
+            Some(mapper) => mapper,
+            None => panic!("Unsupported mapper {}", cartridge.header.mapper),
+        };
+        self.ppu.load_cartridge(mapper.clone());
+        self.set_region(Region::from_timing(cartridge.header.timing));
+        self.mapper = Some(mapper);
+    }
+
+    /// Puts RAM and every chip back the way they are at power on, with a fresh board for the cartridge.
+    /// What's plugged into the ports stays plugged in, and the region, sample rate and watchpoints are kept
+    pub fn power_cycle(&mut self, cartridge: &Cartridge) {
+        let region = self.ppu.region();
+        let sample_rate = self.apu.sample_rate();
+        let ports = std::mem::take(&mut self.ports);
+        let watchpoints = std::mem::take(&mut self.watchpoints);
+        *self = MemoryBus::init();
+        self.ports = ports;
+        self.watchpoints = watchpoints;
+        self.load_cartridge(cartridge);
+        self.set_region(region);
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    /// A copy of the cartridge's PRG-RAM. Empty if it has none
+    pub fn prg_ram(&self) -> Vec<u8> {
+        match &self.mapper {
+            Some(mapper) => mapper.borrow().prg_ram().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Overwrites the cartridge's PRG-RAM from `offset` onwards
+    pub fn load_prg_ram(&mut self, offset: usize, data: &[u8]) {
+        if let Some(mapper) = &self.mapper {
+            mapper.borrow_mut().prg_ram_mut()[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    /// Writes a section for the bus itself, then one for each chip on it. What's plugged into the
+    /// controller ports belongs to the frontend, so isn't saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"BUS ", |state| {
+            state.bytes(&self.memory);
+            state.u8(self.data_bus);
+            state.u64(self.cycles);
+            state.u8(self.irq.bits);
+            state.bool(self.nmi_line);
+            state.bool(self.nmi_pending);
+        });
+        state.section(b"PPU ", |state| self.ppu.save_state(state));
+        state.section(b"APU ", |state| self.apu.save_state(state));
+        if let Some(mapper) = &self.mapper {
+            state.section(b"MAPR", |state| mapper.borrow().save_state(state));
+        }
+    }
+
+    pub fn load_state(&mut self, state: &StateReader) -> Result<(), StateError> {
+        let mut bus = state.section(b"BUS ")?;
+        bus.bytes_into(&mut self.memory)?;
+        self.data_bus = bus.u8()?;
+        self.cycles = bus.u64()?;
+        self.irq = IrqSource::from_bits_truncate(bus.u8()?);
+        self.nmi_line = bus.bool()?;
+        self.nmi_pending = bus.bool()?;
+
+        self.ppu.load_state(&mut state.section(b"PPU ")?)?;
+        self.apu.load_state(&mut state.section(b"APU ")?)?;
+        if let Some(mapper) = &self.mapper {
+            mapper.borrow_mut().load_state(&mut state.section(b"MAPR")?)?;
+        }
+        Ok(())
+    }
+
+    /// Reads from cartridge space have no side effects on any of the boards we support.
+    /// Where the board doesn't drive the data bus, the CPU reads whatever was last on it
+    fn peek_cartridge(&self, address: u16) -> u8 {
+        match &self.mapper {
+            Some(mapper) => mapper.borrow().read_prg(address).unwrap_or(self.data_bus),
+            None => self.data_bus,
+        }
     }
 
     fn tick(&mut self) {
-        let (cycles, _) = self.cycles.overflowing_add(1);
-        self.cycles = cycles;
+        self.cycles += 1;
         self.ppu.step();
+        if let Some(mapper) = &self.mapper {
+            let mut mapper = mapper.borrow_mut();
+            mapper.cpu_tick();
+            self.irq.set(IrqSource::MAPPER, mapper.irq());
+        }
+        self.apu.step();
+        self.irq.set(IrqSource::APU_FRAME_COUNTER, self.apu.frame_irq());
+        self.irq.set(IrqSource::APU_DMC, self.apu.dmc_irq());
+
+        // NMI is edge sensitive: the CPU only reacts to the line going from high to low (modelled here as
+        // false to true), so the PPU holding it active for the whole of vblank only produces one interrupt
+        let nmi_line = self.ppu.nmi_line();
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
+
+        if let Some(address) = self.apu.dmc_fetch_request() {
+            self.dmc_fetch(address);
+        }
+    }
+
+    /// Fetches a sample byte for the DMC. The CPU is halted while this happens, for 4 cycles in the usual case:
+    /// the halt, a dummy cycle, an alignment cycle, then the read itself
+    fn dmc_fetch(&mut self, address: u16) {
+        for _ in 0..3 {
+            self.tick();
+        }
+        let value = self.read_byte(address);
+        self.tick();
+        self.apu.dmc_fill(value);
     }
 }
\ No newline at end of file
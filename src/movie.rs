@@ -0,0 +1,418 @@
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bitflags::*;
+
+use crate::controller::Buttons;
+use crate::cpu::CPU;
+use crate::region::Region;
+use crate::savestate::{self, StateError};
+
+/// The buttons in the order FM2 writes them, which is from the highest bit of `Buttons` down
+const BUTTON_LETTERS: &[u8; 8] = b"RLDUTSBA";
+
+bitflags! {
+    /// What's done to the console at the start of a frame, besides pressing buttons.
+    /// FM2 also has commands for the Famicom Disk System and VS. System, which aren't emulated
+    pub struct Commands: u8 {
+        const SOFT_RESET = 0b00000001;
+        const HARD_RESET = 0b00000010;
+    }
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// A line which doesn't make sense, numbered from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// The movie is fine, but needs something which isn't emulated, e.g. a Zapper
+    Unsupported(String),
+    /// The save state the movie starts from couldn't be loaded
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "Couldn't read or write movie: {}", e),
+            MovieError::Parse { line, message } => write!(f, "Movie is invalid on line {}: {}", line, message),
+            MovieError::Unsupported(what) => write!(f, "Movie uses {}, which isn't supported", what),
+            MovieError::State(e) => write!(f, "Couldn't load the save state the movie starts from: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// Playback has gone differently from the recording, found by the RAM not matching a frame's hash
+#[derive(Debug, PartialEq, Eq)]
+pub struct Desync {
+    /// Numbered from 0, as the movie's first frame
+    pub frame: usize,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Movie desynced on frame {}: RAM hash is {:016x}, but was {:016x} when recorded",
+            self.frame, self.found, self.expected)
+    }
+}
+
+impl std::error::Error for Desync {}
+
+/// One frame of a movie
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub commands: Commands,
+    /// Players 1 and 2
+    pub buttons: [Buttons; 2],
+    /// `ram_hash` at the end of the frame. This isn't part of FM2: it's written after the last | of the line,
+    /// and other emulators' movies won't have it
+    pub ram_hash: Option<u64>,
+}
+
+/// The controller input for every frame, from power on or from a save state, in FCEUX's FM2 text format.
+///
+/// FM2 is a header of `key value` lines, then a line per frame like `|0|R..U...A|........||`: the commands,
+/// then the buttons on ports 1 and 2 in RLDUTSBA order with a . for each one not held, then the expansion
+/// port. Only gamepads are supported, and the ROM's checksum isn't checked or written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_filename: String,
+    pub pal: bool,
+    /// How many times recording went back over frames it had already recorded
+    pub rerecords: u32,
+    /// What the movie starts from. None means power on
+    pub savestate: Option<Vec<u8>>,
+    /// Whether ports 1 and 2 have gamepads plugged in. An empty port's buttons are always empty
+    pub gamepads: [bool; 2],
+    /// Header lines which aren't used here, e.g. comments, kept so they're written back out
+    pub extra: Vec<(String, String)>,
+    pub frames: Vec<Frame>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, pal: bool, savestate: Option<Vec<u8>>) -> Self {
+        Self {
+            rom_filename: rom_filename.to_string(),
+            pal,
+            rerecords: 0,
+            savestate,
+            gamepads: [true, true],
+            extra: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::parse(&fs::read_to_string(path).map_err(MovieError::Io)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        fs::write(path, self.to_fm2()).map_err(MovieError::Io)
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new("", false, None);
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: String| MovieError::Parse { line: number + 1, message };
+            if line.starts_with('|') {
+                movie.frames.push(parse_frame(line, movie.gamepads).map_err(error)?);
+                continue;
+            }
+            let (key, value) = match line.trim().split_once(' ') {
+                Some((key, value)) => (key, value.trim()),
+                None => (line.trim(), ""),
+            };
+            match key {
+                "" => (),
+                "version" if value != "3" => return Err(MovieError::Unsupported(format!("FM2 version {}", value))),
+                "binary" if value != "0" => return Err(MovieError::Unsupported("binary input".to_string())),
+                "fourscore" if value != "0" => return Err(MovieError::Unsupported("the Four Score".to_string())),
+                "port2" if value != "0" => return Err(MovieError::Unsupported("the expansion port".to_string())),
+                "version" | "binary" | "fourscore" | "port2" => (),
+                "port0" | "port1" => {
+                    let port = (key == "port1") as usize;
+                    movie.gamepads[port] = match value {
+                        "0" => false,
+                        "1" => true,
+                        "2" => return Err(MovieError::Unsupported("the Zapper".to_string())),
+                        _ => return Err(error(format!("Unknown device {} in {}", value, key))),
+                    };
+                },
+                "rerecordCount" => {
+                    movie.rerecords = value.parse().map_err(|_| error(format!("{} isn't a number", value)))?;
+                },
+                "palFlag" => movie.pal = value == "1",
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "savestate" => {
+                    let state = decode_savestate(value);
+                    movie.savestate = Some(state.ok_or_else(|| error("Couldn't decode the save state".to_string()))?);
+                },
+                _ => movie.extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        // Writing to a String can't fail
+        let _ = writeln!(text, "version 3");
+        let _ = writeln!(text, "rerecordCount {}", self.rerecords);
+        let _ = writeln!(text, "palFlag {}", self.pal as u8);
+        let _ = writeln!(text, "romFilename {}", self.rom_filename);
+        let _ = writeln!(text, "fourscore 0");
+        let _ = writeln!(text, "port0 {}", self.gamepads[0] as u8);
+        let _ = writeln!(text, "port1 {}", self.gamepads[1] as u8);
+        let _ = writeln!(text, "port2 0");
+        for (key, value) in &self.extra {
+            let _ = writeln!(text, "{} {}", key, value);
+        }
+        if let Some(state) = &self.savestate {
+            let _ = writeln!(text, "savestate base64:{}", base64::encode(state));
+        }
+        for frame in &self.frames {
+            let _ = write!(text, "|{}|", frame.commands.bits());
+            for (buttons, gamepad) in frame.buttons.iter().zip(self.gamepads.iter()) {
+                if *gamepad {
+                    text.extend(BUTTON_LETTERS.iter().enumerate().map(|(i, letter)| {
+                        match buttons.bits() & (0x80 >> i) {
+                            0 => '.',
+                            _ => *letter as char,
+                        }
+                    }));
+                }
+                text.push('|');
+            }
+            text.push('|');
+            if let Some(hash) = frame.ram_hash {
+                let _ = write!(text, "{:016x}", hash);
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// A frame line: `|commands|port 1|port 2|expansion port|`, then maybe our RAM hash
+fn parse_frame(line: &str, gamepads: [bool; 2]) -> Result<Frame, String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() != 6 {
+        return Err(format!("Expected 5 |s in a frame, not {}", fields.len() - 1));
+    }
+    let commands = fields[1].trim().parse().map_err(|_| format!("Commands {} aren't a number", fields[1]))?;
+    let commands = Commands::from_bits(commands)
+        .ok_or_else(|| format!("Commands {} are for the Famicom Disk System or VS. System", commands))?;
+
+    let mut buttons = [Buttons::empty(); 2];
+    for (port, gamepad) in gamepads.iter().enumerate() {
+        let field = fields[port + 2].as_bytes();
+        if !*gamepad {
+            continue;
+        }
+        if field.len() != BUTTON_LETTERS.len() {
+            return Err(format!("Expected {} buttons for port {}", BUTTON_LETTERS.len(), port + 1));
+        }
+        // Anything but a space or . means the button's held
+        let bits = field.iter().enumerate()
+            .filter(|(_, letter)| **letter != b'.' && **letter != b' ')
+            .fold(0, |bits, (i, _)| bits | 0x80 >> i);
+        buttons[port] = Buttons::from_bits_truncate(bits);
+    }
+
+    let ram_hash = match fields[5].trim() {
+        "" => None,
+        hash => Some(u64::from_str_radix(hash, 16).map_err(|_| format!("{} isn't a RAM hash", hash))?),
+    };
+    Ok(Frame {
+        commands,
+        buttons,
+        ram_hash,
+    })
+}
+
+/// FCEUX writes binary values as either base64:... or 0x... in hex
+fn decode_savestate(value: &str) -> Option<Vec<u8>> {
+    if let Some(data) = value.strip_prefix("base64:") {
+        return base64::decode(data).ok();
+    }
+    let hex = value.strip_prefix("0x")?;
+    if hex.len() & 1 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// A hash of the 2KB of RAM, which is enough to notice a movie desyncing soon after it happens
+pub fn ram_hash(cpu: &CPU) -> u64 {
+    let ram: Vec<u8> = (0..0x800).map(|address| cpu.peek(address)).collect();
+    savestate::fingerprint(&ram)
+}
+
+fn apply(cpu: &mut CPU, commands: Commands) {
+    if commands.contains(Commands::HARD_RESET) {
+        cpu.power_cycle();
+    }
+    else if commands.contains(Commands::SOFT_RESET) {
+        cpu.reset();
+    }
+}
+
+/// Records a movie as the game is played, with a RAM hash for every frame
+pub struct Recording {
+    movie: Movie,
+    /// Commands to carry out at the start of the next frame
+    pending: Commands,
+    /// Whether frames have been dropped by rewinding since the last one was recorded
+    rewound: bool,
+}
+
+impl Recording {
+    /// Starts from power on, switching the console off and on to get there. Battery backed RAM survives that,
+    /// so it should be empty for the movie to play back anywhere else
+    pub fn from_power_on(cpu: &mut CPU, rom_filename: &str) -> Self {
+        cpu.power_cycle();
+        Self::new(Movie::new(rom_filename, cpu.region() == Region::Pal, None))
+    }
+
+    /// Starts from wherever the console is, saving its state in the movie
+    pub fn from_state(cpu: &CPU, rom_filename: &str) -> Self {
+        Self::new(Movie::new(rom_filename, cpu.region() == Region::Pal, Some(cpu.save_state())))
+    }
+
+    fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            pending: Commands::empty(),
+            rewound: false,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+
+    /// Resets the console at the start of the next frame, so that it's part of the movie
+    pub fn reset(&mut self, hard: bool) {
+        self.pending |= match hard {
+            true => Commands::HARD_RESET,
+            false => Commands::SOFT_RESET,
+        };
+    }
+
+    /// Called before each frame is run, with the buttons it'll be run with. Carries out any pending reset,
+    /// returning what was done
+    pub fn begin_frame(&mut self, cpu: &mut CPU, buttons: [Buttons; 2]) -> Commands {
+        if self.rewound {
+            self.movie.rerecords += 1;
+            self.rewound = false;
+        }
+        let commands = std::mem::replace(&mut self.pending, Commands::empty());
+        apply(cpu, commands);
+        self.movie.frames.push(Frame {
+            commands,
+            buttons,
+            ram_hash: None,
+        });
+        commands
+    }
+
+    /// Called once the frame has run
+    pub fn end_frame(&mut self, cpu: &CPU) {
+        if let Some(frame) = self.movie.frames.last_mut() {
+            frame.ram_hash = Some(ram_hash(cpu));
+        }
+    }
+
+    /// Drops the last frame, after the console has been rewound to before it
+    pub fn step_back(&mut self) {
+        if self.movie.frames.pop().is_some() {
+            self.rewound = true;
+        }
+    }
+}
+
+/// Plays a movie back, supplying each frame's buttons in place of the player's
+pub struct Playback {
+    movie: Movie,
+    /// The next frame to play
+    frame: usize,
+}
+
+impl Playback {
+    /// Puts the console where the movie starts, in its save state or just switched on, and in PAL or NTSC
+    /// mode to match it
+    pub fn start(cpu: &mut CPU, movie: Movie) -> Result<Self, MovieError> {
+        if movie.pal != (cpu.region() == Region::Pal) {
+            cpu.set_region(match movie.pal {
+                true => Region::Pal,
+                false => Region::Ntsc,
+            });
+        }
+        match &movie.savestate {
+            Some(state) => cpu.load_state(state).map_err(MovieError::State)?,
+            None => cpu.power_cycle(),
+        }
+        Ok(Self {
+            movie,
+            frame: 0,
+        })
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// How many frames have been played
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// Called before each frame is run. Carries out the frame's commands, and returns it for its buttons,
+    /// or None once the movie is over
+    pub fn begin_frame(&mut self, cpu: &mut CPU) -> Option<&Frame> {
+        let frame = self.movie.frames.get(self.frame)?;
+        apply(cpu, frame.commands);
+        self.frame += 1;
+        Some(frame)
+    }
+
+    /// Called once the frame has run, to check the RAM against the frame's hash if it has one
+    pub fn end_frame(&self, cpu: &CPU) -> Result<(), Desync> {
+        let frame = match self.frame.checked_sub(1).and_then(|frame| self.movie.frames.get(frame)) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        match frame.ram_hash {
+            Some(expected) => {
+                let found = ram_hash(cpu);
+                match found == expected {
+                    true => Ok(()),
+                    false => Err(Desync { frame: self.frame - 1, expected, found }),
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Goes back a frame, after the console has been rewound to before it
+    pub fn step_back(&mut self) {
+        self.frame = self.frame.saturating_sub(1);
+    }
+}
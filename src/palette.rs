@@ -0,0 +1,122 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Number of entries in a full palette: 64 colours for each of the 8 combinations of emphasis bits
+pub const PALETTE_SIZE: usize = 512;
+
+/// How much a colour channel is dimmed by emphasising the other channels, as measured on a 2C02
+const EMPHASIS_ATTENUATION: f64 = 0.816328;
+
+/// A palette generated from a 2C02 PPU's composite output, as a reasonable default for NTSC consoles
+const DEFAULT_PALETTE: [u32; 64] = [
+    0x666666, 0x002a88, 0x1412a7, 0x3b00a4, 0x5c007e, 0x6e0040, 0x6c0600, 0x561d00,
+    0x333500, 0x0b4800, 0x005200, 0x004f08, 0x00404d, 0x000000, 0x000000, 0x000000,
+    0xadadad, 0x155fd9, 0x4240ff, 0x7527fe, 0xa01acc, 0xb71e7b, 0xb53120, 0x994e00,
+    0x6b6d00, 0x388700, 0x0c9300, 0x008f32, 0x007c8d, 0x000000, 0x000000, 0x000000,
+    0xfffeff, 0x64b0ff, 0x9290ff, 0xc676ff, 0xf36aff, 0xfe6ecc, 0xfe8170, 0xea9e22,
+    0xbcbe00, 0x88d800, 0x5ce430, 0x45e082, 0x48cdde, 0x4f4f4f, 0x000000, 0x000000,
+    0xfffeff, 0xc0dfff, 0xd3d2ff, 0xe8c8ff, 0xfbc2ff, 0xfec4ea, 0xfeccc5, 0xf7d8a5,
+    0xe4e594, 0xcfef96, 0xbdf4ab, 0xb3f3cc, 0xb5ebf2, 0xb8b8b8, 0x000000, 0x000000,
+];
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    /// A .pal file has to be 64 or 512 RGB triples
+    BadSize(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(e) => write!(f, "Couldn't read palette: {}", e),
+            PaletteError::BadSize(size) => {
+                write!(f, "Palette files should be {} or {} bytes long, not {}", 64 * 3, PALETTE_SIZE * 3, size)
+            },
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+/// Turns the PPU's pixels into RGBA colours.
+///
+/// Each pixel from the PPU is a 6 bit colour, plus the 3 emphasis bits in red, green, blue order above it,
+/// which is the same layout as a 512 entry .pal file. Palettes with only 64 entries have the emphasised
+/// colours made up by dimming the channels which aren't emphasised
+#[derive(Clone)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        let colours: Vec<[u8; 3]> = DEFAULT_PALETTE.iter()
+            .map(|rgb| [(rgb >> 16) as u8, (rgb >> 8) as u8, *rgb as u8])
+            .collect();
+        Palette::from_colours(colours)
+    }
+}
+
+impl Palette {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, PaletteError> {
+        let bytes = fs::read(path).map_err(PaletteError::Io)?;
+        Palette::from_bytes(&bytes)
+    }
+
+    /// Reads a palette in the .pal format: 64 or 512 RGB triples, one byte per channel
+    pub fn from_bytes(bytes: &[u8]) -> Result<Palette, PaletteError> {
+        match bytes.len() {
+            192 | 1536 => (),
+            size => return Err(PaletteError::BadSize(size)),
+        }
+        let colours = bytes.chunks(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect();
+        Ok(Palette::from_colours(colours))
+    }
+
+    /// Fills in the emphasised colours, if only the first 64 are given
+    fn from_colours(mut colours: Vec<[u8; 3]>) -> Palette {
+        if colours.len() == 64 {
+            for emphasis in 1..8 {
+                for colour in 0..64 {
+                    colours.push(emphasise(colours[colour], colour, emphasis));
+                }
+            }
+        }
+        Palette {
+            colours,
+        }
+    }
+
+    /// The colour of one pixel from the PPU
+    pub fn rgba(&self, pixel: u16) -> [u8; 4] {
+        let [r, g, b] = self.colours[pixel as usize % PALETTE_SIZE];
+        [r, g, b, 0xff]
+    }
+
+    /// Converts a whole frame, writing 4 bytes per pixel into `output`
+    pub fn convert(&self, framebuffer: &[u16], output: &mut [u8]) {
+        for (pixel, rgba) in framebuffer.iter().zip(output.chunks_exact_mut(4)) {
+            rgba.copy_from_slice(&self.rgba(*pixel));
+        }
+    }
+}
+
+/// Dims each channel which isn't emphasised. The blacks in columns $E and $F aren't affected,
+/// since they're generated without any colour signal
+fn emphasise(rgb: [u8; 3], colour: usize, emphasis: usize) -> [u8; 3] {
+    if colour & 0x0e == 0x0e {
+        return rgb;
+    }
+    let mut result = rgb;
+    for (channel, value) in result.iter_mut().enumerate() {
+        // Emphasising a channel dims the other two
+        let dimmed = (0..3).any(|other| other != channel && emphasis & (1 << other) != 0);
+        if dimmed {
+            *value = (*value as f64 * EMPHASIS_ATTENUATION).round() as u8;
+        }
+    }
+    result
+}
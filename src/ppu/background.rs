@@ -0,0 +1,120 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The background half of the rendering pipeline.
+///
+/// Every 8 dots the PPU fetches the next tile's nametable byte, attribute byte and two pattern
+/// bytes into latches, then loads them into the bottom of the shift registers. The shift registers
+/// hold two tiles at a time, and move one pixel along every dot, so fine X scrolling is just a
+/// matter of which bit gets picked out of them
+pub(crate) struct Background {
+    /// Fetched for the next tile, waiting to be loaded into the shift registers
+    nametable: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+
+    /// Pattern data for the current tile in the high byte, and the next in the low byte
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    /// Attribute bits for the current tile. These are only 8 bits, and are refilled one bit at a time
+    /// from the latches, which hold the next tile's palette
+    attribute_shift_low: u8,
+    attribute_shift_high: u8,
+    attribute_latch_low: bool,
+    attribute_latch_high: bool,
+}
+
+impl Background {
+    pub fn init() -> Self {
+        Self {
+            nametable: 0,
+            attribute: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+
+            pattern_shift_low: 0,
+            pattern_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
+            attribute_latch_low: false,
+            attribute_latch_high: false,
+        }
+    }
+
+    /// The tile number fetched from the nametable
+    pub fn tile(&self) -> u8 {
+        self.nametable
+    }
+
+    pub fn set_tile(&mut self, value: u8) {
+        self.nametable = value;
+    }
+
+    /// Stores the tile's two palette bits, already shifted out of the attribute byte
+    pub fn set_attribute(&mut self, palette: u8) {
+        self.attribute = palette & 0b11;
+    }
+
+    pub fn set_pattern_low(&mut self, value: u8) {
+        self.pattern_low = value;
+    }
+
+    pub fn set_pattern_high(&mut self, value: u8) {
+        self.pattern_high = value;
+    }
+
+    /// Moves the fetched tile into the bottom of the shift registers
+    pub fn reload(&mut self) {
+        self.pattern_shift_low = (self.pattern_shift_low & 0xff00) | self.pattern_low as u16;
+        self.pattern_shift_high = (self.pattern_shift_high & 0xff00) | self.pattern_high as u16;
+        self.attribute_latch_low = self.attribute & 0b01 != 0;
+        self.attribute_latch_high = self.attribute & 0b10 != 0;
+    }
+
+    pub fn shift(&mut self) {
+        self.pattern_shift_low <<= 1;
+        self.pattern_shift_high <<= 1;
+        self.attribute_shift_low = (self.attribute_shift_low << 1) | self.attribute_latch_low as u8;
+        self.attribute_shift_high = (self.attribute_shift_high << 1) | self.attribute_latch_high as u8;
+    }
+
+    /// The pixel at the front of the shift registers, offset by fine X, as an index into the background
+    /// palettes. 0 is transparent, whichever palette the tile uses
+    pub fn pixel(&self, fine_x: u8) -> u8 {
+        let pattern_bit = 15 - fine_x as u16;
+        let attribute_bit = 7 - fine_x;
+        let pixel = (((self.pattern_shift_high >> pattern_bit) & 1) << 1) | ((self.pattern_shift_low >> pattern_bit) & 1);
+        if pixel == 0 {
+            return 0;
+        }
+        let palette = (((self.attribute_shift_high >> attribute_bit) & 1) << 1) | ((self.attribute_shift_low >> attribute_bit) & 1);
+        (palette << 2) | pixel as u8
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.nametable);
+        state.u8(self.attribute);
+        state.u8(self.pattern_low);
+        state.u8(self.pattern_high);
+        state.u16(self.pattern_shift_low);
+        state.u16(self.pattern_shift_high);
+        state.u8(self.attribute_shift_low);
+        state.u8(self.attribute_shift_high);
+        state.bool(self.attribute_latch_low);
+        state.bool(self.attribute_latch_high);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.nametable = state.u8()?;
+        self.attribute = state.u8()?;
+        self.pattern_low = state.u8()?;
+        self.pattern_high = state.u8()?;
+        self.pattern_shift_low = state.u16()?;
+        self.pattern_shift_high = state.u16()?;
+        self.attribute_shift_low = state.u8()?;
+        self.attribute_shift_high = state.u8()?;
+        self.attribute_latch_low = state.bool()?;
+        self.attribute_latch_high = state.bool()?;
+        Ok(())
+    }
+}
@@ -1,18 +1,111 @@
-use std::rc::Rc;
-use std::cell::RefCell;
-
-use crate::ines::Cartridge;
+use crate::mapper::{Mirroring, SharedMapper};
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 pub(crate) struct GraphicsMemory {
-    pub ram: [u8; 0x800],
-    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    /// The console's 2KB of nametable RAM (CIRAM), followed by the extra 2KB four screen boards provide
+    pub ram: [u8; 0x1000],
+    /// 32 bytes of palette RAM: the background palettes, then the sprite palettes
+    pub palette: [u8; 0x20],
+    /// The pattern tables live on the cartridge. None until a cartridge has been loaded
+    mapper: Option<SharedMapper>,
 }
 
 impl GraphicsMemory {
     pub fn init() -> Self {
         Self {
-            ram: [0; 0x800],
-            cartridge: None
+            ram: [0; 0x1000],
+            palette: [0; 0x20],
+            mapper: None
+        }
+    }
+
+    pub fn load_cartridge(&mut self, mapper: SharedMapper) {
+        self.mapper = Some(mapper);
+    }
+
+    /// Reads from anywhere on the PPU's bus, $0000-$3FFF
+    pub fn read(&self, address: u16) -> u8 {
+        let address = address & 0x3fff;
+        match address {
+            0x0000..=0x1fff => self.read_pattern(address),
+            0x2000..=0x3eff => self.ram[self.nametable_address(address)],
+            _ => self.read_palette(address as u8),
+        }
+    }
+
+    /// Writes to anywhere on the PPU's bus, $0000-$3FFF
+    pub fn write(&mut self, address: u16, value: u8) {
+        let address = address & 0x3fff;
+        match address {
+            0x0000..=0x1fff => self.write_pattern(address, value),
+            0x2000..=0x3eff => self.ram[self.nametable_address(address)] = value,
+            // Only 6 bits of each entry exist
+            _ => self.palette[palette_index(address as u8)] = value & 0x3f,
         }
     }
-}
\ No newline at end of file
+
+    /// Reads from the pattern tables, $0000-$1FFF.
+    /// The cartridge sees the address go past on the bus, which some mappers react to
+    pub fn read_pattern(&self, address: u16) -> u8 {
+        match &self.mapper {
+            Some(mapper) => {
+                let mut mapper = mapper.borrow_mut();
+                mapper.ppu_address(address);
+                mapper.read_chr(address)
+            },
+            None => 0,
+        }
+    }
+
+    /// Writes to the pattern tables, $0000-$1FFF
+    pub fn write_pattern(&mut self, address: u16, value: u8) {
+        if let Some(mapper) = &self.mapper {
+            mapper.borrow_mut().write_chr(address, value);
+        }
+    }
+
+    /// Reads one of the 32 palette entries, giving a colour from the 64 colour master palette
+    pub fn read_palette(&self, index: u8) -> u8 {
+        self.palette[palette_index(index)]
+    }
+
+    /// Maps an address in $2000-$3EFF to nametable RAM, following the cartridge's mirroring.
+    /// $3000-$3EFF mirrors $2000-$2EFF
+    fn nametable_address(&self, address: u16) -> usize {
+        let mirroring = match &self.mapper {
+            Some(mapper) => mapper.borrow().mirroring(),
+            None => Mirroring::Horizontal,
+        };
+        let table = (address >> 10) & 0b11;
+        let offset = (address & 0x3ff) as usize;
+        let page = match mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        (page as usize * 0x400) | offset
+    }
+
+    /// Only the console's own memory. The pattern tables are saved with the mapper
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bytes(&self.palette);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.ram)?;
+        state.bytes_into(&mut self.palette)
+    }
+}
+
+/// Palette RAM is 32 bytes, mirrored through $3F00-$3FFF. The first entry of each sprite palette
+/// ($3F10, $3F14, $3F18 and $3F1C) is the same byte as the first entry of the matching background palette
+fn palette_index(address: u8) -> usize {
+    let index = address as usize & 0x1f;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1c => index & 0x0f,
+        _ => index,
+    }
+}
@@ -1,21 +1,43 @@
+mod background;
 mod memory;
 mod register;
+mod sprites;
 
+use crate::mapper::SharedMapper;
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use background::Background;
 use memory::GraphicsMemory;
-use register::RegisterBank;
+use register::{OpenBus, RegisterBank};
+use sprites::{SpritePixel, Sprites};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
 
 pub struct PPU {
     // PPU Registers - MemoryBus accesses these
     // Therefore visible to CPU through certain memory addresses
     registers: RegisterBank,
+    /// What the CPU sees when it reads back a write-only register
+    open_bus: OpenBus,
 
-    framebuffer: [u8; 1000],
+    /// One entry per pixel: a 6 bit colour, with the emphasis bits above it
+    framebuffer: Vec<u16>,
+    /// Number of frames completed since power on
+    frame: u64,
+    background: Background,
+    sprites: Sprites,
 
     oam_address: u8,
     oam_data: [u8; 0x100],
     memory: GraphicsMemory,
 
+    region: Region,
+    /// CPU cycles, counted up to 5 for PAL's extra dot every fifth cycle
     cpu_cycles: u16,
+    /// NTSC skips a dot on odd frames
+    odd_frame: bool,
     scanline: u16,
     cycles: u16,
 }
@@ -24,10 +46,16 @@ impl PPU {
     pub fn init() -> Self {
         Self {
             registers: RegisterBank::init(),
+            open_bus: OpenBus::init(),
 
-            framebuffer: [0; 1000],
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame: 0,
+            background: Background::init(),
+            sprites: Sprites::init(),
 
+            region: Region::Ntsc,
             cpu_cycles: 0,
+            odd_frame: false,
 
             oam_address: 0,
             oam_data: [0xff; 0x100],
@@ -38,39 +66,153 @@ impl PPU {
         }
     }
 
+    pub(crate) fn load_cartridge(&mut self, mapper: SharedMapper) {
+        self.memory.load_cartridge(mapper);
+    }
+
     pub fn read_register(&mut self, address: u8) -> u8 {
+        let frame = self.frame;
         match address {
-            2 => self.registers.read_status(),
-            4 => self.registers.read_sprite_data(),
-            7 => self.registers.read_ppu_data(),
-            _ => 0, // Invalid read | TODO: Find out if this needs to be handled
+            2 => {
+                // Only the top 3 bits of PPUSTATUS exist
+                let status = self.registers.read_status();
+                self.open_bus.drive(status, 0xe0, frame)
+            },
+            4 => {
+                let value = self.read_oam_data();
+                self.open_bus.drive(value, 0xff, frame)
+            },
+            7 => {
+                // Palette entries are only 6 bits, so the top two bits are left as they were
+                let mask = match self.registers.vram_address() & 0x3fff {
+                    0x3f00..=0x3fff => 0x3f,
+                    _ => 0xff,
+                };
+                let value = self.read_ppu_data();
+                self.open_bus.drive(value, mask, frame)
+            },
+            _ => self.open_bus.read(frame), // Write-only
+        }
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// The current dot (PPU cycle) within the scanline
+    pub fn dot(&self) -> u16 {
+        self.cycles
+    }
+
+    /// The most recently drawn picture, a row at a time from the top left. Each pixel is a 6 bit colour,
+    /// then the red, green and blue emphasis bits, which is an index into a `Palette`.
+    /// Rendering a frame finishes at the start of vblank, when `frame` goes up
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        // Don't leave the PPU on a line the new region doesn't have
+        if self.scanline > region.pre_render_scanline() {
+            self.scanline = region.pre_render_scanline();
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        self.open_bus.save_state(state);
+        state.u16s(&self.framebuffer);
+        state.u64(self.frame);
+        self.background.save_state(state);
+        self.sprites.save_state(state);
+        state.u8(self.oam_address);
+        state.bytes(&self.oam_data);
+        self.memory.save_state(state);
+        state.region(self.region);
+        state.u16(self.cpu_cycles);
+        state.bool(self.odd_frame);
+        state.u16(self.scanline);
+        state.u16(self.cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.open_bus.load_state(state)?;
+        let framebuffer = state.u16s()?;
+        if framebuffer.len() != self.framebuffer.len() {
+            return Err(state.invalid());
         }
+        self.framebuffer = framebuffer;
+        self.frame = state.u64()?;
+        self.background.load_state(state)?;
+        self.sprites.load_state(state)?;
+        self.oam_address = state.u8()?;
+        state.bytes_into(&mut self.oam_data)?;
+        self.memory.load_state(state)?;
+        self.region = state.region()?;
+        self.cpu_cycles = state.u16()?;
+        self.odd_frame = state.bool()?;
+        self.scanline = state.u16()?;
+        self.cycles = state.u16()?;
+        Ok(())
     }
 
     pub fn step(&mut self) {
-        let (cpu_cycles, _) = self.cpu_cycles.overflowing_add(1);
-        self.cpu_cycles = cpu_cycles;
+        self.cpu_cycles = (self.cpu_cycles + 1) % 5;
         self.tick(); self.tick(); self.tick();
-        if self.cpu_cycles % 5 == 0 {
+        if self.region == Region::Pal && self.cpu_cycles == 0 {
             self.tick();
         } // PAL timing: 3.2 PPU ticks for every CPU tick
     }
 
+    /// The level of the PPU's /NMI output, which is connected to the CPU's NMI input.
+    /// It is active whenever the vblank flag is set and NMIs are enabled in PPUCTRL, so enabling NMIs
+    /// part way through vblank will cause an NMI straight away
+    pub fn nmi_line(&self) -> bool {
+        self.registers.nmi_output()
+    }
+
     fn tick(&mut self) {
-        match self.scanline {
-            x if x < 240 => {
-                match self.cycles {
-                    0 => (), // Idle
-                    _ => (),
-                }
-            }
-            241 => self.registers.set_vblank(),
+        if self.rendering() {
+            self.render_background();
+            self.render_sprites();
+        }
+        if self.scanline < 240 && (1..=256).contains(&self.cycles) {
+            self.output_pixel();
+        }
+        let pre_render = self.region.pre_render_scanline();
+        match (self.scanline, self.cycles) {
+            (line, 1) if line == self.region.vblank_scanline() => {
+                self.registers.set_vblank();
+                self.frame += 1;
+            },
+            (line, 1) if line == pre_render => {
+                self.registers.clear_vblank();
+                self.registers.clear_sprite_flags();
+            },
             _ => (),
         }
-        if self.cycles == 340 {
+        // On odd frames the NTSC PPU jumps straight from the end of the pre-render line to the start of the
+        // next frame, skipping the idle dot 0 of the first line, as long as it's rendering
+        let skip_dot = self.scanline == pre_render
+            && self.odd_frame
+            && self.registers.rendering_enabled()
+            && self.region.skips_odd_frame_dot();
+        let last_dot = if skip_dot { 339 } else { 340 };
+        if self.cycles >= last_dot {
             self.cycles = 0;
-            if self.scanline == 310 {
+            if self.scanline == pre_render {
                 self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
             } 
             else {
                 self.scanline += 1;
@@ -82,16 +224,167 @@ impl PPU {
 
     }
 
+    /// One dot of the background pipeline, on a visible or pre-render line.
+    /// Each tile takes 8 dots: nametable byte, attribute byte, then the low and high pattern planes,
+    /// after which v moves on to the next tile. Tiles for the line are fetched on dots 1-256, then at
+    /// 321-336 the first two tiles of the next line are fetched, ready for it to start
+    fn render_background(&mut self) {
+        let dot = self.cycles;
+        if let 2..=257 | 322..=337 = dot {
+            self.background.shift();
+        }
+        if let (9..=257 | 329..=337, 1) = (dot, dot % 8) {
+            self.background.reload();
+        }
+        if let 1..=256 | 321..=336 = dot {
+            let pattern_address = self.registers.background_pattern_table()
+                | ((self.background.tile() as u16) << 4)
+                | self.registers.fine_y() as u16;
+            match (dot - 1) % 8 {
+                0 => {
+                    let tile = self.memory.read(0x2000 | (self.registers.vram_address() & 0x0fff));
+                    self.background.set_tile(tile);
+                },
+                2 => {
+                    let attribute = self.memory.read(self.registers.attribute_address());
+                    self.background.set_attribute(attribute >> self.registers.attribute_shift());
+                },
+                4 => {
+                    let pattern = self.memory.read_pattern(pattern_address);
+                    self.background.set_pattern_low(pattern);
+                },
+                6 => {
+                    let pattern = self.memory.read_pattern(pattern_address + 8);
+                    self.background.set_pattern_high(pattern);
+                },
+                7 => self.registers.increment_coarse_x(),
+                _ => (),
+            }
+        }
+        match dot {
+            256 => self.registers.increment_y(),
+            257 => self.registers.copy_horizontal(),
+            280..=304 if self.scanline == self.region.pre_render_scanline() => self.registers.copy_vertical(),
+            _ => (),
+        }
+    }
+
+    /// The sprite pipeline's work on a visible or pre-render line. Evaluation really runs over dots 65-256,
+    /// but its result isn't used until the fetches on dots 257-320, so it's done in one go.
+    /// The pre-render line doesn't evaluate sprites, which is why none are drawn on the first line
+    fn render_sprites(&mut self) {
+        let dot = self.cycles;
+        let height = self.registers.sprite_height();
+        match dot {
+            256 if self.scanline < 240 => {
+                let overflow = self.sprites.evaluate(&self.oam_data, self.scanline, height);
+                if overflow {
+                    self.registers.set_sprite_overflow();
+                }
+            },
+            256 => self.sprites.clear(),
+            257..=320 => {
+                // OAMADDR is used to read OAM while the patterns are fetched, and is left at 0
+                self.oam_address = 0;
+                let high_plane = match dot % 8 {
+                    5 => false,
+                    7 => true,
+                    _ => return,
+                };
+                let index = ((dot - 257) / 8) as usize;
+                let table = self.registers.sprite_pattern_table();
+                let address = self.sprites.pattern_address(index, self.scanline, height, table, high_plane);
+                let pattern = self.memory.read_pattern(address);
+                self.sprites.load_pattern(index, pattern, high_plane);
+            },
+            _ => (),
+        }
+    }
+
+    /// Draws the pixel for the current dot into the framebuffer, working out whether the background
+    /// or a sprite is in front, and checking for sprite 0 hit
+    fn output_pixel(&mut self) {
+        let x = (self.cycles - 1) as usize;
+        let show_background = self.registers.background_enabled() && (x >= 8 || self.registers.background_left_enabled());
+        let background = match show_background {
+            true => self.background.pixel(self.registers.fine_x()),
+            false => 0,
+        };
+        let show_sprites = self.registers.sprites_enabled() && (x >= 8 || self.registers.sprites_left_enabled());
+        let sprite = match show_sprites {
+            true => self.sprites.pixel(x as u8),
+            false => None,
+        };
+
+        // Sprite 0 hit can't happen on the last pixel of a line
+        if matches!(sprite, Some(SpritePixel { sprite_zero: true, .. })) && background != 0 && x != 255 {
+            self.registers.set_sprite_zero_hit();
+        }
+        let colour = match sprite {
+            Some(sprite) if background == 0 || !sprite.behind_background => sprite.colour,
+            _ => background,
+        };
+        let mut colour = self.memory.read_palette(colour);
+        if self.registers.greyscale() {
+            colour &= 0x30;
+        }
+        let emphasis = self.registers.emphasis(self.region) as u16;
+        self.framebuffer[self.scanline as usize * SCREEN_WIDTH + x] = colour as u16 | (emphasis << 6);
+    }
+
+    /// A read from PPUDATA. Reads from VRAM are delayed by one read, going through a buffer, but palette
+    /// reads return straight away. The buffer is still filled, with the nametable byte "underneath" the palette
+    fn read_ppu_data(&mut self) -> u8 {
+        let address = self.registers.vram_address() & 0x3fff;
+        let value = match address {
+            0x3f00..=0x3fff => {
+                let underneath = self.memory.read(address - 0x1000);
+                self.registers.swap_read_buffer(underneath);
+                self.memory.read(address)
+            },
+            _ => {
+                let value = self.memory.read(address);
+                self.registers.swap_read_buffer(value)
+            },
+        };
+        self.increment_after_access();
+        value
+    }
+
+    fn write_ppu_data(&mut self, value: u8) {
+        let address = self.registers.vram_address();
+        self.memory.write(address, value);
+        self.increment_after_access();
+    }
+
+    /// While rendering, the PPU is using v itself, and the increment after a PPUDATA access glitches
+    /// into moving v on by a tile horizontally and a line vertically at the same time
+    fn increment_after_access(&mut self) {
+        match self.rendering() {
+            true => {
+                self.registers.increment_coarse_x();
+                self.registers.increment_y();
+            },
+            false => self.registers.increment_vram_address(),
+        }
+    }
+
+    /// Whether the PPU is using OAM and VRAM for rendering right now, so the CPU can't safely get at them
+    fn rendering(&self) -> bool {
+        (self.scanline < 240 || self.scanline == self.region.pre_render_scanline()) && self.registers.rendering_enabled()
+    }
+
     pub fn write_register(&mut self, address: u8, value: u8) {
+        self.open_bus.drive(value, 0xff, self.frame);
         match address {
             0 => self.registers.write_cr1(value),
             1 => self.registers.write_cr2(value),
             2 => (), // Read-only
-            3 => self.registers.write_sprite_address(value),
-            4 => self.registers.write_sprite_data(value),
+            3 => self.write_oam_address(value),
+            4 => self.write_oam_register(value),
             5 => self.registers.write_ppu_scroll(value),
             6 => self.registers.write_ppu_address(value),
-            7 => self.registers.write_ppu_data(value),
+            7 => self.write_ppu_data(value),
             _ => panic!("Invalid write to PPU register!"),
         }
     }
@@ -105,12 +398,25 @@ impl PPU {
         self.oam_data[self.oam_address as usize] = data;
         self.oam_address = self.oam_address.wrapping_add(1);
     }
-}
 
-struct Tile {
+    /// A write to OAMDATA. While rendering, the write doesn't happen, but OAMADDR is bumped on to the next sprite
+    fn write_oam_register(&mut self, data: u8) {
+        match self.rendering() {
+            true => self.oam_address = self.oam_address.wrapping_add(4),
+            false => self.write_oam_data(data),
+        }
+    }
 
+    fn read_oam_data(&self) -> u8 {
+        // While secondary OAM is being cleared, the PPU reads OAM as $FF
+        if self.rendering() && (1..=64).contains(&self.cycles) {
+            return 0xff;
+        }
+        let value = self.oam_data[self.oam_address as usize];
+        // Bits 2-4 of the attribute byte don't exist, and read back as 0
+        match self.oam_address & 3 {
+            2 => value & 0xe3,
+            _ => value,
+        }
+    }
 }
-
-struct Sprite {
-
-}
\ No newline at end of file
@@ -1,5 +1,11 @@
+// Addresses are grouped by field, yyy NN YYYYY XXXXX, rather than by nibble
+#![allow(clippy::unusual_byte_groupings)]
+
 use bitflags::*;
 
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
 const FINE_Y: u16 = 0b_0111_00_00000_00000;
 const COARSE_X: u16 = 0b_0000_00_00000_11111;
 const COARSE_Y: u16 = 0b_0000_00_11111_00000;
@@ -9,13 +15,16 @@ const COARSE_X_COMPLEMENT: u16 = COARSE_X ^ 0xffff;
 const COARSE_Y_COMPLEMENT: u16 = COARSE_Y ^ 0xffff;
 const NAMETABLE_COMPLEMENT: u16 = NAMETABLE ^ 0xffff;
 
-// The internal registers of the PPU control the operation of scrolling.
-// These registers are 15 bits, but to emulate we're going to store the data
-// in an easier-to-access format, and serialize/deserialize to u16
+/// Roughly how long a bit on the PPU's data bus holds its value after being driven, which has been
+/// measured at around 600ms
+const OPEN_BUS_DECAY_FRAMES: u64 = 36;
+
+/// The PPU's internal registers v and t, as described by loopy. They're 15 bits, laid out as
+/// yyy NN YYYYY XXXXX: fine Y, nametable, coarse Y and coarse X. During rendering, v is the address of
+/// the tile being fetched, and t is where the top left of the screen is, which v is reset from
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 struct InternalRegister {
-    coarse_x: u8,
-    y: u8,
-    nametable: u8,
+    address: u16,
 }
 
 bitflags! {
@@ -34,8 +43,8 @@ bitflags! {
         const BW = 0b00000001;
         const BACKGROUND_CLIPPING = 0b00000010;
         const SPRITE_CLIPPING = 0b000000100;
-        const BACKGROUND_RENDERING = 0b00010000;
-        const SPRITE_RENDERING = 0b00100000;
+        const BACKGROUND_RENDERING = 0b00001000;
+        const SPRITE_RENDERING = 0b00010000;
         const INTENSIFY_RED = 0b00100000;
         const INTENSIFY_GREEN = 0b01000000;
         const INTENSIFY_BLUE = 0b10000000;
@@ -45,8 +54,8 @@ bitflags! {
 
 bitflags! {
     struct StatusRegister: u8 {
-        const SPRITE0 = 0b00100000;
-        const MAX_SPRITES_SCANLINE = 0b01000000;
+        const MAX_SPRITES_SCANLINE = 0b00100000;
+        const SPRITE0 = 0b01000000;
         const VBLANK = 0b10000000;
     }
 }
@@ -55,66 +64,165 @@ impl InternalRegister {
 
     pub fn init() -> Self {
         Self {
-            coarse_x: 0,
-            y: 0,
-            nametable: 0,
+            address: 0,
         }
     }
 
-    pub fn set_low_address(&mut self, value: u8) {
-        self.coarse_x = value & 0b0001_1111;
-        let y = (value & 0b1110_0000) >> 2;
-        self.y &= 0b1100_0111;
-        self.y |= y;
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    pub fn set_address(&mut self, address: u16) {
+        self.address = address & 0x7fff;
+    }
+
+    pub fn coarse_x(&self) -> u8 {
+        (self.address & COARSE_X) as u8
+    }
+
+    pub fn coarse_y(&self) -> u8 {
+        ((self.address & COARSE_Y) >> 5) as u8
+    }
+
+    pub fn fine_y(&self) -> u8 {
+        ((self.address & FINE_Y) >> 12) as u8
+    }
+
+    pub fn nametable(&self) -> u8 {
+        ((self.address & NAMETABLE) >> 10) as u8
+    }
+
+    pub fn set_coarse_x(&mut self, value: u8) {
+        self.address = (self.address & COARSE_X_COMPLEMENT) | (value as u16 & 0x1f);
+    }
+
+    pub fn set_coarse_y(&mut self, value: u8) {
+        self.address = (self.address & COARSE_Y_COMPLEMENT) | ((value as u16 & 0x1f) << 5);
+    }
+
+    pub fn set_fine_y(&mut self, value: u8) {
+        self.address = (self.address & FINE_Y_COMPLEMENT) | ((value as u16 & 0b111) << 12);
+    }
+
+    pub fn set_nametable(&mut self, value: u8) {
+        self.address = (self.address & NAMETABLE_COMPLEMENT) | ((value as u16 & 0b11) << 10);
     }
+
+    /// The first PPUADDR write sets bits 8-13, and clears bit 14
     pub fn set_high_address(&mut self, value: u8) {
-        let coarse_y = value & 0b0011;
-        let fine_y = (value & 0b0111_0000) >> 4;
-        self.y &= 0b0011_1000;
-        self.y |= fine_y | (coarse_y << 6);
+        self.address = (self.address & 0x00ff) | ((value as u16 & 0x3f) << 8);
+    }
+
+    pub fn set_low_address(&mut self, value: u8) {
+        self.address = (self.address & 0xff00) | value as u16;
     }
 
-    pub fn write_x_scroll(&mut self, value: u8) {
-        self.coarse_x = (value & 0b1111_1000) >> 3;
+    /// Copies the horizontal position, coarse X and the horizontal nametable bit
+    pub fn copy_horizontal(&mut self, from: &InternalRegister) {
+        let mask = COARSE_X | 0x0400;
+        self.address = (self.address & !mask) | (from.address & mask);
     }
 
-    pub fn write_y_scroll(&mut self, value: u8) {
-        self.y = value;
+    /// Copies the vertical position, fine and coarse Y and the vertical nametable bit
+    pub fn copy_vertical(&mut self, from: &InternalRegister) {
+        let mask = FINE_Y | COARSE_Y | 0x0800;
+        self.address = (self.address & !mask) | (from.address & mask);
     }
 
+    /// Moves across a tile, wrapping into the next nametable horizontally
     pub fn increment_coarse_x(&mut self) {
-        if self.coarse_x == 31 {
-            self.coarse_x = 0;
-            self.nametable ^= 1;
+        if self.coarse_x() == 31 {
+            self.set_coarse_x(0);
+            self.address ^= 0x0400;
         }
         else {
-            self.coarse_x += 0b01;
+            self.address += 1;
         }
     }
 
+    /// Moves down a line, into the next tile every 8 lines, wrapping into the next nametable vertically
+    /// after the 30th row of tiles
     pub fn increment_y(&mut self) {
-        match self.y {
-            // Coarse y = 29, fine y = 7
-            0b1110_1111 => {
-                self.y = 0;
-                self.nametable ^= 0b10;
+        if self.fine_y() < 7 {
+            self.address += 0x1000;
+            return;
+        }
+        self.set_fine_y(0);
+        match self.coarse_y() {
+            29 => {
+                self.set_coarse_y(0);
+                self.address ^= 0x0800;
             },
-            // 8 bit overflow, nametable doesn't get flipped
-            // Can occur if a value greater than 29 for y is written
-            0b1111_1111 => self.y = 0,
-            _ => self.y += 1,
+            // Rows 30 and 31 are where the attribute table is, and can only be reached by setting the
+            // scroll out of range. The nametable doesn't get flipped when these wrap
+            31 => self.set_coarse_y(0),
+            y => self.set_coarse_y(y + 1),
         }
     }
 
 }
 
+/// The PPU's data bus to the CPU has enough capacitance to hold the last value put on it for a while.
+/// Reading a write-only register, or the unused bits of PPUSTATUS, gives back whatever is left there,
+/// with each bit fading to 0 some time after it was last driven
+pub struct OpenBus {
+    value: u8,
+    /// The frame on which each bit was last driven
+    refreshed: [u64; 8],
+}
+
+impl OpenBus {
+    pub fn init() -> Self {
+        Self {
+            value: 0,
+            refreshed: [0; 8],
+        }
+    }
+
+    /// What's left on the bus at `frame`
+    pub fn read(&mut self, frame: u64) -> u8 {
+        for bit in 0..8 {
+            if frame.saturating_sub(self.refreshed[bit]) >= OPEN_BUS_DECAY_FRAMES {
+                self.value &= !(1 << bit);
+            }
+        }
+        self.value
+    }
+
+    /// Drives the bits in `mask` with `value`, returning what's on the whole bus
+    pub fn drive(&mut self, value: u8, mask: u8, frame: u64) -> u8 {
+        let value = (self.read(frame) & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.refreshed[bit] = frame;
+            }
+        }
+        self.value = value;
+        value
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.value);
+        for frame in &self.refreshed {
+            state.u64(*frame);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.value = state.u8()?;
+        for frame in self.refreshed.iter_mut() {
+            *frame = state.u64()?;
+        }
+        Ok(())
+    }
+}
+
 pub struct RegisterBank {
     cr1: ControlRegister1,
     cr2: ControlRegister2,
     status: StatusRegister,
-    sprite_address: u8,
-    sprite_data: u8,
-    ppu_data: u8,
+    /// PPUDATA reads return what was read last time, rather than what's at the address now
+    read_buffer: u8,
 
     first_write: bool,
     fine_x_scroll: u8,
@@ -128,9 +236,7 @@ impl RegisterBank {
             cr1: ControlRegister1::from_bits_truncate(0),
             cr2: ControlRegister2::from_bits_truncate(0),
             status: StatusRegister::from_bits_truncate(0),
-            sprite_address: 0,
-            sprite_data: 0,
-            ppu_data: 0,
+            read_buffer: 0,
 
             fine_x_scroll: 0,
             first_write: true,
@@ -140,54 +246,299 @@ impl RegisterBank {
     }
 
     pub fn read_status(&mut self) -> u8 {
-        // When this is read, the Vblank bit is set to 0
+        // When this is read, the Vblank bit is set to 0, and the write toggle is reset
         let value = self.status.bits;
         self.first_write = true;
         self.status.remove(StatusRegister::VBLANK);
         value
     }
-    pub fn read_sprite_data(&self) -> u8 {
-        self.sprite_data
-    }
-    pub fn read_ppu_data(&self) -> u8 {
-        self.ppu_data
-    }
 
     pub fn set_vblank(&mut self) {
         self.status.insert(StatusRegister::VBLANK);
     }
+    pub fn clear_vblank(&mut self) {
+        self.status.remove(StatusRegister::VBLANK);
+    }
+    pub fn set_sprite_zero_hit(&mut self) {
+        self.status.insert(StatusRegister::SPRITE0);
+    }
+    pub fn set_sprite_overflow(&mut self) {
+        self.status.insert(StatusRegister::MAX_SPRITES_SCANLINE);
+    }
+    /// Sprite 0 hit and sprite overflow stay set until the end of vblank
+    pub fn clear_sprite_flags(&mut self) {
+        self.status.remove(StatusRegister::SPRITE0 | StatusRegister::MAX_SPRITES_SCANLINE);
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.cr2.intersects(ControlRegister2::BACKGROUND_RENDERING | ControlRegister2::SPRITE_RENDERING)
+    }
+
+    pub fn background_pattern_table(&self) -> u16 {
+        match self.cr1.contains(ControlRegister1::BACKGROUND_PATTERN) {
+            true => 0x1000,
+            false => 0x0000,
+        }
+    }
+
+    /// The pattern table used by 8x8 sprites. 8x16 sprites choose their own table
+    pub fn sprite_pattern_table(&self) -> u16 {
+        match self.cr1.contains(ControlRegister1::SPRITE_PATTERN) {
+            true => 0x1000,
+            false => 0x0000,
+        }
+    }
+
+    pub fn sprite_height(&self) -> u16 {
+        match self.cr1.contains(ControlRegister1::SPRITE_SIZE) {
+            true => 16,
+            false => 8,
+        }
+    }
+
+    pub fn background_enabled(&self) -> bool {
+        self.cr2.contains(ControlRegister2::BACKGROUND_RENDERING)
+    }
+
+    /// Whether colours are forced to the grey column of the palette
+    pub fn greyscale(&self) -> bool {
+        self.cr2.contains(ControlRegister2::BW)
+    }
+
+    /// The colour emphasis bits, in red, green, blue order from bit 0.
+    /// The PAL and Dendy PPUs have the red and green bits the other way round
+    pub fn emphasis(&self, region: Region) -> u8 {
+        let (red, green) = match region {
+            Region::Ntsc => (ControlRegister2::INTENSIFY_RED, ControlRegister2::INTENSIFY_GREEN),
+            Region::Pal | Region::Dendy => (ControlRegister2::INTENSIFY_GREEN, ControlRegister2::INTENSIFY_RED),
+        };
+        (self.cr2.contains(red) as u8)
+            | (self.cr2.contains(green) as u8) << 1
+            | (self.cr2.contains(ControlRegister2::INTENSIFY_BLUE) as u8) << 2
+    }
+
+    pub fn sprites_enabled(&self) -> bool {
+        self.cr2.contains(ControlRegister2::SPRITE_RENDERING)
+    }
+
+    /// Whether sprites are drawn in the leftmost 8 pixels of the screen
+    pub fn sprites_left_enabled(&self) -> bool {
+        self.cr2.contains(ControlRegister2::SPRITE_CLIPPING)
+    }
+
+    /// Whether the background is drawn in the leftmost 8 pixels of the screen
+    pub fn background_left_enabled(&self) -> bool {
+        self.cr2.contains(ControlRegister2::BACKGROUND_CLIPPING)
+    }
+
+    /// The current VRAM address, v
+    pub fn vram_address(&self) -> u16 {
+        self.v.address()
+    }
+
+    /// The address of the attribute byte covering the tile v points at
+    pub fn attribute_address(&self) -> u16 {
+        0x23c0 | ((self.v.nametable() as u16) << 10) | ((self.v.coarse_y() as u16 >> 2) << 3) | (self.v.coarse_x() as u16 >> 2)
+    }
+
+    /// Which quadrant of its attribute byte's 32x32 pixel area the tile v points at is in, as a shift
+    /// to move that quadrant's two bits into the bottom of the byte
+    pub fn attribute_shift(&self) -> u8 {
+        ((self.v.coarse_y() & 0b10) << 1) | (self.v.coarse_x() & 0b10)
+    }
+
+    pub fn fine_y(&self) -> u8 {
+        self.v.fine_y()
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.fine_x_scroll
+    }
+
+    pub fn increment_coarse_x(&mut self) {
+        self.v.increment_coarse_x();
+    }
+
+    pub fn increment_y(&mut self) {
+        self.v.increment_y();
+    }
+
+    /// At the end of each line, the horizontal position goes back to where the scroll says the line starts
+    pub fn copy_horizontal(&mut self) {
+        self.v.copy_horizontal(&self.t);
+    }
+
+    /// During the pre-render line, the vertical position goes back to the top of the scrolled screen
+    pub fn copy_vertical(&mut self) {
+        self.v.copy_vertical(&self.t);
+    }
+
+    pub fn nmi_output(&self) -> bool {
+        self.status.contains(StatusRegister::VBLANK) && self.cr1.contains(ControlRegister1::NMI_INTERRUPTS)
+    }
 
     pub fn write_cr1(&mut self, bits: u8) {
         self.cr1 = ControlRegister1::from_bits_truncate(bits);
+        self.t.set_nametable(bits & ControlRegister1::BASE_TABLE.bits());
     }
     pub fn write_cr2(&mut self, bits: u8) {
         self.cr2 = ControlRegister2::from_bits_truncate(bits);
     }
-    pub fn write_sprite_address(&mut self, bits: u8) {
-        self.sprite_address = bits;
-    }
-    pub fn write_sprite_data(&mut self, bits: u8) {
-        self.sprite_data = bits;
-    }
     pub fn write_ppu_scroll(&mut self, bits: u8) {
         match self.first_write {
             true =>  {
-                self.t.write_x_scroll(bits);
+                self.t.set_coarse_x(bits >> 3);
                 self.fine_x_scroll = bits & 0b0000_0111;
             }
-            false => self.t.write_y_scroll(bits),
+            false => {
+                self.t.set_coarse_y(bits >> 3);
+                self.t.set_fine_y(bits & 0b0000_0111);
+            },
         }
-        self.first_write = self.first_write && false;
+        self.first_write = !self.first_write;
     }
     pub fn write_ppu_address(&mut self, bits: u8) {
         match self.first_write {
             true => self.t.set_high_address(bits),
-            false => self.t.set_low_address(bits),
+            false => {
+                self.t.set_low_address(bits);
+                self.v = self.t;
+            },
         }
-        self.first_write = self.first_write && false;
+        self.first_write = !self.first_write;
+    }
+    /// Swaps a newly read value into the PPUDATA read buffer, returning what was there before
+    pub fn swap_read_buffer(&mut self, value: u8) -> u8 {
+        std::mem::replace(&mut self.read_buffer, value)
     }
-    pub fn write_ppu_data(&mut self, bits: u8) {
-        self.ppu_data = bits;
+
+    /// Moves v on after a PPUDATA access, by 1 (across) or 32 (down) depending on PPUCTRL
+    pub fn increment_vram_address(&mut self) {
+        let increment = match self.cr1.contains(ControlRegister1::VRAM_INC) {
+            true => 32,
+            false => 1,
+        };
+        let address = self.v.address().wrapping_add(increment) & 0x7fff;
+        self.v.set_address(address);
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.cr1.bits);
+        state.u8(self.cr2.bits);
+        state.u8(self.status.bits);
+        state.u8(self.read_buffer);
+        state.bool(self.first_write);
+        state.u8(self.fine_x_scroll);
+        state.u16(self.t.address());
+        state.u16(self.v.address());
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cr1 = ControlRegister1::from_bits_truncate(state.u8()?);
+        self.cr2 = ControlRegister2::from_bits_truncate(state.u8()?);
+        self.status = StatusRegister::from_bits_truncate(state.u8()?);
+        self.read_buffer = state.u8()?;
+        self.first_write = state.bool()?;
+        self.fine_x_scroll = state.u8()?;
+        self.t.set_address(state.u16()?);
+        self.v.set_address(state.u16()?);
+        Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example sequence from the nesdev wiki's PPU scrolling page
+    #[test]
+    fn scroll_and_address_writes_update_t_v_x_w() {
+        let mut registers = RegisterBank::init();
+
+        registers.write_cr1(0x00);
+        assert_eq!(registers.t.address(), 0x0000);
+        registers.read_status();
+        assert!(registers.first_write);
+
+        registers.write_ppu_scroll(0x7d);
+        assert_eq!(registers.t.address(), 0b000_00_00000_01111);
+        assert_eq!(registers.fine_x_scroll, 0b101);
+        assert!(!registers.first_write);
+
+        registers.write_ppu_scroll(0x5e);
+        assert_eq!(registers.t.address(), 0b110_00_01011_01111);
+        assert!(registers.first_write);
+
+        registers.write_ppu_address(0x3d);
+        assert_eq!(registers.t.address(), 0b011_11_01011_01111);
+        assert!(!registers.first_write);
+
+        registers.write_ppu_address(0xf0);
+        assert_eq!(registers.t.address(), 0b011_11_01111_10000);
+        assert_eq!(registers.v.address(), registers.t.address());
+        assert!(registers.first_write);
+    }
+
+    #[test]
+    fn reading_status_resets_write_toggle() {
+        let mut registers = RegisterBank::init();
+        registers.write_ppu_address(0x21);
+        registers.read_status();
+        registers.write_ppu_address(0x23);
+        registers.write_ppu_address(0xc0);
+        assert_eq!(registers.vram_address(), 0x23c0);
+    }
+
+    #[test]
+    fn ppuctrl_sets_nametable_in_t() {
+        let mut registers = RegisterBank::init();
+        registers.write_ppu_scroll(0xff);
+        registers.write_ppu_scroll(0xff);
+        registers.write_cr1(0x02);
+        assert_eq!(registers.t.nametable(), 0b10);
+        assert_eq!(registers.t.coarse_x(), 31);
+        assert_eq!(registers.t.coarse_y(), 31);
+    }
+
+    #[test]
+    fn increments_wrap_into_next_nametable() {
+        let mut register = InternalRegister::init();
+        register.set_coarse_x(31);
+        register.increment_coarse_x();
+        assert_eq!((register.coarse_x(), register.nametable()), (0, 0b01));
+
+        // Fine Y carries into coarse Y, and row 29 wraps into the nametable below
+        register.set_fine_y(7);
+        register.set_coarse_y(29);
+        register.increment_y();
+        assert_eq!((register.fine_y(), register.coarse_y(), register.nametable()), (0, 0, 0b11));
+
+        // Rows 30 and 31 are out of range, and wrap without switching nametable
+        register.set_fine_y(7);
+        register.set_coarse_y(31);
+        register.increment_y();
+        assert_eq!((register.fine_y(), register.coarse_y(), register.nametable()), (0, 0, 0b11));
+    }
+
+    #[test]
+    fn horizontal_and_vertical_copies_take_their_own_bits() {
+        let mut t = InternalRegister::init();
+        t.set_address(0x7fff);
+        let mut v = InternalRegister::init();
+        v.copy_horizontal(&t);
+        assert_eq!(v.address(), 0b000_01_00000_11111);
+        v.copy_vertical(&t);
+        assert_eq!(v.address(), 0x7fff);
+    }
+
+    #[test]
+    fn open_bus_decays() {
+        let mut bus = OpenBus::init();
+        bus.drive(0xff, 0xff, 0);
+        // PPUSTATUS only drives the top 3 bits
+        assert_eq!(bus.drive(0x00, 0xe0, 20), 0x1f);
+        assert_eq!(bus.read(OPEN_BUS_DECAY_FRAMES - 1), 0x1f);
+        assert_eq!(bus.read(OPEN_BUS_DECAY_FRAMES), 0x00);
+    }
+}
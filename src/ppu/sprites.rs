@@ -0,0 +1,196 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The sprite half of the rendering pipeline.
+///
+/// During each line, the PPU searches OAM for the sprites which are on the next line, copying up to 8
+/// of them into secondary OAM. Then on dots 257-320 it fetches their patterns into the 8 output units,
+/// which draw them over the next line as the background is drawn.
+///
+/// OAM decay isn't modelled, on purpose: OAM is DRAM which is only refreshed while rendering, so its contents
+/// fade if rendering stays off for more than a few milliseconds, but what they fade to differs from chip to chip
+/// and with temperature. Games can't rely on it, so OAM just keeps what was written. The clear on dots 1-64 is
+/// modelled, by `clear` and by OAMDATA reading $FF during it
+pub(crate) struct Sprites {
+    /// The sprites found by evaluation, 4 bytes each like OAM. Unused entries are left as $FF
+    secondary: [u8; 32],
+    /// Number of sprites in secondary OAM
+    count: usize,
+    /// Whether the first sprite in secondary OAM is sprite 0
+    zero_in_secondary: bool,
+
+    units: [OutputUnit; 8],
+    /// Whether output unit 0 holds sprite 0, for the line being drawn
+    zero_in_units: bool,
+}
+
+#[derive(Copy, Clone, Default)]
+struct OutputUnit {
+    /// Pattern bits, already reversed if the sprite is flipped horizontally
+    pattern_low: u8,
+    pattern_high: u8,
+    attributes: u8,
+    x: u8,
+}
+
+/// A non-transparent sprite pixel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct SpritePixel {
+    /// Index into the sprite palettes, $11-$1F
+    pub colour: u8,
+    /// Set if the sprite is drawn behind non-transparent background pixels
+    pub behind_background: bool,
+    pub sprite_zero: bool,
+}
+
+impl Sprites {
+    pub fn init() -> Self {
+        Self {
+            secondary: [0xff; 32],
+            count: 0,
+            zero_in_secondary: false,
+
+            units: [OutputUnit::default(); 8],
+            zero_in_units: false,
+        }
+    }
+
+    /// What the PPU puts in secondary OAM during dots 1-64, before evaluation starts
+    pub fn clear(&mut self) {
+        self.secondary = [0xff; 32];
+        self.count = 0;
+        self.zero_in_secondary = false;
+    }
+
+    /// Finds the sprites which are on the line after `scanline`, returning true if there were more than 8.
+    ///
+    /// Once secondary OAM is full, the hardware goes on looking for a 9th sprite to set the overflow flag,
+    /// but it has a bug: each time a sprite isn't in range, it moves on to the next byte within the next
+    /// sprite, as well as to the next sprite. So it compares tile numbers, attributes and X positions as
+    /// though they were Y positions, which gives both false positives and false negatives
+    pub fn evaluate(&mut self, oam: &[u8], scanline: u16, height: u16) -> bool {
+        let in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+        self.clear();
+
+        let mut n = 0;
+        while n < 64 && self.count < 8 {
+            let y = oam[n * 4];
+            // The Y coordinate is always copied, but only counts if the sprite is in range
+            self.secondary[self.count * 4] = y;
+            if in_range(y) {
+                self.secondary[self.count * 4..self.count * 4 + 4].copy_from_slice(&oam[n * 4..n * 4 + 4]);
+                if n == 0 {
+                    self.zero_in_secondary = true;
+                }
+                self.count += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(oam[n * 4 + m]) {
+                return true;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+        false
+    }
+
+    /// The tile number and row within it for output unit `index`, given the line about to be drawn.
+    /// Units without a sprite fetch tile $FF, as the hardware does
+    fn tile_row(&self, index: usize, scanline: u16, height: u16) -> (u8, u16) {
+        let entry = &self.secondary[index * 4..index * 4 + 4];
+        let row = scanline.wrapping_sub(entry[0] as u16) % height;
+        match entry[2] & 0x80 {
+            0 => (entry[1], row),
+            _ => (entry[1], height - 1 - row),
+        }
+    }
+
+    /// The address of one plane of output unit `index`'s pattern. 8x8 sprites use `table`, and 8x16 sprites
+    /// take their table from bit 0 of the tile number, using an even/odd pair of tiles for the top and bottom
+    pub fn pattern_address(&self, index: usize, scanline: u16, height: u16, table: u16, high_plane: bool) -> u16 {
+        let (tile, row) = self.tile_row(index, scanline, height);
+        let plane = if high_plane { 8 } else { 0 };
+        match height {
+            16 => {
+                let table = ((tile & 1) as u16) << 12;
+                let tile = (tile & 0xfe) as u16 + (row >> 3);
+                table | (tile << 4) | (row & 7) | plane
+            },
+            _ => table | ((tile as u16) << 4) | row | plane,
+        }
+    }
+
+    /// Loads a plane of output unit `index`'s pattern, flipping it if needed. Units without a sprite
+    /// are loaded with transparent pixels, whatever was fetched
+    pub fn load_pattern(&mut self, index: usize, value: u8, high_plane: bool) {
+        let attributes = self.secondary[index * 4 + 2];
+        let value = match (index < self.count, attributes & 0x40) {
+            (false, _) => 0,
+            (true, 0) => value,
+            (true, _) => value.reverse_bits(),
+        };
+        let unit = &mut self.units[index];
+        unit.attributes = attributes;
+        unit.x = self.secondary[index * 4 + 3];
+        match high_plane {
+            true => unit.pattern_high = value,
+            false => unit.pattern_low = value,
+        }
+        if index == 0 {
+            self.zero_in_units = self.zero_in_secondary;
+        }
+    }
+
+    /// The sprite pixel at `x` on the line being drawn, from the first output unit with a non-transparent pixel there
+    pub fn pixel(&self, x: u8) -> Option<SpritePixel> {
+        self.units.iter().enumerate().find_map(|(index, unit)| {
+            let column = x.wrapping_sub(unit.x);
+            if column >= 8 {
+                return None;
+            }
+            let bit = 7 - column;
+            let pixel = (((unit.pattern_high >> bit) & 1) << 1) | ((unit.pattern_low >> bit) & 1);
+            match pixel {
+                0 => None,
+                _ => Some(SpritePixel {
+                    colour: 0x10 | ((unit.attributes & 0b11) << 2) | pixel,
+                    behind_background: unit.attributes & 0x20 != 0,
+                    sprite_zero: index == 0 && self.zero_in_units,
+                }),
+            }
+        })
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.secondary);
+        state.u8(self.count as u8);
+        state.bool(self.zero_in_secondary);
+        for unit in &self.units {
+            state.u8(unit.pattern_low);
+            state.u8(unit.pattern_high);
+            state.u8(unit.attributes);
+            state.u8(unit.x);
+        }
+        state.bool(self.zero_in_units);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.secondary)?;
+        self.count = state.u8()? as usize;
+        if self.count > 8 {
+            return Err(state.invalid());
+        }
+        self.zero_in_secondary = state.bool()?;
+        for unit in self.units.iter_mut() {
+            unit.pattern_low = state.u8()?;
+            unit.pattern_high = state.u8()?;
+            unit.attributes = state.u8()?;
+            unit.x = state.u8()?;
+        }
+        self.zero_in_units = state.bool()?;
+        Ok(())
+    }
+}
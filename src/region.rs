@@ -0,0 +1,120 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ines::Timing;
+
+/// The kind of console being emulated, which decides how fast everything runs relative to everything else.
+/// The CPU and PPU clocks are both divided down from one master clock, by different amounts in each region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Region {
+    /// North America and Japan: 60Hz, 262 lines
+    Ntsc,
+    /// Western Europe and Australia: 50Hz, 312 lines, and a slower CPU
+    Pal,
+    /// The Russian Dendy famiclone, which runs PAL's frame rate with NTSC's CPU to PPU ratio
+    Dendy,
+}
+
+impl Region {
+    /// The region a cartridge was made for. Games which work everywhere get NTSC, as the most common
+    pub fn from_timing(timing: Timing) -> Region {
+        match timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    /// CPU clock rate, in Hz
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    /// PPU dots per CPU cycle: 3 for NTSC and Dendy, and 3.2 for PAL
+    pub fn ppu_dots_per_cpu_cycle(&self) -> f64 {
+        match self {
+            Region::Pal => 3.2,
+            _ => 3.0,
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// The line on which the vblank flag is set. Dendy keeps NTSC's vblank length, so has 51 idle lines before it
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// The last line of the frame, which gets the PPU ready to draw the next one
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+
+    /// Whether the pre-render line is a dot shorter on odd frames while rendering is enabled.
+    /// Only the NTSC PPU does this
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    /// The CPU cycles at which each step of the APU frame counter's 4 step sequence happens.
+    /// The last step is also where the sequence starts again
+    pub fn frame_counter_four_step(&self) -> [u32; 4] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829],
+            Region::Pal => [8313, 16627, 24939, 33253],
+        }
+    }
+
+    /// The CPU cycles at which each step of the APU frame counter's 5 step sequence happens.
+    /// The fourth step does nothing, and the last is also where the sequence starts again
+    pub fn frame_counter_five_step(&self) -> [u32; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+        }
+    }
+
+    /// Frames per second, which the frontend paces itself to
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal => 50.0070,
+            Region::Dendy => 50.0,
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region {}, expected one of ntsc, pal or dendy", s)),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Ntsc => write!(f, "NTSC"),
+            Region::Pal => write!(f, "PAL"),
+            Region::Dendy => write!(f, "Dendy"),
+        }
+    }
+}
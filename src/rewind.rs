@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+
+use crate::controller::Buttons;
+use crate::cpu::CPU;
+
+/// A save state, and the input for each frame played after it
+struct Snapshot {
+    /// The newest snapshot is kept whole. Older ones are XORed with the next one, which leaves mostly zeros,
+    /// then compressed
+    data: Vec<u8>,
+    inputs: Vec<[Buttons; 2]>,
+}
+
+/// Lets the game be run backwards a frame at a time, by keeping the recent past.
+///
+/// A save state is taken every `interval` frames, along with both joypads' buttons on every frame, in a ring
+/// buffer which drops the oldest snapshots once it holds more than a set number of frames or bytes.
+/// Stepping back a frame loads the snapshot before it and replays the input up to it. The states for the
+/// whole interval are kept while doing that, so stepping back through the interval only replays it once
+pub struct Rewind {
+    interval: usize,
+    max_frames: usize,
+    max_bytes: usize,
+    snapshots: VecDeque<Snapshot>,
+    /// Total size of the snapshots' data
+    bytes: usize,
+    /// Frames covered by every snapshot apart from the newest
+    frames: usize,
+    /// Forces a snapshot on the next frame
+    discontinuity: bool,
+    /// States after each frame of the newest snapshot's inputs, from replaying them
+    replayed: Vec<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Keeps at most `max_frames` frames, in at most `max_bytes` bytes of snapshots
+    pub fn new(interval: usize, max_frames: usize, max_bytes: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            max_frames,
+            max_bytes,
+            snapshots: VecDeque::new(),
+            bytes: 0,
+            frames: 0,
+            discontinuity: false,
+            replayed: Vec::new(),
+        }
+    }
+
+    /// How many frames can be stepped back
+    pub fn frames(&self) -> usize {
+        self.frames + self.snapshots.back().map_or(0, |snapshot| snapshot.inputs.len())
+    }
+
+    /// How much memory the snapshots are taking up
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.bytes = 0;
+        self.frames = 0;
+        self.replayed.clear();
+    }
+
+    /// Takes a snapshot on the next frame whatever the interval, for when the machine has been changed
+    /// other than by running it, e.g. by a reset or loading a save state, so replaying wouldn't get there
+    pub fn mark_discontinuity(&mut self) {
+        self.discontinuity = true;
+    }
+
+    /// Called before each frame is run, with the buttons it'll be run with
+    pub fn record(&mut self, cpu: &CPU, input: [Buttons; 2]) {
+        // Anything stepped back past is gone now
+        self.replayed.clear();
+        let due = match self.snapshots.back() {
+            Some(snapshot) => snapshot.inputs.len() >= self.interval,
+            None => true,
+        };
+        if due || self.discontinuity {
+            self.push(cpu.save_state());
+            self.discontinuity = false;
+        }
+        if let Some(snapshot) = self.snapshots.back_mut() {
+            snapshot.inputs.push(input);
+        }
+    }
+
+    /// Steps back one frame, loading the state the machine was in before the last frame recorded.
+    /// `run_frame` should set the joypads to the given buttons and run a frame, as the frontend would.
+    /// Returns false if there's nothing further back to go to
+    pub fn step_back<F: FnMut(&mut CPU, [Buttons; 2])>(&mut self, cpu: &mut CPU, mut run_frame: F) -> bool {
+        loop {
+            match self.snapshots.back() {
+                None => return false,
+                // Sitting exactly on the newest snapshot, so the frame before is in the one before it
+                Some(snapshot) if snapshot.inputs.is_empty() => {
+                    if self.snapshots.len() == 1 {
+                        return false;
+                    }
+                    self.pop();
+                },
+                Some(_) => break,
+            }
+        }
+
+        let snapshot = self.snapshots.back_mut().expect("Checked above");
+        snapshot.inputs.pop();
+        if self.replayed.is_empty() {
+            cpu.load_state(&snapshot.data).expect("Couldn't load a rewind snapshot");
+            self.replayed.push(snapshot.data.clone());
+            for input in &snapshot.inputs {
+                run_frame(cpu, *input);
+                self.replayed.push(cpu.save_state());
+            }
+        }
+        let state = self.replayed.pop().expect("Replayed up to the frame being stepped back to");
+        cpu.load_state(&state).expect("Couldn't load a replayed state");
+        true
+    }
+
+    /// Adds a new newest snapshot, turning the one which was newest into a delta against it
+    fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.snapshots.back_mut() {
+            let delta = compress(&xor(&newest.data, &state));
+            self.bytes = self.bytes - newest.data.len() + delta.len();
+            self.frames += newest.inputs.len();
+            newest.data = delta;
+        }
+        self.bytes += state.len();
+        self.snapshots.push_back(Snapshot {
+            data: state,
+            inputs: Vec::new(),
+        });
+
+        while self.snapshots.len() > 1 && (self.bytes > self.max_bytes || self.frames > self.max_frames) {
+            let oldest = self.snapshots.pop_front().expect("There's more than one");
+            self.bytes -= oldest.data.len();
+            self.frames -= oldest.inputs.len();
+        }
+    }
+
+    /// Drops the newest snapshot, making the one before it whole again
+    fn pop(&mut self) {
+        let newest = self.snapshots.pop_back().expect("Only called with a snapshot to pop");
+        self.bytes -= newest.data.len();
+        self.replayed.clear();
+        if let Some(previous) = self.snapshots.back_mut() {
+            let whole = xor(&decompress(&previous.data), &newest.data);
+            self.bytes = self.bytes - previous.data.len() + whole.len();
+            self.frames -= previous.inputs.len();
+            previous.data = whole;
+        }
+    }
+}
+
+/// `data` XORed with `other`, which is treated as padded with zeros or cut short to the same length
+fn xor(data: &[u8], other: &[u8]) -> Vec<u8> {
+    data.iter().enumerate().map(|(i, byte)| byte ^ other.get(i).unwrap_or(&0)).collect()
+}
+
+/// Runs of zeros shorter than this are left in the literal bytes around them
+const MIN_ZERO_RUN: usize = 4;
+
+/// Run length encodes the zeros: each chunk is the number of zeros, the number of literal bytes, then the
+/// literal bytes, with both numbers as LEB128
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|byte| **byte == 0).count();
+        let start = i + zeros;
+        let mut end = start;
+        while end < data.len() {
+            let run = data[end..].iter().take(MIN_ZERO_RUN).take_while(|byte| **byte == 0).count();
+            if run == MIN_ZERO_RUN || end + run == data.len() {
+                break;
+            }
+            end += run.max(1);
+        }
+        write_length(&mut output, zeros);
+        write_length(&mut output, end - start);
+        output.extend(&data[start..end]);
+        i = end;
+    }
+    output
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = read_length(data, &mut i);
+        let literals = read_length(data, &mut i);
+        output.resize(output.len() + zeros, 0);
+        output.extend(&data[i..i + literals]);
+        i += literals;
+    }
+    output
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        output.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    output.push(length as u8);
+}
+
+fn read_length(data: &[u8], i: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        length |= (byte as usize & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_round_trips() {
+        let mut data = vec![0; 1000];
+        data[10] = 1;
+        data[11..13].copy_from_slice(&[0, 2]);
+        data[500..700].iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+        data.push(3);
+        for data in [data, vec![], vec![0; 5], vec![7; 300], vec![1, 0, 0, 0]] {
+            assert_eq!(decompress(&compress(&data)), data);
+        }
+    }
+}
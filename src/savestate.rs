@@ -0,0 +1,252 @@
+use std::fmt;
+
+use crate::region::Region;
+
+/// Start of every save state
+const MAGIC: &[u8; 4] = b"NKST";
+
+/// Bumped whenever the contents of a section change. Adding whole new sections doesn't need a new version,
+/// as readers skip the sections they don't know
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// Not a save state at all
+    BadMagic,
+    /// Saved by a newer version of the format than this one understands
+    UnsupportedVersion(u16),
+    /// A section which has to be there isn't
+    MissingSection(String),
+    /// A section ended part way through a field
+    Truncated(String),
+    /// A section's contents don't make sense, e.g. memory of the wrong size
+    Invalid(String),
+    /// Saved while running a different game
+    WrongCartridge,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Save state is version {}, but only up to {} is supported", version, STATE_VERSION)
+            },
+            StateError::MissingSection(tag) => write!(f, "Save state has no {} section", tag),
+            StateError::Truncated(tag) => write!(f, "Save state's {} section is cut short", tag),
+            StateError::Invalid(tag) => write!(f, "Save state's {} section is invalid", tag),
+            StateError::WrongCartridge => write!(f, "Save state is for a different game"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Builds a save state: the header, then a series of tagged sections, each one prefixed with its length
+/// so that readers can skip the ones they don't know.
+/// All values are little endian
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.extend(&STATE_VERSION.to_le_bytes());
+        Self {
+            data,
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Writes a section, with whatever `contents` writes in it
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], contents: F) {
+        self.data.extend(tag);
+        let length_at = self.data.len();
+        self.data.extend(&[0; 4]);
+        contents(self);
+        let length = (self.data.len() - length_at - 4) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    /// A block of memory, prefixed with its length
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend(value);
+    }
+
+    pub fn u16s(&mut self, value: &[u16]) {
+        self.u32(value.len() as u32);
+        for v in value {
+            self.u16(*v);
+        }
+    }
+
+    pub fn region(&mut self, value: Region) {
+        self.u8(match value {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::Dendy => 2,
+        });
+    }
+
+    pub fn f32s(&mut self, value: &[f32]) {
+        self.u32(value.len() as u32);
+        for v in value {
+            self.f32(*v);
+        }
+    }
+}
+
+/// Reads a save state back, one section at a time
+pub(crate) struct StateReader<'a> {
+    tag: String,
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, returning a reader over all of the sections
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        if data.len() < 6 || &data[..4] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            tag: "header".to_string(),
+            data: &data[6..],
+        })
+    }
+
+    /// Finds a section by its tag, returning a reader over just its contents
+    pub fn section(&self, tag: &[u8; 4]) -> Result<StateReader<'a>, StateError> {
+        let name = String::from_utf8_lossy(tag).trim().to_string();
+        let mut rest = self.data;
+        while rest.len() >= 8 {
+            let length = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            let contents = rest.get(8..8 + length).ok_or_else(|| StateError::Truncated(name.clone()))?;
+            if &rest[..4] == tag {
+                return Ok(StateReader {
+                    tag: name,
+                    data: contents,
+                });
+            }
+            rest = &rest[8 + length..];
+        }
+        Err(StateError::MissingSection(name))
+    }
+
+    /// An error about this section's contents
+    pub fn invalid(&self) -> StateError {
+        StateError::Invalid(self.tag.clone())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < length {
+            return Err(StateError::Truncated(self.tag.clone()));
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    /// Reads a block of memory into `into`, which has to be exactly the same size
+    pub fn bytes_into(&mut self, into: &mut [u8]) -> Result<(), StateError> {
+        let length = self.u32()? as usize;
+        if length != into.len() {
+            return Err(self.invalid());
+        }
+        into.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    pub fn u16s(&mut self) -> Result<Vec<u16>, StateError> {
+        let length = self.u32()? as usize;
+        (0..length).map(|_| self.u16()).collect()
+    }
+
+    pub fn region(&mut self) -> Result<Region, StateError> {
+        match self.u8()? {
+            0 => Ok(Region::Ntsc),
+            1 => Ok(Region::Pal),
+            2 => Ok(Region::Dendy),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    pub fn f32s(&mut self) -> Result<Vec<f32>, StateError> {
+        let length = self.u32()? as usize;
+        (0..length).map(|_| self.f32()).collect()
+    }
+}
+
+/// A quick fingerprint of a ROM, so states can't be loaded into the wrong game (FNV-1a)
+pub(crate) fn fingerprint(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use neks::cpu::CPU;
+
+/// Stores $4015 at $10 and jams
+const STORE_STATUS: &[u8] = &[
+    0xad, 0x15, 0x40,       // LDA $4015
+    0x85, 0x10,             // STA $10
+    0x02,                   // JAM
+];
+
+#[test]
+fn frame_counter_raises_irq_at_end_of_four_step_sequence() {
+    let program: &[u8] = &[
+        0xa9, 0x00,             // LDA #$00
+        0x8d, 0x17, 0x40,       // STA $4017: 4 step sequence, IRQ allowed
+        0x58,                   // CLI
+        0x4c, 0x06, 0x80,       // JMP $8006
+    ];
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, program), (0x9000, STORE_STATUS)], 0x9000, 0x8000, 0x9000));
+
+    common::run_until_jammed(&mut cpu, 100_000);
+    // Reading $4015 returns the flag, then acknowledges it
+    assert_eq!(cpu.peek(0x0010) & 0x40, 0x40);
+    assert!(!cpu.apu().frame_irq());
+    // The sequence restarted a few cycles after the write, and the handler only took a few cycles more
+    assert!((29_830..29_870).contains(&cpu.cycles()), "IRQ taken at cycle {}", cpu.cycles());
+}
+
+#[test]
+fn five_step_sequence_never_raises_irq() {
+    let program: &[u8] = &[
+        0xa9, 0x80,             // LDA #$80
+        0x8d, 0x17, 0x40,       // STA $4017: 5 step sequence
+        0x58,                   // CLI
+        0x4c, 0x06, 0x80,       // JMP $8006
+    ];
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, program), (0x9000, &[0x02])], 0x9000, 0x8000, 0x9000));
+
+    // Two whole 4 step sequences
+    while cpu.cycles() < 60_000 {
+        cpu.step();
+    }
+    assert!(!cpu.is_jammed());
+    assert!(!cpu.apu().frame_irq());
+}
+
+#[test]
+fn status_reports_length_counters() {
+    let program: &[u8] = &[
+        0xa9, 0x03,             // LDA #$03
+        0x8d, 0x15, 0x40,       // STA $4015: enable both pulse channels
+        0xa9, 0x08,             // LDA #$08
+        0x8d, 0x03, 0x40,       // STA $4003: pulse 1 gets a length of 254
+        0x8d, 0x0b, 0x40,       // STA $400B: but the triangle is disabled, so ignores it
+        0xad, 0x15, 0x40,       // LDA $4015
+        0x85, 0x11,             // STA $11
+        0xa9, 0x02,             // LDA #$02
+        0x8d, 0x15, 0x40,       // STA $4015: disabling pulse 1 clears its length counter
+        0x4c, 0x00, 0x90,       // JMP $9000
+    ];
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, program), (0x9000, STORE_STATUS)], 0x8000, 0x8000, 0x8000));
+
+    common::run_until_jammed(&mut cpu, 1000);
+    assert_eq!(cpu.peek(0x0011) & 0x1f, 0x01);
+    assert_eq!(cpu.peek(0x0010) & 0x1f, 0x00);
+}
+
+#[test]
+fn dmc_fetches_sample_and_raises_irq() {
+    let program: &[u8] = &[
+        0xa9, 0x8f,             // LDA #$8F
+        0x8d, 0x10, 0x40,       // STA $4010: IRQ enabled, fastest rate
+        0xa9, 0x00,             // LDA #$00
+        0x8d, 0x12, 0x40,       // STA $4012: sample at $C000
+        0x8d, 0x13, 0x40,       // STA $4013: 1 byte long
+        0xa9, 0x10,             // LDA #$10
+        0x8d, 0x15, 0x40,       // STA $4015: start the sample
+        0x58,                   // CLI
+        0x4c, 0x13, 0x80,       // JMP $8013
+    ];
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, program), (0x9000, STORE_STATUS)], 0x9000, 0x8000, 0x9000));
+
+    common::run_until_jammed(&mut cpu, 1000);
+    // The whole sample has been fetched, and reading $4015 doesn't acknowledge the DMC's IRQ
+    assert_eq!(cpu.peek(0x0010) & 0x90, 0x80);
+    assert!(cpu.apu().dmc_irq());
+}
+
+#[test]
+fn pulse_is_resampled_to_output_rate() {
+    let program: &[u8] = &[
+        0xa9, 0x01,             // LDA #$01
+        0x8d, 0x15, 0x40,       // STA $4015: enable pulse 1
+        0xa9, 0xbf,             // LDA #$BF
+        0x8d, 0x00, 0x40,       // STA $4000: 50% duty, constant volume 15, no length counter
+        0xa9, 0xfd,             // LDA #$FD
+        0x8d, 0x02, 0x40,       // STA $4002: period $FD, about 440Hz
+        0xa9, 0x00,             // LDA #$00
+        0x8d, 0x03, 0x40,       // STA $4003
+        0x4c, 0x14, 0x80,       // JMP $8014
+    ];
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, program)], 0x8000, 0x8000, 0x8000));
+    cpu.apu_mut().set_sample_rate(44_100);
+
+    // A tenth of a second
+    while cpu.cycles() < 178_977 {
+        cpu.step();
+    }
+    let mut samples = Vec::new();
+    cpu.apu_mut().read_samples(&mut samples);
+    // All but the few which are still waiting on later steps
+    assert!((4380..=4410).contains(&samples.len()), "{} samples", samples.len());
+    // The filters take out the DC offset, so the square wave swings either side of 0
+    let last_cycles = &samples[2205..];
+    let max = last_cycles.iter().cloned().fold(f32::MIN, f32::max);
+    let min = last_cycles.iter().cloned().fold(f32::MAX, f32::min);
+    assert!(max > 0.05 && min < -0.05, "Range {} to {}", min, max);
+    assert!(max < 1.0 && min > -1.0);
+
+    // Nothing is left once they've been read
+    samples.clear();
+    cpu.apu_mut().read_samples(&mut samples);
+    assert!(samples.len() < 16);
+}
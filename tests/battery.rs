@@ -0,0 +1,120 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use neks::battery::{BatteryError, SaveFile};
+use neks::console::Console;
+use neks::ines::{Cartridge, CartridgeError, RomFileParser};
+
+/// Writes $42 to $6000 and $99 to $7FFF, then jams
+const PROGRAM: &[u8] = &[
+    0xa9, 0x42,             // LDA #$42
+    0x8d, 0x00, 0x60,       // STA $6000
+    0xa9, 0x99,             // LDA #$99
+    0x8d, 0xff, 0x7f,       // STA $7FFF
+    0x02,                   // JAM
+];
+
+/// An MMC1 image with the program in the fixed bank at $C000, and a battery unless `flags_6` says otherwise
+fn image(flags_6: u8) -> Vec<u8> {
+    let mut prg = vec![0xea; 0x8000];
+    prg[0x4000..0x4000 + PROGRAM.len()].copy_from_slice(PROGRAM);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0xc000_u16.to_le_bytes());
+    common::image(1, flags_6, &prg, &[0; 0x2000])
+}
+
+fn cartridge() -> Cartridge {
+    common::parse(image(0x02))
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("neks-battery-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory.join(name)
+}
+
+#[test]
+fn battery_ram_survives_restart() {
+    let path = temp_path("game.sav");
+    let mut console = Console::new(cartridge());
+    let mut save_file = SaveFile::open(console.cpu_mut(), &path).unwrap().unwrap();
+    assert_eq!(console.cpu().battery_ram_size(), 0x2000);
+
+    // Nothing's written until the game changes something
+    assert!(!save_file.flush(console.cpu()).unwrap());
+    assert!(!path.exists());
+    assert!(console.run_until(10, |cpu| cpu.is_jammed()));
+    assert!(save_file.flush(console.cpu()).unwrap());
+    assert!(!save_file.flush(console.cpu()).unwrap());
+
+    let saved = fs::read(&path).unwrap();
+    assert_eq!(saved.len(), 0x2000);
+    assert_eq!((saved[0], saved[0x1fff]), (0x42, 0x99));
+
+    // Before the program has had a chance to run
+    let mut restarted = Console::new(cartridge());
+    SaveFile::open(restarted.cpu_mut(), &path).unwrap().unwrap();
+    assert_eq!(restarted.cpu().peek(0x6000), 0x42);
+    assert_eq!(restarted.cpu().peek(0x7fff), 0x99);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn wrong_size_saves_are_rejected() {
+    let path = temp_path("short.sav");
+    fs::write(&path, [0xff; 100]).unwrap();
+    let mut console = Console::new(cartridge());
+    match SaveFile::open(console.cpu_mut(), &path) {
+        Err(BatteryError::WrongSize { expected, found }) => assert_eq!((expected, found), (0x2000, 100)),
+        other => panic!("Expected a size error, not {:?}", other.map(|save_file| save_file.is_some())),
+    }
+    assert_eq!(console.cpu().peek(0x6000), 0x00);
+    // Left alone, so it isn't lost
+    assert_eq!(fs::read(&path).unwrap().len(), 100);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn only_battery_backed_ram_is_saved() {
+    // No battery at all
+    let mut console = Console::new(common::parse(image(0x00)));
+    assert_eq!(console.cpu().battery_ram_size(), 0);
+    assert!(SaveFile::open(console.cpu_mut(), temp_path("none.sav")).unwrap().is_none());
+
+    // NES 2.0, with 8KB of volatile PRG-RAM and 8KB battery backed. $6000 is in the first, volatile, bank
+    let mut image = image(0x02);
+    image[7] |= 0x08;
+    image[10] = 0x77;
+    let mut console = Console::new(common::parse(image));
+    assert_eq!(console.cpu().battery_ram_size(), 0x2000);
+    assert!(console.run_until(10, |cpu| cpu.is_jammed()));
+    assert_eq!(console.cpu().peek(0x6000), 0x42);
+    assert!(console.cpu().battery_ram().iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn nes20_battery_and_nvram_have_to_agree() {
+    let nes20 = |flags_6: u8, ram_sizes: u8| {
+        let mut image = image(flags_6);
+        image[7] |= 0x08;
+        image[10] = ram_sizes;
+        RomFileParser::from_bytes(image).parse().err()
+    };
+    // A battery with nothing to keep, and RAM which would be kept without a battery
+    assert_eq!(nes20(0x02, 0x07), Some(CartridgeError::BatteryMismatch { battery: true, nvram_size: 0 }));
+    assert_eq!(nes20(0x00, 0x70), Some(CartridgeError::BatteryMismatch { battery: false, nvram_size: 0x2000 }));
+    assert_eq!(nes20(0x02, 0x70), None);
+    assert_eq!(nes20(0x00, 0x07), None);
+}
+
+#[test]
+fn prg_ram_is_capped_at_what_the_board_can_reach() {
+    // NES 2.0 asks for 1MB of battery backed RAM, but the MMC1 can only reach 32KB, and NROM 8KB
+    let mut image = image(0x02);
+    image[7] |= 0x08;
+    image[10] = 0xe0;
+    assert_eq!(Console::new(common::parse(image.clone())).cpu().battery_ram_size(), 0x8000);
+    image[6] &= 0x0f;
+    assert_eq!(Console::new(common::parse(image)).cpu().battery_ram_size(), 0x2000);
+}
@@ -0,0 +1,290 @@
+mod common;
+
+use neks::cpu::{Registers, CPU};
+use neks::ines::{CartridgeError, ConsoleType, Format, RomFileParser, Timing};
+
+fn registers(pc: u16) -> Registers {
+    Registers { pc, a: 0, x: 0, y: 0, s: 0xfd, p: 0x24 }
+}
+
+#[test]
+fn nrom_128_is_mirrored_into_upper_bank() {
+    let mut prg = vec![0; 0x4000];
+    prg[0x0000] = 0x42;
+    prg[0x3ffc..0x3ffe].copy_from_slice(&0xc000_u16.to_le_bytes());
+    let cpu = CPU::init(common::parse(common::image(0, 0, &prg, &[0; 0x2000])));
+
+    assert_eq!(cpu.peek(0x8000), 0x42);
+    assert_eq!(cpu.peek(0xc000), 0x42);
+    assert_eq!(cpu.registers().pc, 0xc000);
+}
+
+#[test]
+fn nrom_without_prg_rom_is_an_error() {
+    let result = RomFileParser::from_bytes(common::image(0, 0, &[], &[0; 0x2000])).parse();
+    assert_eq!(result.err(), Some(CartridgeError::BadPrgSize { mapper: 0, size: 0 }));
+}
+
+#[test]
+fn rom_ignores_writes() {
+    let program: &[u8] = &[
+        0xa9, 0x55,             // LDA #$55
+        0x8d, 0x00, 0x80,       // STA $8000
+    ];
+    let cartridge = common::nrom(&[(0x8000, program)], 0x8000, 0x8000, 0x8000);
+    let mut cpu = CPU::init_with_registers(cartridge, registers(0x8000));
+    cpu.step();
+    cpu.step();
+
+    assert_eq!(cpu.peek(0x8000), 0xa9);
+}
+
+/// Writes `value` to an MMC1 register one bit at a time, as games do
+fn mmc1_write(address: u16) -> Vec<u8> {
+    let [low, high] = address.to_le_bytes();
+    let mut program = Vec::new();
+    for _ in 0..5 {
+        program.extend(&[0x8d, low, high]); // STA address
+        program.push(0x4a);                 // LSR A
+    }
+    program
+}
+
+#[test]
+fn mmc1_switches_prg_bank_through_serial_port() {
+    // 128KB of PRG, each 16KB bank starting with its own number
+    let mut prg = vec![0; 0x20000];
+    for bank in 0..8 {
+        prg[bank * 0x4000] = bank as u8;
+    }
+    let mut program = vec![0xa9, 0x03]; // LDA #$03
+    program.extend(mmc1_write(0xe000));
+    let end = 0xc000 + program.len() as u16;
+    program.extend(&[0x4c, (end & 0xff) as u8, (end >> 8) as u8]); // JMP to self
+    // The last bank is fixed at $C000 on power up
+    let last = 7 * 0x4000;
+    prg[last..last + program.len()].copy_from_slice(&program);
+    prg[last + 0x3ffc..last + 0x3ffe].copy_from_slice(&0xc000_u16.to_le_bytes());
+
+    let mut cpu = CPU::init(common::parse(common::image(1, 0, &prg, &[])));
+    assert_eq!(cpu.peek(0x8000), 0);
+    while cpu.registers().pc != end {
+        cpu.step();
+    }
+    assert_eq!(cpu.peek(0x8000), 3);
+    assert_eq!(cpu.peek(0xc000), 0xa9);
+}
+
+#[test]
+fn mmc1_fixes_the_last_bank_of_any_size_of_prg_rom() {
+    // 48KB, which isn't a power of two, so the last bank isn't found by wrapping bank 15 around
+    let mut prg = vec![0; 0xc000];
+    for bank in 0..3 {
+        prg[bank * 0x4000] = bank as u8;
+    }
+    prg[0xbffc..0xbffe].copy_from_slice(&0xc123_u16.to_le_bytes());
+    let cpu = CPU::init(common::parse(common::image(1, 0, &prg, &[])));
+
+    assert_eq!(cpu.peek(0xc000), 2);
+    assert_eq!(cpu.registers().pc, 0xc123);
+}
+
+#[test]
+fn sxrom_switches_between_four_prg_ram_banks() {
+    // CHR bank 0 bits 2 and 3 pick the bank: write a different byte to the start of each, then read them back
+    let mut program = Vec::new();
+    for bank in 0..4_u8 {
+        program.extend(&[0xa9, bank << 2]);                 // LDA #bank << 2
+        program.extend(mmc1_write(0xa000));
+        program.extend(&[0xa9, 0x41 + bank]);               // LDA #$41 + bank
+        program.extend(&[0x8d, 0x00, 0x60]);                // STA $6000
+    }
+    for bank in 0..4_u8 {
+        program.extend(&[0xa9, bank << 2]);                 // LDA #bank << 2
+        program.extend(mmc1_write(0xa000));
+        program.extend(&[0xad, 0x00, 0x60]);                // LDA $6000
+        program.extend(&[0x85, 0x10 + bank]);               // STA $10 + bank
+    }
+    program.push(0x02);                                     // JAM
+    let mut prg = vec![0; 0x8000];
+    prg[0x4000..0x4000 + program.len()].copy_from_slice(&program);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0xc000_u16.to_le_bytes());
+    // 32KB of battery backed PRG-RAM, and CHR-RAM
+    let mut image = common::image(1, 0x02, &prg, &[]);
+    image[8] = 4;
+    let mut cpu = CPU::init(common::parse(image));
+    common::run_until_jammed(&mut cpu, 1000);
+
+    let read: Vec<u8> = (0x10..0x14).map(|address| cpu.peek(address)).collect();
+    assert_eq!(read, [0x41, 0x42, 0x43, 0x44]);
+    // Each bank is where a save file expects it
+    let ram = cpu.battery_ram();
+    assert_eq!(ram.len(), 0x8000);
+    let banks: Vec<u8> = (0..4).map(|bank| ram[bank * 0x2000]).collect();
+    assert_eq!(banks, [0x41, 0x42, 0x43, 0x44]);
+}
+
+#[test]
+fn mmc3_counts_scanlines_and_raises_irq() {
+    let program: &[u8] = &[
+        0xa9, 0x08,             // LDA #$08: sprites from $1000, background from $0000
+        0x8d, 0x00, 0x20,       // STA $2000
+        0xa9, 0x18,             // LDA #$18: show background and sprites
+        0x8d, 0x01, 0x20,       // STA $2001
+        0xa9, 0x02,             // LDA #$02
+        0x8d, 0x00, 0xc0,       // STA $C000: IRQ latch
+        0x8d, 0x01, 0xc0,       // STA $C001: reload
+        0x8d, 0x01, 0xe0,       // STA $E001: enable
+        0x58,                   // CLI
+        0x4c, 0x16, 0xe0,       // JMP $E016
+    ];
+    let handler: &[u8] = &[
+        0x8d, 0x00, 0xe0,       // STA $E000: acknowledge
+        0xa9, 0x42,             // LDA #$42
+        0x85, 0x10,             // STA $10
+        0x02,                   // JAM
+    ];
+    // 32KB of PRG, with the last 8KB bank fixed at $E000
+    let mut prg = vec![0xea; 0x8000];
+    prg[0x6000..0x6000 + program.len()].copy_from_slice(program);
+    prg[0x6100..0x6100 + handler.len()].copy_from_slice(handler);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0xe000_u16.to_le_bytes());
+    prg[0x7ffe..0x8000].copy_from_slice(&0xe100_u16.to_le_bytes());
+    let mut cpu = CPU::init(common::parse(common::image(4, 0, &prg, &[0; 0x2000])));
+
+    // The counter is reloaded on the first scanline and then counts down, so the IRQ comes on the third
+    let mut steps = 0;
+    while !cpu.is_jammed() {
+        cpu.step();
+        steps += 1;
+        assert!(steps < 2000, "IRQ never arrived");
+    }
+    assert_eq!(cpu.peek(0x0010), 0x42);
+}
+
+#[test]
+fn unmapped_cartridge_space_is_open_bus() {
+    let program: &[u8] = &[
+        0xa9, 0x00,             // LDA #$00
+        0x8d, 0x01, 0xa0,       // STA $A001: disable PRG-RAM
+        0xad, 0x00, 0x60,       // LDA $6000
+        0x85, 0x10,             // STA $10
+        0xad, 0x34, 0x52,       // LDA $5234
+        0x85, 0x11,             // STA $11
+        0x02,                   // JAM
+    ];
+    let mut prg = vec![0xea; 0x8000];
+    prg[0x6000..0x6000 + program.len()].copy_from_slice(program);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0xe000_u16.to_le_bytes());
+    let mut cpu = CPU::init(common::parse(common::image(4, 0, &prg, &[0; 0x2000])));
+    common::run_until_jammed(&mut cpu, 1000);
+
+    // Each read leaves the high byte of its address on the bus
+    assert_eq!((cpu.peek(0x0010), cpu.peek(0x0011)), (0x60, 0x52));
+}
+
+#[test]
+fn nes20_header_is_decoded() {
+    let mut image = b"NES\x1a".to_vec();
+    image.extend(&[
+        0x02,                   // 2 x 16KB PRG-ROM
+        0x07,                   // Exponent-multiplier CHR-ROM: 2^1 * 7 = 14 bytes
+        0x42,                   // Mapper 4, horizontal mirroring, battery
+        0x09,                   // NES 2.0, Vs. System
+        0x40,                   // Mapper bits 8-11 = 0, submapper 4
+        0xf0,                   // CHR-ROM size is exponent-multiplier
+        0x97,                   // 8KB PRG-RAM, 32KB PRG-NVRAM
+        0x07,                   // 8KB CHR-RAM
+        0x03,                   // Dendy
+        0x21,                   // Vs. PPU 1, hardware 2
+        0x01,                   // One miscellaneous ROM
+        0x2a,                   // Expansion device
+    ]);
+    image.extend(vec![0; 0x8000 + 14]);
+    let header = common::parse(image).header;
+
+    assert_eq!(header.format, Format::Nes20);
+    assert_eq!(header.mapper, 4);
+    assert_eq!(header.submapper, 4);
+    assert_eq!(header.prg_rom_size, 0x8000);
+    assert_eq!(header.chr_rom_size, 14);
+    assert_eq!(header.prg_ram_size, 0x2000);
+    assert_eq!(header.prg_nvram_size, 0x8000);
+    assert_eq!(header.chr_ram_size, 0x2000);
+    assert_eq!(header.chr_nvram_size, 0);
+    assert_eq!(header.timing, Timing::Dendy);
+    assert_eq!(header.console_type, ConsoleType::VsSystem { ppu: 1, hardware: 2 });
+    assert_eq!(header.misc_roms, 1);
+    assert_eq!(header.expansion_device, 0x2a);
+}
+
+#[test]
+fn bad_images_are_reported_with_offsets() {
+    let parse = |image: Vec<u8>| RomFileParser::from_bytes(image).parse().err();
+    let prg = [0; 0x4000];
+    let chr = [0; 0x2000];
+
+    assert_eq!(parse(b"PK\x03\x04".to_vec()), Some(CartridgeError::BadMagic));
+    assert_eq!(parse(b"NES\x1a\x01".to_vec()), Some(CartridgeError::TruncatedHeader { length: 5 }));
+
+    let mut image = common::image(0, 0, &prg, &chr);
+    image.truncate(0x10 + 0x4000 + 0x100);
+    assert_eq!(parse(image), Some(CartridgeError::TruncatedChr { offset: 0x4010, expected: 0x2000, found: 0x100 }));
+
+    let mut image = common::image(0, 0, &prg, &chr);
+    image.truncate(0x10 + 0x100);
+    assert_eq!(parse(image), Some(CartridgeError::TruncatedPrg { offset: 0x10, expected: 0x4000, found: 0x100 }));
+
+    let mut image = common::image(0, 0x04, &prg, &chr);
+    image.truncate(0x10 + 0x100);
+    assert_eq!(parse(image), Some(CartridgeError::TrainerMissing { offset: 0x10, length: 0x110 }));
+
+    let mut image = common::image(0, 0, &prg, &chr);
+    image.extend(&[0; 0x80]);
+    assert_eq!(parse(image), Some(CartridgeError::OversizeImage { offset: 0x6010, extra: 0x80 }));
+
+    let mut image = common::image(0, 0, &prg, &chr);
+    image[7..16].copy_from_slice(b"DiskDude!");
+    assert_eq!(parse(image), Some(CartridgeError::HeaderGarbage { offset: 12 }));
+
+    // NES 2.0, mapper $104 submapper 2
+    let mut image = common::image(4, 0, &prg, &chr);
+    image[7] = 0x08;
+    image[8] = 0x21;
+    assert_eq!(parse(image), Some(CartridgeError::UnsupportedMapper { mapper: 0x104, submapper: 2 }));
+
+    // 2^63 * 3 doesn't even fit in a usize, and 2^40 is far bigger than any cartridge
+    let mut image = common::image(0, 0, &prg, &chr);
+    image[4] = 63 << 2 | 1;
+    image[7] |= 0x08;
+    image[9] = 0x0f;
+    assert_eq!(parse(image.clone()), Some(CartridgeError::OversizeRom { offset: 4 }));
+    image[4] = 1;
+    image[5] = 40 << 2;
+    image[9] = 0xf0;
+    assert_eq!(parse(image), Some(CartridgeError::OversizeRom { offset: 5 }));
+}
+
+/// An NES 2.0 image whose PRG-ROM size is in exponent-multiplier form, 2^E * (MM * 2 + 1) bytes
+fn nes20_image(mapper: u8, exponent: u8, multiplier: u8) -> Vec<u8> {
+    let size = (multiplier as usize * 2 + 1) << exponent;
+    let mut image = common::image(mapper, 0, &vec![0; size], &[0; 0x2000]);
+    image[4] = exponent << 2 | multiplier;
+    image[7] |= 0x08;
+    image[9] = 0x0f;
+    image
+}
+
+#[test]
+fn prg_rom_sizes_the_board_cant_use_are_rejected() {
+    let parse = |image: Vec<u8>| RomFileParser::from_bytes(image).parse().err();
+
+    // 24KB, which isn't a whole number of 16KB banks
+    assert_eq!(parse(nes20_image(0, 13, 1)), Some(CartridgeError::BadPrgSize { mapper: 0, size: 0x6000 }));
+    assert_eq!(parse(nes20_image(1, 13, 1)), Some(CartridgeError::BadPrgSize { mapper: 1, size: 0x6000 }));
+    // The MMC3 fixes its last two 8KB banks, so it needs at least 16KB
+    assert_eq!(parse(nes20_image(4, 13, 0)), Some(CartridgeError::BadPrgSize { mapper: 4, size: 0x2000 }));
+    let cartridge = RomFileParser::from_bytes(nes20_image(4, 13, 1)).parse().unwrap();
+    assert_eq!(cartridge.header.prg_rom_size, 0x6000);
+    assert_eq!(parse(common::image(1, 0, &[], &[])), Some(CartridgeError::BadPrgSize { mapper: 1, size: 0 }));
+}
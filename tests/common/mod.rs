@@ -0,0 +1,69 @@
+//! Helpers shared between the integration tests
+
+#![allow(dead_code)]
+
+use neks::cpu::CPU;
+use neks::ines::{Cartridge, RomFileParser};
+
+/// Turns on NMIs, then waits. It has to go at $8000
+pub const WAIT_FOR_NMI: &[u8] = &[
+    0xa9, 0x80,             // LDA #$80
+    0x8d, 0x00, 0x20,       // STA $2000
+    0x4c, 0x05, 0x80,       // JMP $8005
+];
+
+/// Builds an iNES image for the given mapper, around the given PRG and CHR data
+pub fn image(mapper: u8, flags_6: u8, prg: &[u8], chr: &[u8]) -> Vec<u8> {
+    let mut rom = b"NES\x1a".to_vec();
+    rom.push((prg.len() / 0x4000) as u8);
+    rom.push((chr.len() / 0x2000) as u8);
+    rom.push((mapper << 4) | (flags_6 & 0x0f));
+    rom.push(mapper & 0xf0);
+    rom.extend(&[0; 8]);
+    rom.extend(prg);
+    rom.extend(chr);
+    rom
+}
+
+pub fn parse(image: Vec<u8>) -> Cartridge {
+    RomFileParser::from_bytes(image)
+        .parse()
+        .unwrap()
+}
+
+/// 32KB of PRG-ROM, NOPs apart from each program copied to its address, with the vectors pointing at the given
+/// addresses, in the order they're in at $FFFA
+pub fn nrom_prg(programs: &[(u16, &[u8])], nmi: u16, reset: u16, irq: u16) -> Vec<u8> {
+    let mut prg = vec![0xea; 0x8000];
+    for (address, program) in programs {
+        let start = (*address - 0x8000) as usize;
+        prg[start..start + program.len()].copy_from_slice(program);
+    }
+    prg[0x7ffa..0x7ffc].copy_from_slice(&nmi.to_le_bytes());
+    prg[0x7ffc..0x7ffe].copy_from_slice(&reset.to_le_bytes());
+    prg[0x7ffe..0x8000].copy_from_slice(&irq.to_le_bytes());
+    prg
+}
+
+/// Builds an NROM-256 cartridge around `nrom_prg`, with blank CHR-ROM
+pub fn nrom(programs: &[(u16, &[u8])], nmi: u16, reset: u16, irq: u16) -> Cartridge {
+    parse(image(0, 0, &nrom_prg(programs, nmi, reset, irq), &[0; 0x2000]))
+}
+
+/// Runs the program until it jams, failing if that takes more than `limit` instructions
+pub fn run_until_jammed(cpu: &mut CPU, limit: usize) {
+    let mut steps = 0;
+    while !cpu.is_jammed() {
+        cpu.step();
+        steps += 1;
+        assert!(steps < limit, "Program never finished");
+    }
+}
+
+/// Runs until the PPU starts the next frame
+pub fn run_frame(cpu: &mut CPU) {
+    let frame = cpu.ppu().frame();
+    while cpu.ppu().frame() == frame {
+        cpu.step();
+    }
+}
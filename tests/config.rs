@@ -0,0 +1,93 @@
+use neks::config::{Binding, Config, Pacing, PixelAspect, Scaling};
+use neks::controller::Buttons;
+
+#[test]
+fn defaults_survive_being_written_out() {
+    let path = std::env::temp_dir().join(format!("neks-config-{}", std::process::id())).join("config.toml");
+    let _ = std::fs::remove_file(&path);
+
+    // The first run writes the defaults, and the next reads them back
+    let created = Config::load_or_create(&path).unwrap();
+    assert_eq!(created, Config::default());
+    assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn missing_settings_get_defaults() {
+    let config = Config::parse(r#"
+        [player_1.keyboard]
+        a = ["Space", "X"]
+
+        [turbo]
+        rate = 20.0
+    "#).unwrap();
+    assert_eq!(config.turbo.rate, 20.0);
+    assert_eq!(config.hotkeys, Config::default().hotkeys);
+    assert_eq!(config.player_2, Config::default().player_2);
+    assert_eq!(config.player_1.controller, Config::default().player_1.controller);
+
+    // Only A changes, and everything else keeps player 1's defaults
+    let defaults = Config::default().player_1.keyboard;
+    let keyboard = &config.player_1.keyboard;
+    assert_eq!(keyboard.a, vec!["Space", "X"]);
+    assert_eq!((&keyboard.b, &keyboard.start, &keyboard.select), (&defaults.b, &defaults.start, &defaults.select));
+    assert_eq!((&keyboard.up, &keyboard.down, &keyboard.left, &keyboard.right),
+        (&defaults.up, &defaults.down, &defaults.left, &defaults.right));
+    let entries = keyboard.entries();
+    assert_eq!(entries[..3], [
+        ("Space", Binding::Held(Buttons::A)),
+        ("X", Binding::Held(Buttons::A)),
+        ("Z", Binding::Held(Buttons::B)),
+    ]);
+
+    // The same goes for part of a controller table, and for player 2, whose defaults are different
+    let config = Config::parse(r#"
+        [player_1.controller]
+        start = ["guide"]
+
+        [player_2.keyboard]
+        b = []
+    "#).unwrap();
+    let mut controller = Config::default().player_1.controller;
+    controller.start = vec!["guide".to_string()];
+    assert_eq!(config.player_1.controller, controller);
+    let mut keyboard = Config::default().player_2.keyboard;
+    keyboard.b.clear();
+    assert_eq!(config.player_2.keyboard, keyboard);
+}
+
+#[test]
+fn bad_config_is_an_error() {
+    assert!(Config::parse("[turbo]\nrate = \"fast\"").is_err());
+}
+
+#[test]
+fn turbo_alternates_at_its_rate() {
+    let mut config = Config::default();
+    config.turbo.rate = 15.0;
+    // At 60 frames a second, 15 presses a second is 2 frames pressed then 2 released
+    let pattern: Vec<bool> = (0..8).map(|frame| config.turbo.pressed(frame, 60.0)).collect();
+    assert_eq!(pattern, vec![true, true, false, false, true, true, false, false]);
+}
+
+#[test]
+fn video_settings_are_read() {
+    let config = Config::parse(r#"
+        [video]
+        scaling = "stretch"
+        pixel_aspect = "square"
+        overscan_top = 0
+        pacing = "vsync"
+        palette = "smooth.pal"
+    "#).unwrap();
+    assert_eq!(config.video.scaling, Scaling::Stretch);
+    assert_eq!(config.video.pixel_aspect, PixelAspect::Square);
+    assert_eq!(config.video.overscan_top, 0);
+    assert_eq!(config.video.overscan_bottom, 8);
+    assert_eq!(config.video.pacing, Pacing::Vsync);
+    assert_eq!(config.video.palette, Some("smooth.pal".into()));
+
+    assert!(Config::parse("[video]\nscaling = \"sideways\"").is_err());
+}
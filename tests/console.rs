@@ -0,0 +1,59 @@
+mod common;
+
+use neks::console::{write_png, Console};
+use neks::palette::Palette;
+
+/// Writes $42 to $10, then jams
+const PROGRAM: &[u8] = &[
+    0xa9, 0x42,             // LDA #$42
+    0x85, 0x10,             // STA $10
+    0x02,                   // JAM
+];
+
+#[test]
+fn runs_until_condition_or_gives_up() {
+    let mut console = Console::new(common::nrom(&[(0x8000, PROGRAM)], 0x8000, 0x8000, 0x8000));
+    assert!(console.run_until(10, |cpu| cpu.is_jammed()));
+    assert_eq!(console.frame(), 0);
+    assert_eq!(console.cpu().peek(0x0010), 0x42);
+
+    // The CPU stays jammed, but the rest of the console keeps going
+    assert!(!console.run_until(3, |cpu| cpu.peek(0x0010) == 0));
+    assert_eq!(console.frame(), 3);
+}
+
+#[test]
+fn writes_png_wav_and_snapshot() {
+    let mut console = Console::new(common::nrom(&[(0x8000, PROGRAM)], 0x8000, 0x8000, 0x8000));
+    console.set_record_audio(true);
+    console.run_frames(6);
+
+    let mut png = Vec::new();
+    console.write_png(&Palette::default(), &mut png).unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    // Width and height, from the IHDR chunk
+    assert_eq!(&png[16..24], &[0, 0, 1, 0, 0, 0, 0, 240]);
+    // As the frontend's screenshots write it, straight from the PPU
+    let mut screenshot = Vec::new();
+    write_png(console.cpu().ppu().framebuffer(), &Palette::default(), &mut screenshot).unwrap();
+    assert_eq!(screenshot, png);
+
+    // About a tenth of a second of audio
+    let samples = console.audio().len();
+    assert!((4700..=4900).contains(&samples), "{} samples", samples);
+    let mut wav = Vec::new();
+    console.write_wav(&mut wav).unwrap();
+    assert_eq!(&wav[..4], b"RIFF");
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    assert_eq!(wav.len(), 44 + samples * 2);
+
+    let mut snapshot = Vec::new();
+    console.write_snapshot(&mut snapshot).unwrap();
+    let snapshot = String::from_utf8(snapshot).unwrap();
+    let lines: Vec<&str> = snapshot.lines().collect();
+    assert!(lines[0].starts_with("PC:"));
+    assert!(lines[0].contains("A:42"));
+    assert!(lines[0].ends_with("FRAME:6"));
+    assert_eq!(lines.len(), 1 + 0x800 / 16);
+    assert_eq!(lines[2], "0010: 42 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00");
+}
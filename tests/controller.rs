@@ -0,0 +1,54 @@
+mod common;
+
+use neks::controller::{Buttons, Joypad, Port};
+use neks::cpu::CPU;
+
+/// Strobes the controllers, then reads $4016 nine times into $10-$18 and $4017 once into $20
+const READ_CONTROLLERS: &[u8] = &[
+    0xa9, 0x01,             // LDA #$01
+    0x8d, 0x16, 0x40,       // STA $4016: strobe high
+    0xad, 0x16, 0x40,       // LDA $4016: only ever A while strobing
+    0xad, 0x16, 0x40,       // LDA $4016
+    0x85, 0x30,             // STA $30
+    0xa9, 0x00,             // LDA #$00
+    0x8d, 0x16, 0x40,       // STA $4016: strobe low, so the buttons are latched
+    0xa2, 0x00,             // LDX #$00
+    0xad, 0x16, 0x40,       // LDA $4016
+    0x95, 0x10,             // STA $10,X
+    0xe8,                   // INX
+    0xe0, 0x09,             // CPX #$09
+    0xd0, 0xf6,             // BNE $8014
+    0xad, 0x17, 0x40,       // LDA $4017
+    0x85, 0x20,             // STA $20
+    0x02,                   // JAM
+];
+
+#[test]
+fn joypad_shifts_out_buttons_in_order() {
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, READ_CONTROLLERS)], 0x8000, 0x8000, 0x8000));
+    let joypad = Joypad::shared();
+    joypad.borrow_mut().set_buttons(Buttons::A | Buttons::START | Buttons::LEFT);
+    cpu.connect(Port::One, Some(joypad));
+
+    common::run_until_jammed(&mut cpu, 1000);
+    assert_eq!(cpu.peek(0x0030) & 0x1f, 0x01);
+    let bits: Vec<u8> = (0x10..0x19).map(|address| cpu.peek(address) & 0x1f).collect();
+    // A, B, Select, Start, Up, Down, Left, Right, then 1s once the buttons run out
+    assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 1, 0, 1]);
+    // The top bits are left over from the address: $40
+    assert_eq!(cpu.peek(0x0010) & 0xe0, 0x40);
+    // Nothing is plugged into port 2
+    assert_eq!(cpu.peek(0x0020), 0x40);
+}
+
+#[test]
+fn opposite_directions_are_not_held_together() {
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, READ_CONTROLLERS)], 0x8000, 0x8000, 0x8000));
+    let joypad = Joypad::shared();
+    joypad.borrow_mut().set_buttons(Buttons::UP | Buttons::DOWN | Buttons::LEFT | Buttons::RIGHT);
+    cpu.connect(Port::One, Some(joypad));
+
+    common::run_until_jammed(&mut cpu, 1000);
+    let bits: Vec<u8> = (0x14..0x18).map(|address| cpu.peek(address) & 0x1f).collect();
+    assert_eq!(bits, vec![1, 0, 1, 0]);
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use std::io::Cursor;
+
+use neks::cpu::CPU;
+use neks::debugger::{Condition, Debugger};
+use neks::memory::{WatchKind, Watchpoint};
+
+/// Counts up in X, storing it at $0300, and calls a subroutine each time round
+const PROGRAM: &[u8] = &[
+    0xa2, 0x00,             // 8000: LDX #$00
+    0x20, 0x10, 0x80,       // 8002: JSR $8010
+    0xe8,                   // 8005: INX
+    0x8e, 0x00, 0x03,       // 8006: STX $0300
+    0x4c, 0x02, 0x80,       // 8009: JMP $8002
+    0xea, 0xea, 0xea, 0xea, // 800C: NOP
+    0xa9, 0x05,             // 8010: LDA #$05
+    0x85, 0x10,             // 8012: STA $10
+    0xe6, 0x11,             // 8014: INC $11
+    0x60,                   // 8016: RTS
+];
+
+fn cpu() -> CPU {
+    CPU::init(common::nrom(&[(0x8000, PROGRAM)], 0x8000, 0x8000, 0x8000))
+}
+
+/// Runs one command, returning what it printed
+fn command(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> String {
+    let mut output = Vec::new();
+    assert!(debugger.command(cpu, line, &mut output).unwrap());
+    String::from_utf8(output).unwrap()
+}
+
+fn pc(cpu: &CPU) -> u16 {
+    cpu.registers().pc
+}
+
+#[test]
+fn steps_over_and_out_of_subroutines() {
+    let mut cpu = cpu();
+    let mut debugger = Debugger::new();
+    assert_eq!(pc(&cpu), 0x8000);
+
+    assert_eq!(command(&mut debugger, &mut cpu, "step"), "8002  20 10 80  JSR $8010\n");
+    command(&mut debugger, &mut cpu, "s");
+    assert_eq!(pc(&cpu), 0x8010);
+    command(&mut debugger, &mut cpu, "finish");
+    assert_eq!(pc(&cpu), 0x8005);
+    assert_eq!((cpu.peek(0x0010), cpu.peek(0x0011)), (0x05, 0x01));
+
+    command(&mut debugger, &mut cpu, "step 3");
+    assert_eq!(pc(&cpu), 0x8002);
+    // Over the whole subroutine, then an empty line does it again
+    command(&mut debugger, &mut cpu, "next");
+    assert_eq!(pc(&cpu), 0x8005);
+    assert_eq!(cpu.peek(0x0011), 0x02);
+    command(&mut debugger, &mut cpu, "");
+    assert_eq!(pc(&cpu), 0x8006);
+
+    // A breakpoint inside the subroutine stops next before it returns
+    command(&mut debugger, &mut cpu, "break 8014");
+    command(&mut debugger, &mut cpu, "step 2");
+    assert_eq!(pc(&cpu), 0x8002);
+    assert_eq!(command(&mut debugger, &mut cpu, "next"), "Stopped at 1\n8014  E6 11     INC $11 = 02\n");
+}
+
+#[test]
+fn breakpoints_and_watchpoints_stop_execution() {
+    let mut cpu = cpu();
+    let mut debugger = Debugger::new();
+
+    assert_eq!(command(&mut debugger, &mut cpu, "break if x == 3 && a >= 5"), "1: break if x == 3 && a >= 5\n");
+    command(&mut debugger, &mut cpu, "continue");
+    assert_eq!((cpu.registers().x, pc(&cpu)), (3, 0x8006));
+    command(&mut debugger, &mut cpu, "delete 1");
+
+    // Stops after the write, on the next instruction
+    assert_eq!(command(&mut debugger, &mut cpu, "watch w 0300"), "2: watch w $0300\n");
+    let output = command(&mut debugger, &mut cpu, "c");
+    assert!(output.starts_with("Stopped at 2: Wrote $03 at $0300\n"), "{}", output);
+    assert_eq!(pc(&cpu), 0x8009);
+
+    command(&mut debugger, &mut cpu, "watch r 0011");
+    let output = command(&mut debugger, &mut cpu, "c");
+    assert!(output.starts_with("Stopped at 3: Read $03 at $0011\n"), "{}", output);
+    assert_eq!(pc(&cpu), 0x8016);
+
+    command(&mut debugger, &mut cpu, "delete");
+    command(&mut debugger, &mut cpu, "watch x 8010-8013");
+    command(&mut debugger, &mut cpu, "c");
+    assert_eq!(pc(&cpu), 0x8010);
+    command(&mut debugger, &mut cpu, "c");
+    assert_eq!(pc(&cpu), 0x8012);
+    assert_eq!(command(&mut debugger, &mut cpu, "list"), "4: watch x $8010-$8013\n");
+
+    // The bus only checks reads and writes
+    cpu.set_watchpoints(&[Watchpoint { start: 0x0010, end: 0x0011, kind: WatchKind::WRITE }]);
+    cpu.step();
+    let hit = cpu.take_watch_hit().unwrap();
+    assert_eq!((hit.address, hit.kind, hit.value), (0x0010, WatchKind::WRITE, 0x05));
+    assert_eq!(cpu.take_watch_hit(), None);
+}
+
+#[test]
+fn registers_and_memory_can_be_changed() {
+    let mut cpu = cpu();
+    let mut debugger = Debugger::new();
+
+    command(&mut debugger, &mut cpu, "set a 42");
+    command(&mut debugger, &mut cpu, "set c 1");
+    let output = command(&mut debugger, &mut cpu, "set x $7f");
+    assert!(output.starts_with("PC:8000 A:42 X:7F Y:00 P:25 SP:FD nv-bdIzC  CYC:7 "), "{}", output);
+    assert_eq!(command(&mut debugger, &mut cpu, "set a 100"), "100 doesn't fit in a\n");
+    assert_eq!(command(&mut debugger, &mut cpu, "set q 1"), "Unknown register q\n");
+
+    command(&mut debugger, &mut cpu, "poke 0200 01 02 ff");
+    assert_eq!(cpu.peek(0x0201), 0x02);
+    assert_eq!(command(&mut debugger, &mut cpu, "memory 01fe 6"), "01FE: 00 00 01 02 FF 00\n");
+    let output = command(&mut debugger, &mut cpu, "m 0200 20");
+    assert_eq!(output.lines().count(), 2);
+    assert!(output.ends_with("0210: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n"));
+
+    assert!(Condition::parse("a = 3").is_err());
+    assert!(Condition::parse("q == 3").is_err());
+    assert!(Condition::parse("z==1").unwrap().holds(&cpu.registers()) == (cpu.registers().p & 0x02 != 0));
+    assert_eq!(command(&mut debugger, &mut cpu, "frobnicate"), "Unknown command frobnicate, try help\n");
+}
+
+#[test]
+fn disassembles_around_pc() {
+    let mut cpu = cpu();
+    let mut debugger = Debugger::new();
+    command(&mut debugger, &mut cpu, "step 3");
+    assert_eq!(pc(&cpu), 0x8012);
+
+    let output = command(&mut debugger, &mut cpu, "disassemble");
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(lines.contains(&"> 8012  85 10     STA $10 = 00"), "{}", output);
+    assert!(lines.contains(&"  8010  A9 05     LDA #$05"), "{}", output);
+    assert!(lines.contains(&"  8016  60        RTS"), "{}", output);
+    assert_eq!(lines.iter().position(|line| line.starts_with('>')), Some(4));
+    assert_eq!(lines.len(), 11);
+
+    let output = command(&mut debugger, &mut cpu, "u 8002");
+    assert!(output.contains("  8002  20 10 80  JSR $8010\n"), "{}", output);
+    assert!(!output.contains('>'));
+}
+
+#[test]
+fn reads_commands_until_quit() {
+    let mut cpu = cpu();
+    let input = Cursor::new("break 8016\ncontinue\nregisters\nquit\nstep\n");
+    let mut output = Vec::new();
+    Debugger::new().repl(&mut cpu, input, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("8000  A2 00     LDX #$00\n(neks) 1: break at $8016\n"), "{}", output);
+    assert!(output.contains("(neks) Stopped at 1\n8016  60        RTS\n(neks) PC:8016 A:05"), "{}", output);
+    assert!(output.ends_with("(neks) "));
+    assert_eq!(pc(&cpu), 0x8016);
+}
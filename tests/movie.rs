@@ -0,0 +1,220 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use neks::controller::{Buttons, Joypad, Port};
+use neks::cpu::CPU;
+use neks::movie::{Commands, Desync, Frame, Movie, MovieError, Playback, Recording};
+
+/// Counts frames in $10, and adds player 1's joypad, read as a byte, to $11
+const NMI: &[u8] = &[
+    0xe6, 0x10,             // INC $10
+    0xa9, 0x01,             // LDA #$01
+    0x8d, 0x16, 0x40,       // STA $4016
+    0xa9, 0x00,             // LDA #$00
+    0x8d, 0x16, 0x40,       // STA $4016
+    0xa2, 0x08,             // LDX #$08
+    0xad, 0x16, 0x40,       // LDA $4016
+    0x4a,                   // LSR A
+    0x26, 0x12,             // ROL $12
+    0xca,                   // DEX
+    0xd0, 0xf7,             // BNE -9
+    0xa5, 0x12,             // LDA $12
+    0x18,                   // CLC
+    0x65, 0x11,             // ADC $11
+    0x85, 0x11,             // STA $11
+    0x40,                   // RTI
+];
+
+fn machine() -> (CPU, [Rc<RefCell<Joypad>>; 2]) {
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, common::WAIT_FOR_NMI), (0x9000, NMI)], 0x9000, 0x8000, 0x8000));
+    let joypads = [Joypad::shared(), Joypad::shared()];
+    cpu.connect(Port::One, Some(joypads[0].clone()));
+    cpu.connect(Port::Two, Some(joypads[1].clone()));
+    (cpu, joypads)
+}
+
+fn run_frame(cpu: &mut CPU, joypads: &[Rc<RefCell<Joypad>>; 2], buttons: [Buttons; 2]) {
+    for (joypad, buttons) in joypads.iter().zip(buttons.iter()) {
+        joypad.borrow_mut().force_buttons(*buttons);
+    }
+    common::run_frame(cpu);
+}
+
+fn input(frame: usize) -> [Buttons; 2] {
+    let player_1 = match frame % 5 {
+        0 => Buttons::A | Buttons::RIGHT,
+        1 => Buttons::START,
+        2 => Buttons::UP | Buttons::DOWN,
+        _ => Buttons::empty(),
+    };
+    [player_1, Buttons::from_bits_truncate(frame as u8)]
+}
+
+/// Records 60 frames, with a soft reset on frame 20 and a hard one on frame 40
+fn record(cpu: &mut CPU, joypads: &[Rc<RefCell<Joypad>>; 2], mut recording: Recording) -> Movie {
+    for frame in 0..60 {
+        if frame == 20 || frame == 40 {
+            recording.reset(frame == 40);
+        }
+        recording.begin_frame(cpu, input(frame));
+        run_frame(cpu, joypads, input(frame));
+        recording.end_frame(cpu);
+    }
+    recording.into_movie()
+}
+
+/// Plays the whole movie, returning the first desync
+fn play(cpu: &mut CPU, joypads: &[Rc<RefCell<Joypad>>; 2], playback: &mut Playback) -> Option<Desync> {
+    let mut desync = None;
+    while let Some(frame) = playback.begin_frame(cpu) {
+        let buttons = frame.buttons;
+        run_frame(cpu, joypads, buttons);
+        if let Err(e) = playback.end_frame(cpu) {
+            desync.get_or_insert(e);
+        }
+    }
+    desync
+}
+
+fn ram(cpu: &CPU) -> Vec<u8> {
+    (0..0x800).map(|address| cpu.peek(address)).collect()
+}
+
+#[test]
+fn reads_and_writes_fm2() {
+    let text = "version 3\n\
+                emuVersion 22020\n\
+                rerecordCount 12\n\
+                palFlag 0\n\
+                romFilename Some Game\n\
+                romChecksum base64:AAAAAAAAAAAAAAAAAAAAAA==\n\
+                guid 00000000-0000-0000-0000-000000000000\n\
+                fourscore 0\n\
+                port0 1\n\
+                port1 0\n\
+                port2 0\n\
+                comment author Somebody\n\
+                |0|........|||\n\
+                |1|R..U...A|||\n\
+                |0|RLDUTSBA|||\n\
+                |2| L  T B |||\n";
+    let movie = Movie::parse(text).unwrap();
+    assert_eq!(movie.rom_filename, "Some Game");
+    assert_eq!(movie.rerecords, 12);
+    assert!(!movie.pal);
+    assert_eq!(movie.gamepads, [true, false]);
+    assert_eq!(movie.savestate, None);
+    assert_eq!(movie.extra[0], ("emuVersion".to_string(), "22020".to_string()));
+    assert_eq!(movie.extra[3], ("comment".to_string(), "author Somebody".to_string()));
+
+    let buttons: Vec<Buttons> = movie.frames.iter().map(|frame| frame.buttons[0]).collect();
+    assert_eq!(buttons, [
+        Buttons::empty(),
+        Buttons::RIGHT | Buttons::UP | Buttons::A,
+        Buttons::all(),
+        Buttons::LEFT | Buttons::START | Buttons::B,
+    ]);
+    let commands: Vec<Commands> = movie.frames.iter().map(|frame| frame.commands).collect();
+    assert_eq!(commands, [Commands::empty(), Commands::SOFT_RESET, Commands::empty(), Commands::HARD_RESET]);
+    assert!(movie.frames.iter().all(|frame| frame.buttons[1].is_empty() && frame.ram_hash.is_none()));
+
+    // Written back out, the frames look just the same, apart from spaces becoming dots
+    let written = movie.to_fm2();
+    assert!(written.contains("|0|........|||\n|1|R..U...A|||\n|0|RLDUTSBA|||\n|2|.L..T.B.|||\n"), "{}", written);
+    assert!(written.contains("comment author Somebody\n"));
+    assert_eq!(Movie::parse(&written).unwrap(), movie);
+
+    // With both ports, a save state and RAM hashes
+    let mut movie = Movie::new("Game", true, Some(vec![0, 1, 2, 0xff]));
+    movie.frames.push(Frame {
+        commands: Commands::empty(),
+        buttons: [Buttons::SELECT, Buttons::DOWN],
+        ram_hash: Some(0x0123_4567_89ab_cdef),
+    });
+    let written = movie.to_fm2();
+    assert!(written.contains("savestate base64:AAEC/w==\n"), "{}", written);
+    assert!(written.ends_with("|0|.....S..|..D.....||0123456789abcdef\n"), "{}", written);
+    assert_eq!(Movie::parse(&written).unwrap(), movie);
+}
+
+#[test]
+fn unsupported_movies_are_rejected() {
+    let unsupported = |text: &str| matches!(Movie::parse(text), Err(MovieError::Unsupported(_)));
+    assert!(unsupported("version 3\nport1 2\n"));
+    assert!(unsupported("version 3\nbinary 1\n"));
+    assert!(unsupported("version 3\nfourscore 1\n"));
+    assert!(unsupported("version 2\n"));
+
+    match Movie::parse("version 3\nport1 0\n|0|........|||\n|0|R..U|||\n") {
+        Err(MovieError::Parse { line, .. }) => assert_eq!(line, 4),
+        other => panic!("Expected a parse error, not {:?}", other),
+    }
+    // Famicom Disk System commands
+    assert!(matches!(Movie::parse("|4|........|........||\n"), Err(MovieError::Parse { line: 1, .. })));
+}
+
+#[test]
+fn recorded_movies_play_back_identically() {
+    let (mut cpu, joypads) = machine();
+    run_frame(&mut cpu, &joypads, [Buttons::B, Buttons::empty()]);
+    let recording = Recording::from_power_on(&mut cpu, "test");
+    let movie = record(&mut cpu, &joypads, recording);
+    assert_eq!(movie.frames.len(), 60);
+    assert_eq!(movie.frames[20].commands, Commands::SOFT_RESET);
+    assert_eq!(movie.frames[40].commands, Commands::HARD_RESET);
+    assert!(movie.frames.iter().all(|frame| frame.ram_hash.is_some()));
+    // Up and down together made it through
+    assert_eq!(movie.frames[2].buttons[0], Buttons::UP | Buttons::DOWN);
+    let expected = ram(&cpu);
+
+    // From a file, on a console which has already been running
+    let movie = Movie::parse(&movie.to_fm2()).unwrap();
+    let (mut cpu, joypads) = machine();
+    for frame in 0..10 {
+        run_frame(&mut cpu, &joypads, input(frame + 3));
+    }
+    let mut playback = Playback::start(&mut cpu, movie.clone()).unwrap();
+    assert_eq!(play(&mut cpu, &joypads, &mut playback), None);
+    assert!(playback.is_finished());
+    assert_eq!(playback.frame(), 60);
+    assert_eq!(ram(&cpu), expected);
+
+    // Different input on one frame throws it off from then on, or at least until the hard reset
+    let mut changed = movie;
+    changed.frames[45].buttons[0] = Buttons::SELECT;
+    let (mut cpu, joypads) = machine();
+    let mut playback = Playback::start(&mut cpu, changed).unwrap();
+    let desync = play(&mut cpu, &joypads, &mut playback).expect("Expected a desync");
+    assert_eq!(desync.frame, 45);
+    assert_ne!(ram(&cpu), expected);
+}
+
+#[test]
+fn movies_can_start_from_a_save_state() {
+    let (mut cpu, joypads) = machine();
+    for frame in 0..25 {
+        run_frame(&mut cpu, &joypads, input(frame));
+    }
+    let recording = Recording::from_state(&cpu, "test");
+    let movie = record(&mut cpu, &joypads, recording);
+    assert!(movie.savestate.is_some());
+    let expected = ram(&cpu);
+
+    let movie = Movie::parse(&movie.to_fm2()).unwrap();
+    let (mut cpu, joypads) = machine();
+    let mut playback = Playback::start(&mut cpu, movie).unwrap();
+    assert_eq!(cpu.peek(0x0010), 24);
+    assert_eq!(play(&mut cpu, &joypads, &mut playback), None);
+    assert_eq!(ram(&cpu), expected);
+
+    // A state from some other game
+    let mut movie = Movie::new("test", false, Some(b"FCSX".to_vec()));
+    movie.frames.push(Frame {
+        commands: Commands::empty(),
+        buttons: [Buttons::empty(); 2],
+        ram_hash: None,
+    });
+    assert!(matches!(Playback::start(&mut cpu, movie), Err(MovieError::State(_))));
+}
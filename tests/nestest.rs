@@ -0,0 +1,165 @@
+//! CPU conformance tests, driven through the trace hook.
+//!
+//! Every trace line is in the format of nestest.log, so the output can be diffed line by line
+//! against logs from other emulators. The golden log test needs nestest.nes and nestest.log from
+//! https://www.qmtpro.com/~nes/misc/ to be copied into tests/roms, and is ignored by default.
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+mod common;
+
+use neks::cpu::{Registers, CPU};
+use neks::ines::RomFileParser;
+use neks::memory::IrqSource;
+
+const NESTEST_ROM: &str = "tests/roms/nestest.nes";
+const NESTEST_LOG: &str = "tests/roms/nestest.log";
+
+/// The state nestest expects to be started in: after reset, but with PC moved to the automation entry point
+fn nestest_registers(pc: u16) -> Registers {
+    Registers {
+        pc,
+        a: 0,
+        x: 0,
+        y: 0,
+        s: 0xfd,
+        p: 0x24,
+    }
+}
+
+/// Steps the CPU until `count` instructions have been traced, collecting the trace lines.
+/// Servicing an interrupt is a step of its own, but doesn't produce a line
+fn trace(cpu: &mut CPU, count: usize) -> Vec<String> {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let hook_lines = lines.clone();
+    cpu.set_trace_hook(move |trace| hook_lines.borrow_mut().push(trace.to_string()));
+    while lines.borrow().len() < count {
+        cpu.step();
+    }
+    cpu.clear_trace_hook();
+    let lines = lines.borrow().clone();
+    lines
+}
+
+/// The PPU position depends on the timing region, which these tests don't care about
+fn without_ppu(line: &str) -> String {
+    match (line.find("PPU:"), line.find("CYC:")) {
+        (Some(start), Some(end)) => format!("{}{}", &line[..start], &line[end..]),
+        _ => line.to_string(),
+    }
+}
+
+/// Compares two traces, reporting the first line which differs with some context
+fn assert_traces_match(expected: &[String], actual: &[String]) {
+    for (i, (expected_line, actual_line)) in expected.iter().zip(actual.iter()).enumerate() {
+        if expected_line != actual_line {
+            let context: Vec<&String> = actual[i.saturating_sub(5)..i].iter().collect();
+            panic!("Trace differs at line {}\nPreceding lines:\n{}\nexpected: {}\nactual:   {}",
+                i + 1,
+                context.iter().map(|l| l.as_str()).collect::<Vec<&str>>().join("\n"),
+                expected_line,
+                actual_line,
+            );
+        }
+    }
+    assert_eq!(expected.len(), actual.len(), "Traces have different lengths");
+}
+
+#[test]
+fn trace_matches_nestest_format() {
+    let main: &[u8] = &[
+        0xa2, 0x05,             // LDX #$05
+        0x86, 0x10,             // STX $10
+        0xb5, 0x0b,             // LDA $0B,X
+        0x69, 0xff,             // ADC #$FF
+        0x8d, 0x00, 0x02,       // STA $0200
+        0x20, 0x20, 0xc0,       // JSR $C020
+        0xbd, 0xfb, 0x01,       // LDA $01FB,X
+        0xf0, 0x02,             // BEQ $C015
+        0x02,                   // JAM
+    ];
+    let subroutine: &[u8] = &[
+        0xa7, 0x10,             // LAX $10
+        0x60,                   // RTS
+    ];
+    let cartridge = common::nrom(&[(0xc000, main), (0xc020, subroutine)], 0xc000, 0xc000, 0xc000);
+    let mut cpu = CPU::init_with_registers(cartridge, nestest_registers(0xc000));
+
+    let expected: Vec<String> = [
+        "C000  A2 05     LDX #$05                        A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+        "C002  86 10     STX $10 = 00                    A:00 X:05 Y:00 P:24 SP:FD CYC:9",
+        "C004  B5 0B     LDA $0B,X @ 10 = 05             A:00 X:05 Y:00 P:24 SP:FD CYC:12",
+        "C006  69 FF     ADC #$FF                        A:05 X:05 Y:00 P:24 SP:FD CYC:16",
+        "C008  8D 00 02  STA $0200 = 00                  A:04 X:05 Y:00 P:25 SP:FD CYC:18",
+        "C00B  20 20 C0  JSR $C020                       A:04 X:05 Y:00 P:25 SP:FD CYC:22",
+        "C020  A7 10    *LAX $10 = 05                    A:04 X:05 Y:00 P:25 SP:FB CYC:28",
+        "C022  60        RTS                             A:05 X:05 Y:00 P:25 SP:FB CYC:31",
+        "C00E  BD FB 01  LDA $01FB,X @ 0200 = 04         A:05 X:05 Y:00 P:25 SP:FD CYC:37",
+        "C011  F0 02     BEQ $C015                       A:04 X:05 Y:00 P:25 SP:FD CYC:42",
+        "C013  02       *JAM                             A:04 X:05 Y:00 P:25 SP:FD CYC:44",
+    ].iter().map(|line| line.to_string()).collect();
+    let actual: Vec<String> = trace(&mut cpu, expected.len()).iter().map(|line| without_ppu(line)).collect();
+
+    assert_traces_match(&expected, &actual);
+    assert!(cpu.is_jammed());
+    // JSR pushes the address of its own last byte
+    assert_eq!(cpu.peek(0x01fd), 0xc0);
+    assert_eq!(cpu.peek(0x01fc), 0x0d);
+}
+
+#[test]
+fn irq_waits_for_instruction_after_cli() {
+    let main: &[u8] = &[
+        0x58,                   // CLI
+        0xea,                   // NOP
+        0xea,                   // NOP
+    ];
+    let handler: &[u8] = &[
+        0x40,                   // RTI
+    ];
+    let cartridge = common::nrom(&[(0xc000, main), (0xc100, handler)], 0xc100, 0xc000, 0xc100);
+    let mut cpu = CPU::init_with_registers(cartridge, nestest_registers(0xc000));
+    cpu.set_irq(IrqSource::EXTERNAL, true);
+
+    let actual: Vec<String> = trace(&mut cpu, 3).iter().map(|line| without_ppu(line)).collect();
+    let expected: Vec<String> = [
+        "C000  58        CLI                             A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+        // CLI's change to I isn't seen until the end of the next instruction
+        "C001  EA        NOP                             A:00 X:00 Y:00 P:20 SP:FD CYC:9",
+        "C100  40        RTI                             A:00 X:00 Y:00 P:24 SP:FA CYC:18",
+    ].iter().map(|line| line.to_string()).collect();
+
+    assert_traces_match(&expected, &actual);
+    // Return address, then P with B clear
+    assert_eq!(cpu.peek(0x01fd), 0xc0);
+    assert_eq!(cpu.peek(0x01fc), 0x02);
+    assert_eq!(cpu.peek(0x01fb), 0x20);
+}
+
+#[test]
+#[ignore]
+fn nestest_golden_log() {
+    assert!(Path::new(NESTEST_ROM).exists() && Path::new(NESTEST_LOG).exists(),
+        "Copy nestest.nes and nestest.log into tests/roms to run this test");
+
+    let cartridge = RomFileParser::load(NESTEST_ROM)
+        .unwrap()
+        .parse()
+        .unwrap();
+    // $C000 runs every test without needing a display
+    let mut cpu = CPU::init_with_registers(cartridge, nestest_registers(0xc000));
+
+    let expected: Vec<String> = std::fs::read_to_string(NESTEST_LOG)
+        .unwrap()
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+    let actual = trace(&mut cpu, expected.len());
+    assert_traces_match(&expected, &actual);
+
+    // nestest leaves its result codes in $02 and $03, 0 meaning every test passed
+    assert_eq!(cpu.peek(0x0002), 0, "Official opcode tests failed");
+    assert_eq!(cpu.peek(0x0003), 0, "Unofficial opcode tests failed");
+}
@@ -0,0 +1,33 @@
+use neks::palette::{Palette, PaletteError};
+
+#[test]
+fn default_palette_has_black_and_white() {
+    let palette = Palette::default();
+    assert_eq!(palette.rgba(0x0f), [0, 0, 0, 0xff]);
+    assert_eq!(palette.rgba(0x30), [0xff, 0xfe, 0xff, 0xff]);
+}
+
+#[test]
+fn emphasis_is_made_up_for_64_colour_palettes() {
+    let palette = Palette::from_bytes(&[0xff; 64 * 3]).unwrap();
+    // Emphasising red dims green and blue
+    let [r, g, b, _] = palette.rgba(0x20 | (0b001 << 6));
+    assert_eq!(r, 0xff);
+    assert!(g < 0xff && b < 0xff);
+    // Emphasising everything dims everything
+    let [r, g, b, _] = palette.rgba(0x20 | (0b111 << 6));
+    assert!(r < 0xff && g < 0xff && b < 0xff);
+}
+
+#[test]
+fn full_palettes_are_used_as_they_are() {
+    let mut bytes = vec![0; 512 * 3];
+    bytes[(0x1c0 + 0x21) * 3] = 0x12;
+    let palette = Palette::from_bytes(&bytes).unwrap();
+    assert_eq!(palette.rgba(0x1c0 + 0x21), [0x12, 0, 0, 0xff]);
+}
+
+#[test]
+fn odd_sized_palettes_are_rejected() {
+    assert!(matches!(Palette::from_bytes(&[0; 100]), Err(PaletteError::BadSize(100))));
+}
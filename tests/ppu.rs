@@ -0,0 +1,101 @@
+mod common;
+
+use neks::cpu::CPU;
+use neks::region::Region;
+
+#[test]
+fn sprite_zero_hit_and_overflow_are_set() {
+    let program: &[u8] = &[
+        0xa9, 0x02,             // LDA #$02
+        0x8d, 0x14, 0x40,       // STA $4014: OAM DMA from $0200, so every sprite is tile 0 at 0,0
+        0xa9, 0x1e,             // LDA #$1E: show the background and sprites, including the left 8 pixels
+        0x8d, 0x01, 0x20,       // STA $2001
+        0xad, 0x02, 0x20,       // LDA $2002
+        0x29, 0x40,             // AND #$40
+        0xf0, 0xf9,             // BEQ $800A: wait for sprite 0 hit
+        0xad, 0x02, 0x20,       // LDA $2002
+        0x85, 0x10,             // STA $10
+        0x02,                   // JAM
+    ];
+    let mut prg = vec![0xea; 0x8000];
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0x8000_u16.to_le_bytes());
+    // Every tile is solid colour 3, so the background and sprites are opaque everywhere
+    let chr = vec![0xff; 0x2000];
+    let mut cpu = CPU::init(common::parse(common::image(0, 0, &prg, &chr)));
+
+    common::run_until_jammed(&mut cpu, 100_000);
+    // All 64 sprites are on the same lines, so the overflow flag is set too
+    assert_eq!(cpu.peek(0x0010) & 0x60, 0x60);
+}
+
+/// CPU cycles taken by 10 frames, with rendering off so there's no skipped dot
+fn cycles_for_ten_frames(region: Region) -> u64 {
+    let mut cpu = CPU::init(common::nrom(&[], 0x8000, 0x8000, 0x8000));
+    cpu.set_region(region);
+    let start_frame = cpu.ppu().frame() + 1;
+    while cpu.ppu().frame() < start_frame {
+        cpu.step();
+    }
+    let start = cpu.cycles();
+    while cpu.ppu().frame() < start_frame + 10 {
+        cpu.step();
+    }
+    cpu.cycles() - start
+}
+
+#[test]
+fn frame_length_depends_on_region() {
+    // Lines per frame * 341 dots / dots per CPU cycle, give or take an instruction
+    let ntsc = 262.0 * 341.0 * 10.0 / 3.0;
+    let pal = 312.0 * 341.0 * 10.0 / 3.2;
+    let dendy = 312.0 * 341.0 * 10.0 / 3.0;
+    assert!((cycles_for_ten_frames(Region::Ntsc) as f64 - ntsc).abs() < 10.0);
+    assert!((cycles_for_ten_frames(Region::Pal) as f64 - pal).abs() < 10.0);
+    assert!((cycles_for_ten_frames(Region::Dendy) as f64 - dendy).abs() < 10.0);
+}
+
+/// Points PPUADDR at `address`, after reading PPUSTATUS to make sure the next write is the high byte
+fn set_ppu_address(address: u16) -> Vec<u8> {
+    let [low, high] = address.to_le_bytes();
+    vec![
+        0xad, 0x02, 0x20,       // LDA $2002
+        0xa9, high,             // LDA #high
+        0x8d, 0x06, 0x20,       // STA $2006
+        0xa9, low,              // LDA #low
+        0x8d, 0x06, 0x20,       // STA $2006
+    ]
+}
+
+#[test]
+fn ppudata_reaches_vram_and_palette() {
+    let mut program = Vec::new();
+    // $3F10 is a mirror of $3F00
+    program.extend(set_ppu_address(0x3f10));
+    program.extend(&[0xa9, 0x2a, 0x8d, 0x07, 0x20]);            // LDA #$2A, STA $2007
+    program.extend(set_ppu_address(0x3f00));
+    program.extend(&[0xad, 0x07, 0x20, 0x85, 0x10]);            // LDA $2007, STA $10: palette reads aren't buffered
+    // Going down by 32 at a time, write $2000 and $2020
+    program.extend(&[0xa9, 0x04, 0x8d, 0x00, 0x20]);            // LDA #$04, STA $2000
+    program.extend(set_ppu_address(0x2000));
+    program.extend(&[0xa9, 0x11, 0x8d, 0x07, 0x20]);            // LDA #$11, STA $2007
+    program.extend(&[0xa9, 0x22, 0x8d, 0x07, 0x20]);            // LDA #$22, STA $2007
+    program.extend(&[0xa9, 0x00, 0x8d, 0x00, 0x20]);            // LDA #$00, STA $2000
+    // The first read after setting the address only fills the buffer
+    program.extend(set_ppu_address(0x2020));
+    program.extend(&[0xad, 0x07, 0x20, 0xad, 0x07, 0x20, 0x85, 0x11]); // LDA $2007, LDA $2007, STA $11
+    // With vertical mirroring, $2800 is the same as $2000
+    program.extend(set_ppu_address(0x2800));
+    program.extend(&[0xad, 0x07, 0x20, 0xad, 0x07, 0x20, 0x85, 0x12]); // LDA $2007, LDA $2007, STA $12
+    program.push(0x02);                                         // JAM
+
+    let mut prg = vec![0xea; 0x8000];
+    prg[..program.len()].copy_from_slice(&program);
+    prg[0x7ffc..0x7ffe].copy_from_slice(&0x8000_u16.to_le_bytes());
+    let mut cpu = CPU::init(common::parse(common::image(0, 0x01, &prg, &[0; 0x2000])));
+
+    common::run_until_jammed(&mut cpu, 1000);
+    assert_eq!(cpu.peek(0x0010), 0x2a);
+    assert_eq!(cpu.peek(0x0011), 0x22);
+    assert_eq!(cpu.peek(0x0012), 0x11);
+}
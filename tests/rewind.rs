@@ -0,0 +1,117 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use neks::controller::{Buttons, Joypad, Port};
+use neks::cpu::{Registers, CPU};
+use neks::rewind::Rewind;
+
+/// Counts frames in $10, and adds A on player 1's joypad to $11
+const NMI: &[u8] = &[
+    0xe6, 0x10,             // INC $10
+    0xa9, 0x01,             // LDA #$01
+    0x8d, 0x16, 0x40,       // STA $4016
+    0xa9, 0x00,             // LDA #$00
+    0x8d, 0x16, 0x40,       // STA $4016
+    0xad, 0x16, 0x40,       // LDA $4016
+    0x29, 0x01,             // AND #$01
+    0x18,                   // CLC
+    0x65, 0x11,             // ADC $11
+    0x85, 0x11,             // STA $11
+    0x40,                   // RTI
+];
+
+fn machine() -> (CPU, Rc<RefCell<Joypad>>) {
+    let mut cpu = CPU::init(common::nrom(&[(0x8000, common::WAIT_FOR_NMI), (0x9000, NMI)], 0x9000, 0x8000, 0x8000));
+    let joypad = Joypad::shared();
+    cpu.connect(Port::One, Some(joypad.clone()));
+    (cpu, joypad)
+}
+
+fn run_frame(cpu: &mut CPU, joypad: &RefCell<Joypad>, buttons: Buttons) {
+    joypad.borrow_mut().set_buttons(buttons);
+    common::run_frame(cpu);
+}
+
+/// Enough to tell two moments apart
+fn moment(cpu: &CPU) -> (u64, Registers, Vec<u8>) {
+    (cpu.cycles(), cpu.registers(), (0..0x800).map(|address| cpu.peek(address)).collect())
+}
+
+fn input(frame: usize) -> Buttons {
+    match frame % 3 {
+        0 => Buttons::A,
+        _ => Buttons::empty(),
+    }
+}
+
+#[test]
+fn steps_back_one_frame_at_a_time() {
+    let (mut cpu, joypad) = machine();
+    let mut rewind = Rewind::new(5, 1000, 16 << 20);
+    let mut moments = vec![moment(&cpu)];
+    for frame in 0..40 {
+        rewind.record(&cpu, [input(frame), Buttons::empty()]);
+        run_frame(&mut cpu, &joypad, input(frame));
+        moments.push(moment(&cpu));
+    }
+    // The first NMI comes at the end of the first frame
+    assert_eq!(cpu.peek(0x0010), 39);
+    assert_ne!(cpu.peek(0x0011), 0);
+    assert_eq!(rewind.frames(), 40);
+
+    let mut replay = |cpu: &mut CPU, input: [Buttons; 2]| run_frame(cpu, &joypad, input[0]);
+    for frame in (30..40).rev() {
+        assert!(rewind.step_back(&mut cpu, &mut replay));
+        assert_eq!(moment(&cpu), moments[frame], "Stepping back to frame {}", frame);
+    }
+
+    // Carrying on from there with different input, then going back over it
+    for _ in 30..35 {
+        rewind.record(&cpu, [Buttons::empty(), Buttons::empty()]);
+        run_frame(&mut cpu, &joypad, Buttons::empty());
+    }
+    assert_ne!(moment(&cpu), moments[35]);
+    for _ in 30..35 {
+        assert!(rewind.step_back(&mut cpu, &mut replay));
+    }
+    assert_eq!(moment(&cpu), moments[30]);
+
+    // All the way back to the start
+    for frame in (0..30).rev() {
+        assert!(rewind.step_back(&mut cpu, &mut replay));
+        assert_eq!(moment(&cpu), moments[frame], "Stepping back to frame {}", frame);
+    }
+    assert!(!rewind.step_back(&mut cpu, &mut replay));
+    assert_eq!(rewind.frames(), 0);
+}
+
+#[test]
+fn drops_the_oldest_frames() {
+    let (mut cpu, joypad) = machine();
+    let mut rewind = Rewind::new(4, 20, 16 << 20);
+    for frame in 0..100 {
+        rewind.record(&cpu, [input(frame), Buttons::empty()]);
+        run_frame(&mut cpu, &joypad, input(frame));
+    }
+    // Whole intervals are dropped, so it keeps somewhere between the limit and an interval less
+    assert!((16..=24).contains(&rewind.frames()), "{} frames", rewind.frames());
+
+    let nmis = cpu.peek(0x0010) as usize;
+    let mut steps = 0;
+    while rewind.step_back(&mut cpu, |cpu, input| run_frame(cpu, &joypad, input[0])) {
+        steps += 1;
+    }
+    assert!((16..=24).contains(&steps), "{} steps", steps);
+    assert_eq!(cpu.peek(0x0010) as usize, nmis - steps);
+
+    // Snapshots of a machine this quiet compress to almost nothing, apart from the newest
+    let mut small = Rewind::new(1, 1000, 150_000);
+    for frame in 0..100 {
+        small.record(&cpu, [input(frame), Buttons::empty()]);
+        run_frame(&mut cpu, &joypad, input(frame));
+    }
+    assert!(small.bytes() <= 150_000);
+    assert!(small.frames() > 1, "{} frames", small.frames());
+}
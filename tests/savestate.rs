@@ -0,0 +1,158 @@
+mod common;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use neks::console::Console;
+use neks::ines::Cartridge;
+use neks::savestate::StateError;
+
+/// Sets up the palette and a nametable, starts the pulse, triangle and noise channels playing, then turns on
+/// rendering and NMIs and waits
+const RESET: &[u8] = &[
+    0x78,                   // SEI
+    0xa2, 0xff,             // LDX #$FF
+    0x9a,                   // TXS
+    0x2c, 0x02, 0x20,       // BIT $2002
+    0x10, 0xfb,             // BPL -5
+    0x2c, 0x02, 0x20,       // BIT $2002
+    0x10, 0xfb,             // BPL -5
+    // Palette, from the table at $9000
+    0xa9, 0x3f,             // LDA #$3F
+    0x8d, 0x06, 0x20,       // STA $2006
+    0xa9, 0x00,             // LDA #$00
+    0x8d, 0x06, 0x20,       // STA $2006
+    0xa2, 0x00,             // LDX #$00
+    0xbd, 0x00, 0x90,       // LDA $9000,X
+    0x8d, 0x07, 0x20,       // STA $2007
+    0xe8,                   // INX
+    0xe0, 0x20,             // CPX #$20
+    0xd0, 0xf5,             // BNE -11
+    // The first nametable and its attributes, with 0-255 four times over
+    0xa9, 0x20,             // LDA #$20
+    0x8d, 0x06, 0x20,       // STA $2006
+    0xa9, 0x00,             // LDA #$00
+    0x8d, 0x06, 0x20,       // STA $2006
+    0xa0, 0x04,             // LDY #$04
+    0xa2, 0x00,             // LDX #$00
+    0x8a,                   // TXA
+    0x8d, 0x07, 0x20,       // STA $2007
+    0xe8,                   // INX
+    0xd0, 0xf9,             // BNE -7
+    0x88,                   // DEY
+    0xd0, 0xf6,             // BNE -10
+    // Pulse 1 at constant volume, triangle and noise, all with their length counters halted
+    0xa9, 0x0f,             // LDA #$0F
+    0x8d, 0x15, 0x40,       // STA $4015
+    0xa9, 0xbf,             // LDA #$BF
+    0x8d, 0x00, 0x40,       // STA $4000
+    0xa9, 0x08,             // LDA #$08
+    0x8d, 0x03, 0x40,       // STA $4003
+    0xa9, 0xff,             // LDA #$FF
+    0x8d, 0x08, 0x40,       // STA $4008
+    0xa9, 0x80,             // LDA #$80
+    0x8d, 0x0a, 0x40,       // STA $400A
+    0xa9, 0x08,             // LDA #$08
+    0x8d, 0x0b, 0x40,       // STA $400B
+    0xa9, 0x36,             // LDA #$36
+    0x8d, 0x0c, 0x40,       // STA $400C
+    0xa9, 0x08,             // LDA #$08
+    0x8d, 0x0f, 0x40,       // STA $400F
+    // NMI on, background and sprites on
+    0xa9, 0x80,             // LDA #$80
+    0x8d, 0x00, 0x20,       // STA $2000
+    0xa9, 0x1e,             // LDA #$1E
+    0x8d, 0x01, 0x20,       // STA $2001
+];
+
+/// Every frame: copies sprites from $0200, scrolls by the frame count, and changes the pulse and noise pitch
+const NMI: &[u8] = &[
+    0x48,                   // PHA
+    0xa9, 0x02,             // LDA #$02
+    0x8d, 0x14, 0x40,       // STA $4014
+    0xe6, 0x10,             // INC $10
+    0xa5, 0x10,             // LDA $10
+    0x8d, 0x05, 0x20,       // STA $2005
+    0x8d, 0x05, 0x20,       // STA $2005
+    0x8d, 0x02, 0x40,       // STA $4002
+    0x9d, 0x00, 0x02,       // STA $0200,X
+    0xe8,                   // INX
+    0x4a,                   // LSR A
+    0x8d, 0x0e, 0x40,       // STA $400E
+    0x68,                   // PLA
+    0x40,                   // RTI
+];
+
+fn cartridge() -> Cartridge {
+    let mut reset = RESET.to_vec();
+    // Then wait forever
+    let end = 0x8000 + reset.len() as u16;
+    reset.push(0x4c);
+    reset.extend(&end.to_le_bytes());
+    let palette: Vec<u8> = (0..0x20).map(|i: u8| (i * 7 + 1) & 0x3f).collect();
+    let prg = common::nrom_prg(&[(0x8000, &reset), (0x8800, NMI), (0x9000, &palette)], 0x8800, 0x8000, 0x8800);
+
+    let chr: Vec<u8> = (0..0x2000_usize).map(|i| (i * 37 + (i >> 8)) as u8).collect();
+    common::parse(common::image(0, 0, &prg, &chr))
+}
+
+fn snapshot(console: &Console) -> String {
+    let mut snapshot = Vec::new();
+    console.write_snapshot(&mut snapshot).unwrap();
+    String::from_utf8(snapshot).unwrap()
+}
+
+/// Runs for `frames` frames, returning a hash of each frame's picture
+fn run(console: &mut Console, frames: u64) -> Vec<u64> {
+    (0..frames).map(|_| {
+        console.run_frame();
+        let mut hasher = DefaultHasher::new();
+        console.cpu().ppu().framebuffer().hash(&mut hasher);
+        hasher.finish()
+    }).collect()
+}
+
+#[test]
+fn reloaded_state_runs_identically() {
+    let mut console = Console::new(cartridge());
+    console.run_frames(30);
+    let state = console.save_state();
+    console.set_record_audio(true);
+    let frames = run(&mut console, 600);
+    assert!(frames.windows(2).any(|pair| pair[0] != pair[1]), "The picture never changed");
+    assert!(console.audio().iter().any(|sample| sample.abs() > 0.01), "Nothing was heard");
+
+    // Into a console which has just been switched on, so anything not saved would show up
+    let mut reloaded = Console::new(cartridge());
+    reloaded.load_state(&state).unwrap();
+    assert_eq!(reloaded.frame(), 30);
+    reloaded.set_record_audio(true);
+    assert_eq!(run(&mut reloaded, 600), frames);
+    assert_eq!(reloaded.cpu().ppu().framebuffer(), console.cpu().ppu().framebuffer());
+    assert!(reloaded.audio() == console.audio(), "The audio was different");
+    assert_eq!(snapshot(&reloaded), snapshot(&console));
+    assert_eq!(reloaded.save_state(), console.save_state());
+}
+
+#[test]
+fn bad_states_are_rejected() {
+    let mut console = Console::new(cartridge());
+    console.run_frames(5);
+    let state = console.save_state();
+    let before = snapshot(&console);
+
+    assert_eq!(console.load_state(b"not a state"), Err(StateError::BadMagic));
+
+    let mut newer = state.clone();
+    newer[4] = 0xff;
+    assert!(matches!(console.load_state(&newer), Err(StateError::UnsupportedVersion(_))));
+
+    let mut other_game = Console::new(common::nrom(&[], 0x8000, 0x8000, 0x8000));
+    assert_eq!(other_game.load_state(&state), Err(StateError::WrongCartridge));
+
+    // A failed load leaves the machine as it was
+    let truncated = &state[..state.len() - 10];
+    assert!(console.load_state(truncated).is_err());
+    assert_eq!(snapshot(&console), before);
+    assert_eq!(console.save_state(), state);
+}